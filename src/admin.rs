@@ -0,0 +1,114 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::header,
+    response::IntoResponse,
+};
+use serde::Serialize;
+
+use crate::AppState;
+use crate::backend::{CircuitState, CircuitStats, TargetState};
+use crate::error::Json;
+
+#[derive(Serialize, Debug)]
+pub struct TargetList {
+    pub models: Vec<ModelTargets>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModelTargets {
+    pub model: String,
+    pub targets: Vec<TargetStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TargetStatus {
+    pub name: String,
+    pub weight: u32,
+    pub in_flight: usize,
+    pub circuit: CircuitStats,
+}
+
+/// List the targets of all models with their requests in flight and circuit breaker state.
+pub async fn list_targets(State(state): State<AppState>) -> impl IntoResponse {
+    let models = state.backends.targets().iter()
+        .map(|(model, targets)| ModelTargets {
+            model: model.to_owned(),
+            targets: targets.iter()
+                .map(|target| TargetStatus {
+                    name: target.name.to_owned(),
+                    weight: target.weight,
+                    in_flight: target.in_flight(),
+                    circuit: target.breaker.stats(),
+                })
+                .collect(),
+        })
+        .collect();
+
+    Json(TargetList { models })
+}
+
+/// Metrics of the targets in the Prometheus text format.
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], render_metrics(state.backends.targets()))
+}
+
+/// Value of a metric of a target.
+type MetricValue = fn(&TargetState, &CircuitStats) -> f64;
+
+fn render_metrics(models: &[(String, Vec<Arc<TargetState>>)]) -> String {
+    let targets: Vec<(&str, &TargetState, CircuitStats)> = models.iter()
+        .flat_map(|(model, targets)| targets.iter().map(move |target| (model.as_str(), target.as_ref(), target.breaker.stats())))
+        .collect();
+    let metrics: [(&str, &str, &str, MetricValue); 5] = [
+        ("msgapi_target_in_flight", "gauge", "Requests in flight on the target.", |target, _| target.in_flight() as f64),
+        ("msgapi_target_calls_total", "counter", "Completed calls of the target.", |_, stats| stats.calls as f64),
+        ("msgapi_target_failures_total", "counter", "Calls of the target which failed with a server error.", |_, stats| stats.failures as f64),
+        ("msgapi_circuit_state", "gauge", "State of the circuit breaker of the target: 0 closed, 1 open, 2 half-open.", |_, stats| match stats.state {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen => 2.0,
+        }),
+        ("msgapi_circuit_opened_total", "counter", "Number of times the circuit breaker of the target opened.", |_, stats| stats.opened as f64),
+    ];
+
+    let mut output = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(output, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for (model, target, stats) in &targets {
+            let _ = writeln!(output, "{}{{model=\"{}\",target=\"{}\"}} {}", name, label(model), label(&target.name), value(target, stats));
+        }
+    }
+    output
+}
+
+/// Escape a label value of the Prometheus text format.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::endpoint_loader::Target;
+
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let target = Target {
+            name: Some("us-west-2:\"llama\"".to_owned()),
+            endpoint_name: None,
+            inference_component: None,
+            target_model: None,
+            region: None,
+            weight: 1,
+        };
+        let models = vec![("Llama-3-8B".to_owned(), vec![Arc::new(TargetState::new(&target, None))])];
+        let metrics = render_metrics(&models);
+        assert!(metrics.starts_with("# HELP msgapi_target_in_flight Requests in flight on the target.\n# TYPE msgapi_target_in_flight gauge\n"));
+        assert!(metrics.contains("msgapi_circuit_state{model=\"Llama-3-8B\",target=\"us-west-2:\\\"llama\\\"\"} 0\n"));
+        assert_eq!(metrics.lines().count(), 15);
+    }
+}
@@ -0,0 +1,355 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use async_stream::stream;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::backend::{Backend, EmbedRequest, Embeddings, GenerateRequest, Generation, GenerationStream};
+use crate::backend::breaker::{CircuitBreaker, is_failure};
+use crate::endpoint_loader::{CircuitBreakerConfig, LoadBalancing, Target};
+use crate::error::Error;
+use crate::timeout::{self, Timeouts};
+
+/// State of a target, shared by the requests sent to it.
+#[derive(Debug)]
+pub struct TargetState {
+    pub name: String,
+    pub weight: u32,
+    in_flight: AtomicUsize,
+    pub breaker: CircuitBreaker,
+}
+
+impl TargetState {
+    pub fn new(target: &Target, circuit_breaker: Option<CircuitBreakerConfig>) -> TargetState {
+        TargetState {
+            name: target.name(),
+            weight: target.weight,
+            in_flight: AtomicUsize::new(0),
+            breaker: CircuitBreaker::new(circuit_breaker),
+        }
+    }
+
+    /// Number of requests sent to the target which have not completed yet. Streaming requests
+    /// complete when their stream is dropped.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Start a request, or return `None` if the circuit breaker of the target does not admit it.
+    fn start(self: &Arc<Self>) -> Option<InFlight> {
+        let trial = self.breaker.admit()?;
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlight { state: self.clone(), trial, failed: false })
+    }
+}
+
+/// Request in flight on a target, until dropped. Its outcome is then recorded by the circuit
+/// breaker of the target, as a success unless a failure was seen.
+struct InFlight {
+    state: Arc<TargetState>,
+    trial: bool,
+    failed: bool,
+}
+
+impl InFlight {
+    fn observe<T>(&mut self, result: &Result<T>) {
+        self.failed |= result.as_ref().is_err_and(is_failure);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.state.breaker.record(&self.state.name, self.trial, self.failed);
+    }
+}
+
+/// Backend sending each request to one of the targets of a model, chosen by the load balancing
+/// strategy of the model.
+#[derive(Debug)]
+pub struct BalancedBackend {
+    targets: Vec<(Arc<TargetState>, Arc<dyn Backend>)>,
+    strategy: LoadBalancing,
+    /// Number of selections, used to rotate through the targets.
+    selections: AtomicUsize,
+}
+
+impl BalancedBackend {
+    /// `targets` must not be empty, and must have a target with positive weight.
+    pub fn new(targets: Vec<(Arc<TargetState>, Arc<dyn Backend>)>, strategy: LoadBalancing) -> BalancedBackend {
+        BalancedBackend {
+            targets,
+            strategy,
+            selections: AtomicUsize::new(0),
+        }
+    }
+
+    /// Index of the target of the next request among `candidates`, which must not be empty.
+    fn select(&self, candidates: &[usize], rotation: usize) -> usize {
+        match self.strategy {
+            LoadBalancing::RoundRobin => candidates[rotation % candidates.len()],
+            LoadBalancing::WeightedRandom => {
+                // Weights are summed as u64, as the sum of u32 weights may overflow.
+                let total: u64 = candidates.iter().map(|index| u64::from(self.targets[*index].0.weight)).sum();
+                let mut point = rand::thread_rng().gen_range(0..total);
+                for index in candidates {
+                    let weight = u64::from(self.targets[*index].0.weight);
+                    if point < weight {
+                        return *index;
+                    }
+                    point -= weight;
+                }
+                unreachable!("point is lower than the total weight")
+            }
+            // Ties are broken in turn, so that idle targets share requests.
+            LoadBalancing::LeastOutstanding => candidates.iter()
+                .cycle()
+                .skip(rotation % candidates.len())
+                .take(candidates.len())
+                .min_by_key(|index| self.targets[**index].0.in_flight())
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Start a request on a target with positive weight whose circuit breaker admits it.
+    fn target(&self) -> Result<(InFlight, &dyn Backend)> {
+        let mut candidates: Vec<usize> = (0..self.targets.len())
+            .filter(|index| self.targets[*index].0.weight > 0 && self.targets[*index].0.breaker.available())
+            .collect();
+        let rotation = self.selections.fetch_add(1, Ordering::Relaxed);
+        // A half-open circuit may admit no more trial calls by the time the target is selected.
+        while !candidates.is_empty() {
+            let index = self.select(&candidates, rotation);
+            let (state, backend) = &self.targets[index];
+            if let Some(in_flight) = state.start() {
+                debug!("sending request to target {} with {} requests in flight", state.name, state.in_flight());
+                return Ok((in_flight, backend.as_ref()));
+            }
+            candidates.retain(|candidate| *candidate != index);
+        }
+        let names: Vec<&str> = self.targets.iter().map(|(state, _)| state.name.as_str()).collect();
+        Err(Error::CircuitOpen(format!("The circuit breakers of all targets are open: {}", names.join(", "))).into())
+    }
+}
+
+impl Backend for BalancedBackend {
+    fn accepts_messages(&self) -> bool {
+        self.targets[0].1.accepts_messages()
+    }
+
+    fn sampling_params(&self) -> &'static [&'static str] {
+        self.targets[0].1.sampling_params()
+    }
+
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        async move {
+            let (mut in_flight, backend) = self.target()?;
+            let result = backend.invoke(request).await;
+            in_flight.observe(&result);
+            result
+        }.boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        async move {
+            let (mut in_flight, backend) = self.target()?;
+            // Timeouts of the target end the stream with an error, which is recorded as a failure.
+            let started = Instant::now();
+            let timeouts = Timeouts { total: None, ..request.timeouts };
+            let first_token = timeouts.first_token.map(|first_token| started + first_token);
+            let result = timeout::until(first_token, timeouts.first_token_message(), backend.invoke_stream(request)).await;
+            in_flight.observe(&result);
+            let mut generation_stream = timeout::stream_until(result?, timeouts, started);
+            let stream: GenerationStream = Box::pin(stream! {
+                let mut in_flight = in_flight;
+                while let Some(chunk) = generation_stream.next().await {
+                    in_flight.observe(&chunk);
+                    yield chunk;
+                }
+            });
+            Ok(stream)
+        }.boxed()
+    }
+
+    fn embed<'a>(&'a self, request: &'a EmbedRequest) -> BoxFuture<'a, Result<Embeddings>> {
+        async move {
+            let (mut in_flight, backend) = self.target()?;
+            let result = backend.embed(request).await;
+            in_flight.observe(&result);
+            result
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use futures::{future, stream};
+
+    use crate::backend::{CircuitState, GenerationChunk};
+
+    use super::*;
+
+    /// Backend answering with its name.
+    #[derive(Debug)]
+    struct NamedBackend(&'static str);
+
+    impl Backend for NamedBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            async move { Ok(Generation { text: self.0.to_owned(), ..Default::default() }) }.boxed()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            async move {
+                let chunks = vec![Ok(GenerationChunk { text: self.0.to_owned(), ..Default::default() }), Err(anyhow!("failed"))];
+                Ok(Box::pin(stream::iter(chunks)) as GenerationStream)
+            }.boxed()
+        }
+    }
+
+    /// Backend failing with an unexpected response.
+    #[derive(Debug)]
+    struct FailingBackend;
+
+    impl Backend for FailingBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            async move { Err(anyhow!("unexpected response")) }.boxed()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            async move { Err(anyhow!("unexpected response")) }.boxed()
+        }
+    }
+
+    /// Backend whose streams stall after the first chunk.
+    #[derive(Debug)]
+    struct StalledBackend;
+
+    impl Backend for StalledBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            future::pending().boxed()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            async move {
+                let chunk = Ok(GenerationChunk { text: "Hi".to_owned(), ..Default::default() });
+                Ok(Box::pin(stream::iter(vec![chunk]).chain(stream::pending())) as GenerationStream)
+            }.boxed()
+        }
+    }
+
+    fn target_state(name: &str, weight: u32, circuit_breaker: Option<CircuitBreakerConfig>) -> Arc<TargetState> {
+        let target = Target {
+            name: Some(name.to_owned()),
+            endpoint_name: None,
+            inference_component: None,
+            target_model: None,
+            region: None,
+            weight,
+        };
+        Arc::new(TargetState::new(&target, circuit_breaker))
+    }
+
+    fn balancer(weights: &[u32], strategy: LoadBalancing) -> BalancedBackend {
+        const NAMES: [&str; 3] = ["a", "b", "c"];
+        let targets = weights.iter().zip(NAMES)
+            .map(|(weight, name)| {
+                (target_state(name, *weight, None), Arc::new(NamedBackend(name)) as Arc<dyn Backend>)
+            })
+            .collect();
+        BalancedBackend::new(targets, strategy)
+    }
+
+    fn in_flight(balancer: &BalancedBackend) -> Vec<usize> {
+        balancer.targets.iter().map(|(state, _)| state.in_flight()).collect()
+    }
+
+    async fn served(balancer: &BalancedBackend, requests: usize) -> Vec<String> {
+        let mut served = vec![];
+        for _ in 0..requests {
+            served.push(balancer.invoke(&GenerateRequest::default()).await.unwrap().text);
+        }
+        served
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let balancer = balancer(&[1, 0, 2], LoadBalancing::RoundRobin);
+        assert_eq!(served(&balancer, 4).await, vec!["a", "c", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_random() {
+        // The total weight does not fit in u32.
+        let heavy = balancer(&[3_000_000_000, 3_000_000_000], LoadBalancing::WeightedRandom);
+        assert_eq!(served(&heavy, 10).await.len(), 10);
+
+        let balancer = balancer(&[3, 0, 1], LoadBalancing::WeightedRandom);
+        let served = served(&balancer, 1000).await;
+        let a = served.iter().filter(|name| *name == "a").count();
+        assert!(!served.contains(&"b".to_owned()));
+        assert!((600..900).contains(&a), "{} requests served by a", a);
+    }
+
+    #[tokio::test]
+    async fn test_least_outstanding() {
+        let balancer = balancer(&[1, 1, 1], LoadBalancing::LeastOutstanding);
+        let request = GenerateRequest::default();
+        let a = balancer.invoke_stream(&request).await.unwrap();
+        let b = balancer.invoke_stream(&request).await.unwrap();
+        assert_eq!(in_flight(&balancer), vec![1, 1, 0]);
+        assert_eq!(served(&balancer, 2).await, vec!["c", "c"]);
+
+        // Streams are in flight until dropped, even after an error.
+        let chunks: Vec<Result<_>> = a.collect().await;
+        assert_eq!(chunks.len(), 2);
+        drop(b);
+        assert_eq!(in_flight(&balancer), vec![0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let config = CircuitBreakerConfig { window: 2, min_calls: 2, failure_rate: 0.5, cooldown_ms: 60_000, trial_calls: 1 };
+        let targets = vec![
+            (target_state("a", 1, Some(config)), Arc::new(FailingBackend) as Arc<dyn Backend>),
+            (target_state("b", 1, Some(config)), Arc::new(NamedBackend("b")) as Arc<dyn Backend>),
+        ];
+        let balancer = BalancedBackend::new(targets, LoadBalancing::RoundRobin);
+        let request = GenerateRequest::default();
+        assert!(balancer.invoke(&request).await.is_err());
+        assert!(balancer.invoke(&request).await.is_ok());
+        assert!(balancer.invoke_stream(&request).await.is_err());
+        assert_eq!(balancer.targets[0].0.breaker.stats().state, CircuitState::Open);
+
+        // Requests skip the open circuit.
+        assert_eq!(served(&balancer, 3).await, vec!["b", "b", "b"]);
+
+        let balancer = BalancedBackend::new(vec![balancer.targets[0].clone()], LoadBalancing::RoundRobin);
+        let err = Error::from(balancer.invoke(&request).await.unwrap_err());
+        assert!(matches!(err, Error::CircuitOpen(_)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_timeouts() {
+        let config = CircuitBreakerConfig { window: 2, min_calls: 2, failure_rate: 0.5, cooldown_ms: 60_000, trial_calls: 1 };
+        let targets = vec![(target_state("a", 1, Some(config)), Arc::new(StalledBackend) as Arc<dyn Backend>)];
+        let balancer = BalancedBackend::new(targets, LoadBalancing::RoundRobin);
+        let request = GenerateRequest {
+            timeouts: Timeouts { idle: Some(Duration::from_millis(10)), ..Default::default() },
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let chunks: Vec<Result<_>> = balancer.invoke_stream(&request).await.unwrap().collect().await;
+            assert!(matches!(chunks[1].as_ref().unwrap_err().downcast_ref::<Error>(), Some(Error::Timeout(_))));
+        }
+        assert_eq!(balancer.targets[0].0.breaker.stats().state, CircuitState::Open);
+    }
+}
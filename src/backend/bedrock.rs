@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_stream::try_stream;
+use aws_sdk_bedrockruntime::primitives::Blob;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use crate::backend::{Backend, GenerateRequest, Generation, GenerationChunk, GenerationStream, Usage};
+use crate::endpoint_loader::{Endpoint, Target};
+use crate::error::Error;
+use crate::types::{BedrockRequest, BedrockResponse, BedrockStreamResponse};
+
+/// Bedrock InvokeModel API for Llama models.
+#[derive(Debug)]
+pub struct BedrockBackend {
+    client: Arc<aws_sdk_bedrockruntime::Client>,
+    model_id: String,
+}
+
+impl BedrockBackend {
+    pub fn new(client: Arc<aws_sdk_bedrockruntime::Client>, endpoint: &Endpoint, target: &Target) -> Result<BedrockBackend> {
+        let model_id = target.target_model.to_owned()
+            .ok_or_else(|| anyhow!("target_model must be set for Bedrock backend: {}", endpoint.model))?;
+        if endpoint.vision {
+            return Err(anyhow!("vision is not supported by Bedrock backend, use BedrockConverse: {}", endpoint.model));
+        }
+
+        Ok(BedrockBackend {
+            client,
+            model_id,
+        })
+    }
+
+    pub fn build_request(request: &GenerateRequest) -> Blob {
+        BedrockRequest {
+            prompt: request.prompt.to_owned(),
+            top_p: request.top_p,
+            temperature: request.temperature,
+            max_gen_len: request.max_tokens,
+        }.serialize()
+    }
+
+    pub fn parse_response(body: &[u8]) -> Result<Generation> {
+        let output: BedrockResponse = serde_json::from_slice(body)?;
+        Ok(Generation {
+            text: output.generation,
+            finish_reason: Some(output.stop_reason),
+            usage: Usage {
+                prompt_tokens: output.prompt_token_count,
+                completion_tokens: output.generation_token_count,
+            },
+            ..Default::default()
+        })
+    }
+
+    pub fn parse_stream_chunk(chunk: &[u8]) -> Result<GenerationChunk> {
+        let output: BedrockStreamResponse = serde_json::from_slice(chunk)?;
+        let metrics = output.invocation_metrics.unwrap_or_default();
+        Ok(GenerationChunk {
+            text: output.generation,
+            finish_reason: output.stop_reason,
+            usage: Usage {
+                prompt_tokens: metrics.input_token_count.or(output.prompt_token_count),
+                completion_tokens: metrics.output_token_count.or(output.generation_token_count),
+            },
+            ..Default::default()
+        })
+    }
+}
+
+impl Backend for BedrockBackend {
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        async move {
+            let output = self.client.invoke_model()
+                .set_model_id(Some(self.model_id.to_owned()))
+                .set_content_type(Some("application/json".to_owned()))
+                .set_accept(Some("application/json".to_owned()))
+                .set_body(Some(Self::build_request(request)))
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            Self::parse_response(output.body.as_ref())
+        }.boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        async move {
+            let mut output = self.client.invoke_model_with_response_stream()
+                .set_model_id(Some(self.model_id.to_owned()))
+                .set_accept(Some("application/json".to_owned()))
+                .set_content_type(Some("application/json".to_owned()))
+                .set_body(Some(Self::build_request(request)))
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            let stream: GenerationStream = Box::pin(try_stream! {
+                while let Some(response_stream) = output.body.recv().await.map_err(Error::from_sdk)? {
+                    let payload_part = response_stream.as_chunk()
+                        .map_err(|_| anyhow!("unexpected event in response stream"))?;
+                    if let Some(bytes) = payload_part.bytes.as_ref() {
+                        yield Self::parse_stream_chunk(bytes.as_ref())?;
+                    }
+                }
+            });
+            Ok(stream)
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let request = GenerateRequest {
+            prompt: "Hello".to_owned(),
+            top_k: Some(10),
+            max_tokens: Some(256),
+            ..Default::default()
+        };
+        let body: Value = serde_json::from_slice(BedrockBackend::build_request(&request).as_ref()).unwrap();
+        assert_eq!(body, json!({"prompt": "Hello", "max_gen_len": 256}));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let generation = BedrockBackend::parse_response(br#"{"generation": "Hi", "prompt_token_count": 10, "generation_token_count": 1, "stop_reason": "stop"}"#).unwrap();
+        assert_eq!(generation, Generation {
+            text: "Hi".to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(10), completion_tokens: Some(1) },
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_parse_stream_chunk() {
+        let chunk = BedrockBackend::parse_stream_chunk(br#"{"generation": "Hi", "prompt_token_count": 10, "generation_token_count": 1, "stop_reason": null}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk {
+            text: "Hi".to_owned(),
+            finish_reason: None,
+            usage: Usage { prompt_tokens: Some(10), completion_tokens: Some(1) },
+            ..Default::default()
+        });
+
+        let chunk = BedrockBackend::parse_stream_chunk(br#"{"generation": "", "prompt_token_count": null, "generation_token_count": 5, "stop_reason": "stop", "amazon-bedrock-invocationMetrics": {"inputTokenCount": 10, "outputTokenCount": 6, "invocationLatency": 300, "firstByteLatency": 100}}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk {
+            text: String::new(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(10), completion_tokens: Some(6) },
+            ..Default::default()
+        });
+    }
+}
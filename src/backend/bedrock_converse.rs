@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_stream::try_stream;
+use aws_sdk_bedrockruntime::operation::converse::ConverseOutput;
+use aws_sdk_bedrockruntime::primitives::Blob;
+use aws_sdk_bedrockruntime::types::{ContentBlock, ContentBlockDelta, ConversationRole, ConverseOutput as ConverseOutputMessage, ConverseStreamOutput, ImageBlock, ImageFormat, ImageSource, InferenceConfiguration, Message, StopReason, SystemContentBlock, TokenUsage};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+
+use crate::backend::{Backend, GenerateRequest, Generation, GenerationChunk, GenerationStream, Usage};
+use crate::endpoint_loader::{Endpoint, Target};
+use crate::error::Error;
+use crate::image::Image;
+use crate::types::{ChatCompletionsContent, ChatCompletionsContentPart, ChatCompletionsMessage};
+
+/// Bedrock Converse API, which takes structured messages for any Bedrock chat model.
+#[derive(Debug)]
+pub struct BedrockConverseBackend {
+    client: Arc<aws_sdk_bedrockruntime::Client>,
+    model_id: String,
+}
+
+/// Converse request parts shared by `Converse` and `ConverseStream`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConverseRequest {
+    pub system: Vec<SystemContentBlock>,
+    pub messages: Vec<Message>,
+    pub inference_config: InferenceConfiguration,
+}
+
+impl BedrockConverseBackend {
+    pub fn new(client: Arc<aws_sdk_bedrockruntime::Client>, endpoint: &Endpoint, target: &Target) -> Result<BedrockConverseBackend> {
+        let model_id = target.target_model.to_owned()
+            .ok_or_else(|| anyhow!("target_model must be set for BedrockConverse backend: {}", endpoint.model))?;
+
+        Ok(BedrockConverseBackend {
+            client,
+            model_id,
+        })
+    }
+
+    /// Map chat messages onto Converse system blocks and messages.
+    ///
+    /// Converse requires user and assistant turns to alternate, so consecutive messages with
+    /// the same role are merged into a single message with multiple content blocks.
+    pub fn build_request(request: &GenerateRequest) -> Result<ConverseRequest> {
+        let mut system = vec![];
+        let mut turns: Vec<(ConversationRole, Vec<ContentBlock>)> = vec![];
+        for message in &request.messages {
+            let role = match message.role.as_str() {
+                "system" => {
+                    system.push(SystemContentBlock::Text(message.text()));
+                    continue;
+                }
+                "user" => ConversationRole::User,
+                "assistant" => ConversationRole::Assistant,
+                role => return Err(Error::invalid_param("messages", format!("unknown role: {}", role)).into()),
+            };
+            let content = content_blocks(message)?;
+            match turns.last_mut() {
+                Some((last_role, blocks)) if *last_role == role => blocks.extend(content),
+                _ => turns.push((role, content)),
+            }
+        }
+
+        let messages = turns.into_iter()
+            .map(|(role, content)| Message::builder().role(role).set_content(Some(content)).build())
+            .collect::<Result<Vec<Message>, _>>()?;
+
+        let max_tokens = request.max_tokens
+            .map(|max_tokens| i32::try_from(max_tokens)
+                .map_err(|_| Error::invalid_param("max_tokens", format!("max_tokens must be at most {}", i32::MAX))))
+            .transpose()?;
+        let inference_config = InferenceConfiguration::builder()
+            .set_max_tokens(max_tokens)
+            .set_temperature(request.temperature)
+            .set_top_p(request.top_p)
+            .set_stop_sequences(if request.stop.is_empty() { None } else { Some(request.stop.to_owned()) })
+            .build();
+
+        Ok(ConverseRequest {
+            system,
+            messages,
+            inference_config,
+        })
+    }
+
+    pub fn parse_response(output: &ConverseOutput) -> Result<Generation> {
+        let message = match output.output.as_ref() {
+            Some(ConverseOutputMessage::Message(message)) => message,
+            _ => return Err(anyhow!("Converse response does not contain a message")),
+        };
+        let text = message.content.iter()
+            .filter_map(|block| block.as_text().ok().map(|text| text.as_str()))
+            .collect::<Vec<&str>>()
+            .join("");
+
+        Ok(Generation {
+            text,
+            finish_reason: Some(finish_reason(&output.stop_reason)),
+            usage: output.usage.as_ref().map(usage).unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    /// Convert a `ConverseStream` event into a chunk, skipping events which carry no output.
+    pub fn parse_stream_chunk(event: &ConverseStreamOutput) -> Option<GenerationChunk> {
+        match event {
+            ConverseStreamOutput::ContentBlockDelta(event) => match event.delta.as_ref() {
+                Some(ContentBlockDelta::Text(text)) => Some(GenerationChunk {
+                    text: text.to_owned(),
+                    ..Default::default()
+                }),
+                _ => None,
+            },
+            ConverseStreamOutput::MessageStop(event) => Some(GenerationChunk {
+                finish_reason: Some(finish_reason(&event.stop_reason)),
+                ..Default::default()
+            }),
+            ConverseStreamOutput::Metadata(event) => Some(GenerationChunk {
+                usage: event.usage.as_ref().map(usage).unwrap_or_default(),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Map message content onto text and image blocks. Images are base64 data URLs.
+fn content_blocks(message: &ChatCompletionsMessage) -> Result<Vec<ContentBlock>> {
+    let parts = match message.content.as_ref() {
+        Some(ChatCompletionsContent::Parts(parts)) => parts,
+        _ => return Ok(vec![ContentBlock::Text(message.text())]),
+    };
+
+    let mut blocks = vec![];
+    for part in parts {
+        let block = match part {
+            ChatCompletionsContentPart::Text { text } => ContentBlock::Text(text.to_owned()),
+            ChatCompletionsContentPart::ImageUrl { image_url } => {
+                let image = Image::from_url(&image_url.url, false)?;
+                ContentBlock::Image(ImageBlock::builder()
+                    .format(ImageFormat::from(image.format()))
+                    .source(ImageSource::Bytes(Blob::new(image.data)))
+                    .build()?)
+            }
+        };
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+/// Map Converse stop reasons onto OpenAI finish reasons.
+fn finish_reason(stop_reason: &StopReason) -> String {
+    match stop_reason {
+        StopReason::MaxTokens => "length",
+        StopReason::ToolUse => "tool_calls",
+        StopReason::ContentFiltered | StopReason::GuardrailIntervened => "content_filter",
+        _ => "stop",
+    }.to_owned()
+}
+
+fn usage(usage: &TokenUsage) -> Usage {
+    Usage {
+        prompt_tokens: Some(usage.input_tokens as i64),
+        completion_tokens: Some(usage.output_tokens as i64),
+    }
+}
+
+impl Backend for BedrockConverseBackend {
+    fn accepts_messages(&self) -> bool {
+        true
+    }
+
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        async move {
+            let converse_request = Self::build_request(request)?;
+            let output = self.client.converse()
+                .model_id(self.model_id.to_owned())
+                .set_system(Some(converse_request.system))
+                .set_messages(Some(converse_request.messages))
+                .inference_config(converse_request.inference_config)
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            Self::parse_response(&output)
+        }.boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        async move {
+            let converse_request = Self::build_request(request)?;
+            let mut output = self.client.converse_stream()
+                .model_id(self.model_id.to_owned())
+                .set_system(Some(converse_request.system))
+                .set_messages(Some(converse_request.messages))
+                .inference_config(converse_request.inference_config)
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            // The usage arrives in the metadata event after the stop event, so the final chunk is
+            // held back until the end of the stream to report both together.
+            let stream: GenerationStream = Box::pin(try_stream! {
+                let mut last_chunk: Option<GenerationChunk> = None;
+                while let Some(event) = output.stream.recv().await.map_err(Error::from_sdk)? {
+                    match (Self::parse_stream_chunk(&event), last_chunk.as_mut()) {
+                        (Some(chunk), _) if chunk.finish_reason.is_some() => last_chunk = Some(chunk),
+                        (Some(chunk), Some(last_chunk)) => last_chunk.usage.merge(&chunk.usage),
+                        (Some(chunk), None) => yield chunk,
+                        (None, _) => continue,
+                    }
+                }
+                if let Some(chunk) = last_chunk {
+                    yield chunk;
+                }
+            });
+            Ok(stream)
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_bedrockruntime::types::{ContentBlockDeltaEvent, ConverseStreamMetadataEvent, MessageStopEvent};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let request = GenerateRequest {
+            messages: vec![
+                ChatCompletionsMessage::new("system", "You are a pirate."),
+                ChatCompletionsMessage::new("user", "Hello."),
+                ChatCompletionsMessage::new("user", "Who are you?"),
+                ChatCompletionsMessage::new("assistant", "Arr!"),
+            ],
+            max_tokens: Some(100),
+            temperature: Some(0.2),
+            stop: vec!["END".to_owned()],
+            ..Default::default()
+        };
+        let converse_request = BedrockConverseBackend::build_request(&request).unwrap();
+        assert_eq!(converse_request.system, vec![SystemContentBlock::Text("You are a pirate.".to_owned())]);
+        assert_eq!(converse_request.messages.len(), 2);
+        assert_eq!(converse_request.messages[0].role, ConversationRole::User);
+        assert_eq!(converse_request.messages[0].content, vec![
+            ContentBlock::Text("Hello.".to_owned()),
+            ContentBlock::Text("Who are you?".to_owned()),
+        ]);
+        assert_eq!(converse_request.messages[1].role, ConversationRole::Assistant);
+        assert_eq!(converse_request.inference_config.max_tokens, Some(100));
+        assert_eq!(converse_request.inference_config.temperature, Some(0.2));
+        assert_eq!(converse_request.inference_config.stop_sequences, Some(vec!["END".to_owned()]));
+
+        let request = GenerateRequest {
+            messages: vec![ChatCompletionsMessage::new("tool", "{}")],
+            ..Default::default()
+        };
+        assert!(BedrockConverseBackend::build_request(&request).is_err());
+
+        let request = GenerateRequest {
+            messages: vec![ChatCompletionsMessage::new("user", "Hello.")],
+            max_tokens: Some(i64::from(i32::MAX) + 1),
+            ..Default::default()
+        };
+        let err = Error::from(BedrockConverseBackend::build_request(&request).unwrap_err());
+        assert_eq!(err.to_response().error.param.as_deref(), Some("max_tokens"));
+    }
+
+    #[test]
+    fn test_build_request_images() {
+        let request = GenerateRequest {
+            messages: serde_json::from_value(json!([{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            ]}])).unwrap(),
+            ..Default::default()
+        };
+        let converse_request = BedrockConverseBackend::build_request(&request).unwrap();
+        assert_eq!(converse_request.messages[0].content, vec![
+            ContentBlock::Text("What is this?".to_owned()),
+            ContentBlock::Image(ImageBlock::builder()
+                .format(ImageFormat::Png)
+                .source(ImageSource::Bytes(Blob::new(b"\x89PNG\r\n\x1a\n".to_vec())))
+                .build()
+                .unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let output = ConverseOutput::builder()
+            .output(ConverseOutputMessage::Message(Message::builder()
+                .role(ConversationRole::Assistant)
+                .content(ContentBlock::Text("Arr, ".to_owned()))
+                .content(ContentBlock::Text("matey!".to_owned()))
+                .build()
+                .unwrap()))
+            .stop_reason(StopReason::MaxTokens)
+            .usage(TokenUsage::builder().input_tokens(12).output_tokens(3).total_tokens(15).build().unwrap())
+            .build()
+            .unwrap();
+
+        assert_eq!(BedrockConverseBackend::parse_response(&output).unwrap(), Generation {
+            text: "Arr, matey!".to_owned(),
+            finish_reason: Some("length".to_owned()),
+            usage: Usage { prompt_tokens: Some(12), completion_tokens: Some(3) },
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_parse_stream_chunk() {
+        let delta = ConverseStreamOutput::ContentBlockDelta(ContentBlockDeltaEvent::builder()
+            .content_block_index(0)
+            .delta(ContentBlockDelta::Text("Arr".to_owned()))
+            .build()
+            .unwrap());
+        assert_eq!(BedrockConverseBackend::parse_stream_chunk(&delta), Some(GenerationChunk {
+            text: "Arr".to_owned(),
+            ..Default::default()
+        }));
+
+        let stop = ConverseStreamOutput::MessageStop(MessageStopEvent::builder()
+            .stop_reason(StopReason::EndTurn)
+            .build()
+            .unwrap());
+        assert_eq!(BedrockConverseBackend::parse_stream_chunk(&stop), Some(GenerationChunk {
+            finish_reason: Some("stop".to_owned()),
+            ..Default::default()
+        }));
+
+        let metadata = ConverseStreamOutput::Metadata(ConverseStreamMetadataEvent::builder()
+            .usage(TokenUsage::builder().input_tokens(12).output_tokens(3).total_tokens(15).build().unwrap())
+            .build());
+        assert_eq!(BedrockConverseBackend::parse_stream_chunk(&metadata), Some(GenerationChunk {
+            usage: Usage { prompt_tokens: Some(12), completion_tokens: Some(3) },
+            ..Default::default()
+        }));
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::endpoint_loader::CircuitBreakerConfig;
+use crate::error::Error;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Calls are sent to the target.
+    Closed,
+    /// Calls are not sent to the target until the cooldown ends.
+    Open,
+    /// Trial calls are sent to the target, which close the circuit if they succeed.
+    HalfOpen,
+}
+
+/// Statistics of the calls of a target and state of its circuit.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CircuitStats {
+    pub state: CircuitState,
+    /// Failure rate of the recent calls.
+    pub failure_rate: f64,
+    pub calls: u64,
+    pub failures: u64,
+    /// Number of times the circuit opened.
+    pub opened: u64,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    /// Whether each of the recent calls failed, while the circuit is closed.
+    window: VecDeque<bool>,
+    opened_at: Option<Instant>,
+    /// Trial calls in flight while the circuit is half-open.
+    trials: u32,
+    calls: u64,
+    failures: u64,
+    opened: u64,
+}
+
+/// Circuit breaker of a target. Without config, the circuit is always closed and only the
+/// statistics of the calls are kept.
+///
+/// An open circuit turns half-open when a call is admitted after the cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: Option<CircuitBreakerConfig>,
+    circuit: Mutex<Circuit>,
+}
+
+/// Whether a call failed with an error which shows that the target is unhealthy. Invalid
+/// requests and throttling do not.
+pub fn is_failure(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<Error>() {
+        Some(err) => matches!(err, Error::Model(_) | Error::Unavailable(_) | Error::Upstream(_) | Error::Timeout(_)),
+        None => true,
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: Option<CircuitBreakerConfig>) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                window: VecDeque::new(),
+                opened_at: None,
+                trials: 0,
+                calls: 0,
+                failures: 0,
+                opened: 0,
+            }),
+        }
+    }
+
+    /// Whether a call may be admitted, without admitting it.
+    pub fn available(&self) -> bool {
+        let Some(config) = self.config else {
+            return true;
+        };
+        let circuit = self.circuit.lock().unwrap();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => cooled_down(&circuit, &config),
+            CircuitState::HalfOpen => circuit.trials < config.trial_calls,
+        }
+    }
+
+    /// Admit a call, returning whether it is a trial call, or `None` if the circuit is open.
+    /// The outcome of an admitted call must be recorded.
+    pub fn admit(&self) -> Option<bool> {
+        let Some(config) = self.config else {
+            return Some(false);
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        if circuit.state == CircuitState::Open && cooled_down(&circuit, &config) {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trials = 0;
+        }
+        match circuit.state {
+            CircuitState::Closed => Some(false),
+            CircuitState::HalfOpen if circuit.trials < config.trial_calls => {
+                circuit.trials += 1;
+                Some(true)
+            }
+            _ => None,
+        }
+    }
+
+    /// Record the outcome of an admitted call.
+    pub fn record(&self, target: &str, trial: bool, failed: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.calls += 1;
+        circuit.failures += failed as u64;
+        let Some(config) = self.config else {
+            return;
+        };
+        match circuit.state {
+            CircuitState::Closed => {
+                circuit.window.push_back(failed);
+                if circuit.window.len() > config.window as usize {
+                    circuit.window.pop_front();
+                }
+                if circuit.window.len() >= config.min_calls as usize && failure_rate(&circuit.window) >= config.failure_rate {
+                    warn!("opening circuit of target {}: {} of the last {} calls failed",
+                        target, circuit.window.iter().filter(|failed| **failed).count(), circuit.window.len());
+                    open(&mut circuit);
+                }
+            }
+            // Calls admitted before the circuit opened do not change its state.
+            CircuitState::HalfOpen if trial => {
+                circuit.trials -= 1;
+                if failed {
+                    warn!("reopening circuit of target {} after a failed trial call", target);
+                    open(&mut circuit);
+                } else {
+                    circuit.state = CircuitState::Closed;
+                    circuit.window.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn stats(&self) -> CircuitStats {
+        let circuit = self.circuit.lock().unwrap();
+        CircuitStats {
+            state: circuit.state,
+            failure_rate: failure_rate(&circuit.window),
+            calls: circuit.calls,
+            failures: circuit.failures,
+            opened: circuit.opened,
+        }
+    }
+}
+
+fn open(circuit: &mut Circuit) {
+    circuit.state = CircuitState::Open;
+    circuit.opened_at = Some(Instant::now());
+    circuit.opened += 1;
+    circuit.window.clear();
+}
+
+fn cooled_down(circuit: &Circuit, config: &CircuitBreakerConfig) -> bool {
+    circuit.opened_at.is_none_or(|opened_at| opened_at.elapsed() >= Duration::from_millis(config.cooldown_ms))
+}
+
+fn failure_rate(window: &VecDeque<bool>) -> f64 {
+    if window.is_empty() {
+        return 0.0;
+    }
+    window.iter().filter(|failed| **failed).count() as f64 / window.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown_ms: u64) -> CircuitBreaker {
+        CircuitBreaker::new(Some(CircuitBreakerConfig { window: 4, min_calls: 2, failure_rate: 0.5, cooldown_ms, trial_calls: 1 }))
+    }
+
+    fn call(breaker: &CircuitBreaker, failed: bool) -> Option<bool> {
+        let trial = breaker.admit()?;
+        breaker.record("a", trial, failed);
+        Some(trial)
+    }
+
+    #[test]
+    fn test_open() {
+        let breaker = breaker(60_000);
+        assert_eq!(call(&breaker, true), Some(false));
+        assert_eq!(breaker.stats().state, CircuitState::Closed);
+        assert_eq!(call(&breaker, false), Some(false));
+        assert_eq!(breaker.stats().state, CircuitState::Open);
+        assert!(!breaker.available());
+        assert_eq!(breaker.admit(), None);
+        assert_eq!(breaker.stats(), CircuitStats { state: CircuitState::Open, failure_rate: 0.0, calls: 2, failures: 1, opened: 1 });
+
+        let breaker = CircuitBreaker::new(None);
+        for _ in 0..10 {
+            assert_eq!(call(&breaker, true), Some(false));
+        }
+        assert_eq!(breaker.stats().state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open() {
+        let breaker = breaker(0);
+        call(&breaker, true);
+        call(&breaker, true);
+        assert!(breaker.available());
+
+        // A single trial call is admitted at a time.
+        assert_eq!(breaker.admit(), Some(true));
+        assert_eq!(breaker.stats().state, CircuitState::HalfOpen);
+        assert!(!breaker.available());
+        assert_eq!(breaker.admit(), None);
+        breaker.record("a", true, true);
+        assert_eq!(breaker.stats().state, CircuitState::Open);
+        assert_eq!(breaker.stats().opened, 2);
+
+        assert_eq!(call(&breaker, false), Some(true));
+        assert_eq!(breaker.stats().state, CircuitState::Closed);
+        assert_eq!(call(&breaker, false), Some(false));
+    }
+
+    #[test]
+    fn test_is_failure() {
+        assert!(is_failure(&Error::Timeout("timed out".to_owned()).into()));
+        assert!(is_failure(&anyhow::anyhow!("unexpected response")));
+        assert!(!is_failure(&Error::RateLimited("throttled".to_owned()).into()));
+        assert!(!is_failure(&Error::invalid_request("invalid request").into()));
+    }
+}
@@ -0,0 +1,286 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::endpoint_loader::StreamFormat;
+
+/// Key of the generated text in the streamed document of the `json` format.
+const GENERATED_TEXT_KEY: &[u8] = b"\"generated_text\"";
+
+/// Decoded event of an LMI/TGI response stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Piece of the generated text, for the `json` and `text` formats.
+    Text(String),
+    /// JSON document of a line or server-sent event, for the `jsonlines` and `sse` formats.
+    Json(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonState {
+    /// Before the opening quote of the generated text.
+    Start,
+    /// Inside the generated text string.
+    Text,
+    /// After the closing quote of the generated text. The rest of the document is ignored.
+    End,
+}
+
+/// Incremental decoder of the payload parts of `InvokeEndpointWithResponseStream`.
+///
+/// Payload parts are split at arbitrary bytes, so incomplete lines, escape sequences and UTF-8
+/// sequences are held back until the part completing them arrives.
+#[derive(Debug)]
+pub struct StreamDecoder {
+    format: StreamFormat,
+    /// Bytes received but not decoded yet.
+    buffer: Vec<u8>,
+    json_state: JsonState,
+    /// `data` lines of the current server-sent event.
+    data: Vec<u8>,
+}
+
+impl StreamDecoder {
+    pub fn new(format: StreamFormat) -> StreamDecoder {
+        StreamDecoder {
+            format,
+            buffer: vec![],
+            json_state: JsonState::Start,
+            data: vec![],
+        }
+    }
+
+    /// Decode a payload part, returning the events it completes.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<StreamEvent>> {
+        self.buffer.extend_from_slice(bytes);
+        self.decode(false)
+    }
+
+    /// Decode the remaining bytes at the end of the stream.
+    pub fn finish(&mut self) -> Result<Vec<StreamEvent>> {
+        self.decode(true)
+    }
+
+    fn decode(&mut self, end: bool) -> Result<Vec<StreamEvent>> {
+        let mut events = vec![];
+        match self.format {
+            StreamFormat::Json => {
+                if let Some(text) = self.decode_json(end)? {
+                    events.push(StreamEvent::Text(text));
+                }
+            }
+            StreamFormat::Text => {
+                let len = utf8_len(&self.buffer, end)?;
+                let text = String::from_utf8(self.buffer.drain(..len).collect())?;
+                if !text.is_empty() {
+                    events.push(StreamEvent::Text(text));
+                }
+            }
+            StreamFormat::Jsonlines | StreamFormat::Sse => {
+                while let Some(pos) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+                    events.extend(self.decode_line(&line));
+                }
+                if end {
+                    let line = std::mem::take(&mut self.buffer);
+                    events.extend(self.decode_line(&line));
+                    events.extend(self.decode_line(b""));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Decode the generated text of the `{"generated_text": "..."}` document received so far.
+    fn decode_json(&mut self, end: bool) -> Result<Option<String>> {
+        if self.json_state == JsonState::Start {
+            match text_start(&self.buffer)? {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                    self.json_state = JsonState::Text;
+                }
+                None if end && !self.buffer.trim_ascii().is_empty() => {
+                    bail!("unexpected response stream: {}", String::from_utf8_lossy(&self.buffer));
+                }
+                None => return Ok(None),
+            }
+        }
+        if self.json_state == JsonState::End {
+            self.buffer.clear();
+            return Ok(None);
+        }
+
+        let (len, closed) = escaped_len(&self.buffer);
+        let len = if closed || end { len } else { utf8_len(&self.buffer[..len], false)? };
+        let mut string = Vec::with_capacity(len + 2);
+        string.push(b'"');
+        string.extend(self.buffer.drain(..len));
+        string.push(b'"');
+        if closed {
+            self.json_state = JsonState::End;
+            self.buffer.clear();
+        } else if end && !self.buffer.is_empty() {
+            bail!("incomplete escape sequence at the end of the response stream");
+        }
+
+        let text: String = serde_json::from_slice(&string)
+            .map_err(|err| anyhow!("invalid generated text in response stream: {}", err))?;
+        Ok(if text.is_empty() { None } else { Some(text) })
+    }
+
+    /// Decode a line of the `jsonlines` or `sse` formats. Lines of `jsonlines` may be prefixed with
+    /// `data:`, and `[DONE]` markers are skipped.
+    fn decode_line(&mut self, line: &[u8]) -> Option<StreamEvent> {
+        let line = line.trim_ascii();
+        if self.format == StreamFormat::Jsonlines {
+            let json = line.strip_prefix(b"data:").unwrap_or(line).trim_ascii();
+            return json_event(json);
+        }
+
+        // Server-sent events end with an empty line, and may have several `data` lines joined by
+        // new lines. Other fields and comments are ignored.
+        if line.is_empty() {
+            let data = std::mem::take(&mut self.data);
+            return json_event(&data);
+        }
+        if let Some(data) = line.strip_prefix(b"data:") {
+            if !self.data.is_empty() {
+                self.data.push(b'\n');
+            }
+            self.data.extend_from_slice(data.trim_ascii());
+        }
+        None
+    }
+}
+
+fn json_event(json: &[u8]) -> Option<StreamEvent> {
+    if json.is_empty() || json == b"[DONE]" {
+        None
+    } else {
+        Some(StreamEvent::Json(json.to_vec()))
+    }
+}
+
+/// Position of the first byte of the generated text in `buffer`, or `None` if the opening quote
+/// has not been received yet.
+fn text_start(buffer: &[u8]) -> Result<Option<usize>> {
+    let Some(key) = buffer.windows(GENERATED_TEXT_KEY.len()).position(|window| window == GENERATED_TEXT_KEY) else {
+        return Ok(None);
+    };
+    let mut colon = false;
+    for (pos, byte) in buffer.iter().enumerate().skip(key + GENERATED_TEXT_KEY.len()) {
+        match byte {
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            b':' if !colon => colon = true,
+            b'"' if colon => return Ok(Some(pos + 1)),
+            _ => bail!("unexpected response stream: {}", String::from_utf8_lossy(buffer)),
+        }
+    }
+    Ok(None)
+}
+
+/// Length of the JSON string content at the start of `buffer` which does not end with an
+/// incomplete escape sequence, and whether the closing quote follows it.
+///
+/// A `\u` escape of a high surrogate is only complete with the escape of the low surrogate.
+fn escaped_len(buffer: &[u8]) -> (usize, bool) {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        match buffer[pos] {
+            b'"' => return (pos, true),
+            b'\\' => {
+                let len = match buffer.get(pos + 1) {
+                    None => return (pos, false),
+                    Some(b'u') => match buffer.get(pos + 2..pos + 6) {
+                        None => return (pos, false),
+                        Some(hex) if is_high_surrogate(hex) => 12,
+                        Some(_) => 6,
+                    },
+                    Some(_) => 2,
+                };
+                if pos + len > buffer.len() {
+                    return (pos, false);
+                }
+                pos += len;
+            }
+            _ => pos += 1,
+        }
+    }
+    (pos, false)
+}
+
+fn is_high_surrogate(hex: &[u8]) -> bool {
+    std::str::from_utf8(hex).ok()
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+        .is_some_and(|code| (0xD800..0xDC00).contains(&code))
+}
+
+/// Length of `bytes` without an incomplete UTF-8 sequence at the end, which is an error at the
+/// end of the stream.
+fn utf8_len(bytes: &[u8], end: bool) -> Result<usize> {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => Ok(bytes.len()),
+        Err(err) if err.error_len().is_none() && !end => Ok(err.valid_up_to()),
+        Err(err) => Err(anyhow!("response stream is not UTF-8: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `parts`, returning the text of the events or the JSON events as strings.
+    fn decode(format: StreamFormat, parts: &[&[u8]]) -> Result<Vec<String>> {
+        let mut decoder = StreamDecoder::new(format);
+        let mut events = vec![];
+        for part in parts {
+            events.extend(decoder.push(part)?);
+        }
+        events.extend(decoder.finish()?);
+        Ok(events.into_iter()
+            .map(|event| match event {
+                StreamEvent::Text(text) => text,
+                StreamEvent::Json(json) => String::from_utf8(json).unwrap(),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_json() {
+        let parts: [&[u8]; 3] = [b"{\"generated_text\": \"Hi", b" there", b"!\"}"];
+        assert_eq!(decode(StreamFormat::Json, &parts).unwrap(), vec!["Hi", " there", "!"]);
+
+        let parts: [&[u8]; 5] = [b"{\"generated_", b"text\":\"", br#"Say \"hi\"\"#, b"n\xc3\xa9\\ud83d", br#"\ude00"}"#];
+        assert_eq!(decode(StreamFormat::Json, &parts).unwrap(), vec!["Say \"hi\"", "\n\u{e9}", "\u{1f600}"]);
+
+        let parts: [&[u8]; 3] = [b"{\"generated_text\": \"caf\xc3", b"\xa9 \xe2\x98", b"\x95\"}"];
+        assert_eq!(decode(StreamFormat::Json, &parts).unwrap(), vec!["caf", "é ", "☕"]);
+
+        assert!(decode(StreamFormat::Json, &[b"{\"error\": \"model failed\"}"]).is_err());
+        assert!(decode(StreamFormat::Json, &[b"{\"generated_text\": \"Hi\\"]).is_err());
+        assert!(decode(StreamFormat::Json, &[b"{\"generated_text\": 1}"]).is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        let parts: [&[u8]; 3] = [b"caf\xc3", b"\xa9", b" au lait"];
+        assert_eq!(decode(StreamFormat::Text, &parts).unwrap(), vec!["caf", "é", " au lait"]);
+        assert!(decode(StreamFormat::Text, &[b"caf\xc3"]).is_err());
+        assert!(decode(StreamFormat::Text, &[b"caf\xff"]).is_err());
+    }
+
+    #[test]
+    fn test_jsonlines() {
+        let parts: [&[u8]; 3] = [b"{\"token\": {\"text\": \"Hi\"}}\n{\"tok", b"en\": {\"text\": \"!\"}}\n\n", b"data: {\"token\": {\"text\": \"\"}}"];
+        assert_eq!(decode(StreamFormat::Jsonlines, &parts).unwrap(), vec![
+            r#"{"token": {"text": "Hi"}}"#,
+            r#"{"token": {"text": "!"}}"#,
+            r#"{"token": {"text": ""}}"#,
+        ]);
+    }
+
+    #[test]
+    fn test_sse() {
+        let parts: [&[u8]; 4] = [b": keep-alive\n\ndata:{\"token\": {\"text\": \"Hi\"}}\r\n\r", b"\nevent: message\ndata: {\"a\":\n", b"data: 1}\n\n", b"data: [DONE]\n\n"];
+        assert_eq!(decode(StreamFormat::Sse, &parts).unwrap(), vec![r#"{"token": {"text": "Hi"}}"#, "{\"a\":\n1}"]);
+        assert_eq!(decode(StreamFormat::Sse, &[b"data: {}"]).unwrap(), vec!["{}"]);
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::stream::BoxStream;
+use serde_json::Value;
+
+pub use crate::backend::balancer::{BalancedBackend, TargetState};
+pub use crate::backend::bedrock::BedrockBackend;
+pub use crate::backend::breaker::{CircuitState, CircuitStats};
+pub use crate::backend::bedrock_converse::BedrockConverseBackend;
+pub use crate::backend::retry::RetryBackend;
+pub use crate::backend::sagemaker_embedding::SageMakerEmbeddingBackend;
+pub use crate::backend::sagemaker_lmi::SageMakerLmiBackend;
+use crate::endpoint_loader::{BackendKind, EndpointLoader, ModelKind};
+use crate::error::Error;
+use crate::timeout::Timeouts;
+use crate::types::ChatCompletionsMessage;
+
+mod balancer;
+mod bedrock;
+mod bedrock_converse;
+mod breaker;
+mod lmi_stream;
+mod retry;
+mod sagemaker_embedding;
+mod sagemaker_lmi;
+
+/// Backend-agnostic generation request built by the HTTP handlers.
+#[derive(Debug, Clone, Default)]
+pub struct GenerateRequest {
+    pub request_id: String,
+    /// Prompt rendered with the model's chat template.
+    pub prompt: String,
+    /// Original chat messages for backends which apply the chat template themselves.
+    pub messages: Vec<ChatCompletionsMessage>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i64>,
+    pub do_sample: Option<bool>,
+    /// Stop strings the backend should stop generating at.
+    pub stop: Vec<String>,
+    /// JSON schema of `response_format`, which backends with guided decoding constrain the output to.
+    pub json_schema: Option<Value>,
+    pub seed: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// Return the log probabilities of the generated tokens.
+    pub logprobs: bool,
+    /// Number of most likely alternatives returned for each token.
+    pub top_logprobs: Option<u32>,
+    /// First token and idle timeouts of streams, which are enforced per target so that they
+    /// count as failures of the target.
+    pub timeouts: Timeouts,
+}
+
+impl GenerateRequest {
+    /// Names of the optional sampling parameters set in the request.
+    pub fn sampling_params(&self) -> Vec<&'static str> {
+        [
+            ("seed", self.seed.is_some()),
+            ("presence_penalty", self.presence_penalty.is_some()),
+            ("frequency_penalty", self.frequency_penalty.is_some()),
+            ("repetition_penalty", self.repetition_penalty.is_some()),
+            ("logit_bias", self.logit_bias.as_ref().is_some_and(|logit_bias| !logit_bias.is_empty())),
+            ("logprobs", self.logprobs),
+        ].into_iter()
+            .filter_map(|(param, set)| set.then_some(param))
+            .collect()
+    }
+
+    /// Whether the messages contain images, which backends send to multimodal models with the messages.
+    pub fn has_images(&self) -> bool {
+        self.messages.iter().any(|message| message.images().next().is_some())
+    }
+}
+
+/// Token counts reported by the backend. Counts the backend does not report are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+}
+
+impl Usage {
+    /// Update with the counts reported in a later stream chunk.
+    pub fn merge(&mut self, other: &Usage) {
+        self.prompt_tokens = other.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = other.completion_tokens.or(self.completion_tokens);
+    }
+}
+
+/// Log probability of a generated token and of its most likely alternatives.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub top_logprobs: Vec<(String, f32)>,
+}
+
+/// Complete output of a non-streaming invocation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Generation {
+    pub text: String,
+    /// Finish reason reported by the model, if the backend provides one.
+    pub finish_reason: Option<String>,
+    pub usage: Usage,
+    /// Log probabilities of the tokens of `text`, when requested.
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// A single piece of a streaming invocation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationChunk {
+    pub text: String,
+    pub finish_reason: Option<String>,
+    /// Token counts reported so far. Completion tokens are cumulative.
+    pub usage: Usage,
+    /// Log probabilities of the tokens of `text`, when requested.
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+pub type GenerationStream = BoxStream<'static, Result<GenerationChunk>>;
+
+/// Embedding request for a batch of inputs.
+#[derive(Debug, Clone, Default)]
+pub struct EmbedRequest {
+    pub request_id: String,
+    pub inputs: Vec<String>,
+}
+
+/// Embedding vectors in the same order as `EmbedRequest::inputs`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Embeddings {
+    pub embeddings: Vec<Vec<f32>>,
+    pub usage: Usage,
+}
+
+/// An upstream model serving backend.
+///
+/// Each configured model gets its own backend instance, so implementations hold the
+/// target resource (endpoint, inference component, model id) alongside the client.
+pub trait Backend: Send + Sync + Debug {
+    /// Whether the backend takes `GenerateRequest::messages` as they are instead of a prompt
+    /// rendered with a chat template.
+    fn accepts_messages(&self) -> bool {
+        false
+    }
+
+    /// Optional sampling parameters of `GenerateRequest` which the backend sends to the model.
+    fn sampling_params(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Invoke the model and wait for the complete generation.
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>>;
+
+    /// Invoke the model and return the generation as a stream of chunks.
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>>;
+
+    /// Compute embeddings of the inputs.
+    fn embed<'a>(&'a self, _request: &'a EmbedRequest) -> BoxFuture<'a, Result<Embeddings>> {
+        async move { Err(Error::invalid_request("embeddings are not supported by the model").into()) }.boxed()
+    }
+}
+
+/// Reject sampling parameters the backend would otherwise silently ignore.
+pub fn check_sampling_params(backend: &dyn Backend, request: &GenerateRequest) -> Result<(), Error> {
+    match request.sampling_params().into_iter().find(|param| !backend.sampling_params().contains(param)) {
+        Some(param) => Err(Error::invalid_param(param, format!("{} is not supported by the model", param))),
+        None => Ok(()),
+    }
+}
+
+/// AWS clients shared by all backends.
+#[derive(Clone, Debug)]
+pub struct BackendClients {
+    pub sagemaker: Arc<aws_sdk_sagemakerruntime::Client>,
+    pub bedrock: Arc<aws_sdk_bedrockruntime::Client>,
+}
+
+impl BackendClients {
+    /// Clients of the targets in `region`, which default to the shared clients.
+    pub fn in_region(&self, region: Option<&str>) -> BackendClients {
+        let Some(region) = region else {
+            return self.clone();
+        };
+        let sagemaker = self.sagemaker.config().to_builder()
+            .region(aws_sdk_sagemakerruntime::config::Region::new(region.to_owned()))
+            .build();
+        let bedrock = self.bedrock.config().to_builder()
+            .region(aws_sdk_bedrockruntime::config::Region::new(region.to_owned()))
+            .build();
+        BackendClients {
+            sagemaker: Arc::new(aws_sdk_sagemakerruntime::Client::from_conf(sagemaker)),
+            bedrock: Arc::new(aws_sdk_bedrockruntime::Client::from_conf(bedrock)),
+        }
+    }
+}
+
+/// Backends keyed by model name. Requests are balanced across the targets of each model, and
+/// retried on failure.
+#[derive(Debug, Default)]
+pub struct Backends {
+    backends: HashMap<String, Arc<dyn Backend>>,
+    /// States of the targets of each model, in the order of the config.
+    targets: Vec<(String, Vec<Arc<TargetState>>)>,
+}
+
+impl Backends {
+    pub fn from_endpoints(endpoints: &EndpointLoader, clients: &BackendClients) -> Result<Backends> {
+        let mut backends: HashMap<String, Arc<dyn Backend>> = HashMap::new();
+        let mut target_states = vec![];
+        for endpoint in endpoints.endpoints() {
+            let mut targets = vec![];
+            for target in endpoint.targets()? {
+                let clients = clients.in_region(target.region.as_deref());
+                let backend: Arc<dyn Backend> = match (endpoint.kind, endpoint.backend) {
+                    (ModelKind::Chat, BackendKind::Lmi) => Arc::new(SageMakerLmiBackend::new(clients.sagemaker, endpoint, &target)?),
+                    (ModelKind::Chat, BackendKind::Bedrock) => Arc::new(BedrockBackend::new(clients.bedrock, endpoint, &target)?),
+                    (ModelKind::Chat, BackendKind::BedrockConverse) => Arc::new(BedrockConverseBackend::new(clients.bedrock, endpoint, &target)?),
+                    (ModelKind::Embeddings, BackendKind::Lmi) => Arc::new(SageMakerEmbeddingBackend::new(clients.sagemaker, endpoint, &target)?),
+                    (ModelKind::Embeddings, backend) => return Err(anyhow!("{:?} backend does not support embeddings: {}", backend, endpoint.model)),
+                };
+                targets.push((Arc::new(TargetState::new(&target, endpoint.circuit_breaker)), backend));
+            }
+            target_states.push((endpoint.model.to_owned(), targets.iter().map(|(state, _)| state.clone()).collect()));
+            let backend = Arc::new(BalancedBackend::new(targets, endpoint.load_balancing));
+            let backend = Arc::new(RetryBackend::new(backend, endpoint.retry));
+            if backends.insert(endpoint.model.to_owned(), backend).is_some() {
+                return Err(anyhow!("duplicated model: {}", endpoint.model));
+            }
+        }
+
+        Ok(Backends { backends, targets: target_states })
+    }
+
+    /// Backends of the models of `backends`, without targets, for tests of the handlers.
+    #[cfg(test)]
+    pub fn from_backends(backends: Vec<(&str, Arc<dyn Backend>)>) -> Backends {
+        Backends {
+            backends: backends.into_iter().map(|(model, backend)| (model.to_owned(), backend)).collect(),
+            targets: vec![],
+        }
+    }
+
+    pub fn get<S: AsRef<str>>(&self, model: S) -> Option<Arc<dyn Backend>> {
+        self.backends.get(model.as_ref()).cloned()
+    }
+
+    /// Models with the states of their targets.
+    pub fn targets(&self) -> &[(String, Vec<Arc<TargetState>>)] {
+        &self.targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct SeedBackend;
+
+    impl Backend for SeedBackend {
+        fn sampling_params(&self) -> &'static [&'static str] {
+            &["seed"]
+        }
+
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            async move { Ok(Generation::default()) }.boxed()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            async move { Err(anyhow!("not implemented")) }.boxed()
+        }
+    }
+
+    #[test]
+    fn test_check_sampling_params() {
+        let request = GenerateRequest { seed: Some(42), ..Default::default() };
+        assert!(check_sampling_params(&SeedBackend, &request).is_ok());
+
+        let request = GenerateRequest {
+            presence_penalty: Some(0.5),
+            logit_bias: Some(HashMap::from([("50256".to_owned(), -100.0)])),
+            ..request
+        };
+        assert_eq!(request.sampling_params(), vec!["seed", "presence_penalty", "logit_bias"]);
+        let err = check_sampling_params(&SeedBackend, &request).unwrap_err();
+        assert_eq!(err.to_response().error.param.as_deref(), Some("presence_penalty"));
+    }
+}
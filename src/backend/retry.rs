@@ -0,0 +1,176 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::{FutureExt, stream, StreamExt};
+use rand::Rng;
+use tokio::time::{Instant, sleep};
+use tracing::warn;
+
+use crate::backend::{Backend, EmbedRequest, Embeddings, GenerateRequest, Generation, GenerationStream};
+use crate::endpoint_loader::RetryConfig;
+use crate::error::Error;
+
+/// Backend retrying the calls of another backend which fail with a retryable error, with
+/// exponential backoff and full jitter.
+///
+/// Streaming calls are retried until the first chunk is received, as nothing has been sent to
+/// the client before.
+#[derive(Debug)]
+pub struct RetryBackend {
+    backend: Arc<dyn Backend>,
+    config: RetryConfig,
+}
+
+impl RetryBackend {
+    pub fn new(backend: Arc<dyn Backend>, config: RetryConfig) -> RetryBackend {
+        RetryBackend { backend, config }
+    }
+
+    /// Delay before retrying a call which failed at `attempt` with `err`, or `None` if the call
+    /// must not be retried.
+    fn retry_delay(&self, attempt: u32, started: Instant, err: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.config.max_attempts || !err.downcast_ref::<Error>().is_some_and(Error::is_retryable) {
+            return None;
+        }
+        let max_delay = self.config.base_delay_ms.saturating_mul(1 << (attempt - 1).min(20));
+        let delay = Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay));
+        (started.elapsed() + delay < Duration::from_millis(self.config.deadline_ms)).then_some(delay)
+    }
+
+    async fn retry<'a, T>(&'a self, request_id: &str, call: impl Fn() -> BoxFuture<'a, Result<T>>) -> Result<T> {
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let err = match call().await {
+                Ok(output) => return Ok(output),
+                Err(err) => err,
+            };
+            let Some(delay) = self.retry_delay(attempt, started, &err) else {
+                return Err(err);
+            };
+            warn!("retrying request {} in {:?} after attempt {}: {}", request_id, delay, attempt, err);
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Backend for RetryBackend {
+    fn accepts_messages(&self) -> bool {
+        self.backend.accepts_messages()
+    }
+
+    fn sampling_params(&self) -> &'static [&'static str] {
+        self.backend.sampling_params()
+    }
+
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        self.retry(&request.request_id, || self.backend.invoke(request)).boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        let call = move || async move {
+            let mut generation_stream = self.backend.invoke_stream(request).await?;
+            match generation_stream.next().await {
+                Some(Err(err)) => Err(err),
+                first => Ok(Box::pin(stream::iter(first).chain(generation_stream)) as GenerationStream),
+            }
+        }.boxed();
+        self.retry(&request.request_id, call).boxed()
+    }
+
+    fn embed<'a>(&'a self, request: &'a EmbedRequest) -> BoxFuture<'a, Result<Embeddings>> {
+        self.retry(&request.request_id, || self.backend.embed(request)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use anyhow::anyhow;
+
+    use crate::backend::GenerationChunk;
+
+    use super::*;
+
+    /// Backend failing its first calls with the errors returned by `error`.
+    #[derive(Debug)]
+    struct FlakyBackend {
+        failures: u32,
+        calls: AtomicU32,
+        error: fn() -> anyhow::Error,
+    }
+
+    impl FlakyBackend {
+        fn new(failures: u32, error: fn() -> anyhow::Error) -> Arc<FlakyBackend> {
+            Arc::new(FlakyBackend { failures, calls: AtomicU32::new(0), error })
+        }
+
+        fn fails(&self) -> bool {
+            self.calls.fetch_add(1, Ordering::SeqCst) < self.failures
+        }
+    }
+
+    impl Backend for FlakyBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            async move {
+                if self.fails() {
+                    return Err((self.error)());
+                }
+                Ok(Generation { text: "Hi".to_owned(), ..Default::default() })
+            }.boxed()
+        }
+
+        /// Streams fail on their first chunk, after the call succeeded.
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            async move {
+                let first = if self.fails() { Err((self.error)()) } else { Ok(GenerationChunk { text: "Hi".to_owned(), ..Default::default() }) };
+                let chunks = vec![first, Ok(GenerationChunk { text: "!".to_owned(), ..Default::default() })];
+                Ok(Box::pin(stream::iter(chunks)) as GenerationStream)
+            }.boxed()
+        }
+    }
+
+    fn throttled() -> anyhow::Error {
+        Error::RateLimited("throttled".to_owned()).into()
+    }
+
+    fn config(max_attempts: u32) -> RetryConfig {
+        RetryConfig { max_attempts, base_delay_ms: 1, deadline_ms: 10_000 }
+    }
+
+    #[tokio::test]
+    async fn test_invoke() {
+        let request = GenerateRequest::default();
+        let backend = FlakyBackend::new(2, throttled);
+        let generation = RetryBackend::new(backend.clone(), config(3)).invoke(&request).await.unwrap();
+        assert_eq!(generation.text, "Hi");
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 3);
+
+        let backend = FlakyBackend::new(3, throttled);
+        assert!(RetryBackend::new(backend.clone(), config(3)).invoke(&request).await.is_err());
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 3);
+
+        let backend = FlakyBackend::new(1, || anyhow!("unexpected response"));
+        assert!(RetryBackend::new(backend.clone(), config(3)).invoke(&request).await.is_err());
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 1);
+
+        let backend = FlakyBackend::new(1, throttled);
+        let config = RetryConfig { deadline_ms: 0, ..config(3) };
+        assert!(RetryBackend::new(backend.clone(), config).invoke(&request).await.is_err());
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invoke_stream() {
+        let request = GenerateRequest::default();
+        let backend = FlakyBackend::new(1, || Error::Unavailable("model not ready".to_owned()).into());
+        let generation_stream = RetryBackend::new(backend.clone(), config(2)).invoke_stream(&request).await.unwrap();
+        let chunks: Vec<String> = generation_stream.map(|chunk| chunk.unwrap().text).collect().await;
+        assert_eq!(chunks, vec!["Hi", "!"]);
+        assert_eq!(backend.calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use aws_sdk_sagemakerruntime as sagemakerruntime;
+use aws_sdk_sagemakerruntime::primitives::Blob;
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, StreamExt, TryStreamExt};
+use serde_json::json;
+
+use crate::backend::{Backend, EmbedRequest, Embeddings, GenerateRequest, Generation, GenerationStream, Usage};
+use crate::endpoint_loader::{Endpoint, Target};
+use crate::error::Error;
+
+const DEFAULT_BATCH_SIZE: usize = 32;
+/// Batches of a request sent to the endpoint at the same time.
+const MAX_CONCURRENT_BATCHES: usize = 4;
+
+/// SageMaker endpoint serving an embedding model with TEI or HuggingFace containers.
+#[derive(Debug)]
+pub struct SageMakerEmbeddingBackend {
+    client: Arc<sagemakerruntime::Client>,
+    endpoint_name: String,
+    inference_component: Option<String>,
+    target_model: Option<String>,
+    batch_size: usize,
+}
+
+impl SageMakerEmbeddingBackend {
+    pub fn new(client: Arc<sagemakerruntime::Client>, endpoint: &Endpoint, target: &Target) -> Result<SageMakerEmbeddingBackend> {
+        let endpoint_name = target.endpoint_name.to_owned()
+            .ok_or_else(|| anyhow!("endpoint_name must be set for embedding model: {}", endpoint.model))?;
+
+        Ok(SageMakerEmbeddingBackend {
+            client,
+            endpoint_name,
+            inference_component: target.inference_component.to_owned(),
+            target_model: target.target_model.to_owned(),
+            batch_size: endpoint.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+        })
+    }
+
+    pub fn build_request(inputs: &[String]) -> Blob {
+        Blob::new(json!({"inputs": inputs}).to_string())
+    }
+
+    /// Parse the embedding vectors, one per input, returned by TEI and sentence-transformers
+    /// models on the HuggingFace container.
+    pub fn parse_response(body: &[u8], num_inputs: usize) -> Result<Vec<Vec<f32>>> {
+        let embeddings: Vec<Vec<f32>> = serde_json::from_slice(body)
+            .map_err(|err| anyhow!("unexpected embeddings response: {}", err))?;
+        if embeddings.len() != num_inputs {
+            return Err(anyhow!("expected {} embeddings but got {}", num_inputs, embeddings.len()));
+        }
+
+        Ok(embeddings)
+    }
+
+    async fn invoke_batch(&self, request_id: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+        let output = self.client.invoke_endpoint()
+            .set_inference_id(Some(request_id.to_owned()))
+            .set_endpoint_name(Some(self.endpoint_name.to_owned()))
+            .set_inference_component_name(self.inference_component.to_owned())
+            .set_target_model(self.target_model.to_owned())
+            .set_body(Some(Self::build_request(inputs)))
+            .set_content_type(Some("application/json".to_owned()))
+            .send()
+            .await
+            .map_err(Error::from_sdk)?;
+
+        let body = output.body.ok_or_else(|| anyhow!("empty response body"))?;
+        Self::parse_response(body.as_ref(), inputs.len())
+    }
+}
+
+impl Backend for SageMakerEmbeddingBackend {
+    fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        async move { Err(Error::invalid_request("text generation is not supported by embedding model").into()) }.boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        async move { Err(Error::invalid_request("text generation is not supported by embedding model").into()) }.boxed()
+    }
+
+    fn embed<'a>(&'a self, request: &'a EmbedRequest) -> BoxFuture<'a, Result<Embeddings>> {
+        async move {
+            let batches: Vec<_> = request.inputs.chunks(self.batch_size)
+                .map(|inputs| self.invoke_batch(&request.request_id, inputs))
+                .collect();
+            let batches: Vec<Vec<Vec<f32>>> = stream::iter(batches)
+                .buffered(MAX_CONCURRENT_BATCHES)
+                .try_collect()
+                .await?;
+            let embeddings = batches.into_iter().flatten().collect();
+
+            Ok(Embeddings {
+                embeddings,
+                usage: Usage::default(),
+            })
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let body: Value = serde_json::from_slice(SageMakerEmbeddingBackend::build_request(&["a".to_owned(), "b".to_owned()]).as_ref()).unwrap();
+        assert_eq!(body, json!({"inputs": ["a", "b"]}));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let embeddings = SageMakerEmbeddingBackend::parse_response(b"[[0.1, 0.2], [0.3, 0.4]]", 2).unwrap();
+        assert_eq!(embeddings, vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+
+        assert!(SageMakerEmbeddingBackend::parse_response(b"[[0.1, 0.2]]", 2).is_err());
+        assert!(SageMakerEmbeddingBackend::parse_response(br#"{"generated_text": "hi"}"#, 1).is_err());
+    }
+}
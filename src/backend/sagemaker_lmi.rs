@@ -0,0 +1,448 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_stream::try_stream;
+use aws_sdk_sagemakerruntime as sagemakerruntime;
+use aws_sdk_sagemakerruntime::primitives::Blob;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde_json::json;
+
+use crate::backend::lmi_stream::{StreamDecoder, StreamEvent};
+use crate::backend::{Backend, GenerateRequest, Generation, GenerationChunk, GenerationStream, TokenLogprob, Usage};
+use crate::endpoint_loader::{Endpoint, GuidedDecoding, StreamFormat, Target};
+use crate::error::Error;
+use crate::types::{LmiChatLogprobs, LmiChatRequest, LmiChatResponse, PredictParams, SMPredictionOutput, SMPredictionRequest, SMStreamError, SMStreamToken, SMToken};
+
+/// SageMaker endpoint served by LMI/TGI containers.
+#[derive(Debug)]
+pub struct SageMakerLmiBackend {
+    client: Arc<sagemakerruntime::Client>,
+    endpoint_name: String,
+    inference_component: Option<String>,
+    target_model: Option<String>,
+    guided_decoding: Option<GuidedDecoding>,
+    stream_format: StreamFormat,
+}
+
+impl SageMakerLmiBackend {
+    pub fn new(client: Arc<sagemakerruntime::Client>, endpoint: &Endpoint, target: &Target) -> Result<SageMakerLmiBackend> {
+        let endpoint_name = target.endpoint_name.to_owned()
+            .ok_or_else(|| anyhow!("endpoint_name must be set for LMI backend: {}", endpoint.model))?;
+
+        Ok(SageMakerLmiBackend {
+            client,
+            endpoint_name,
+            inference_component: target.inference_component.to_owned(),
+            target_model: target.target_model.to_owned(),
+            guided_decoding: endpoint.guided_decoding,
+            stream_format: endpoint.stream_format,
+        })
+    }
+
+    /// Build the request payload. `details` asks LMI to return generation details such as
+    /// token counts and token logprobs, which is only available for non-streaming invocations.
+    /// The JSON schema of the request is sent with the endpoint's `guided_decoding` parameter, if any.
+    pub fn build_request(request: &GenerateRequest, details: bool, guided_decoding: Option<GuidedDecoding>) -> Blob {
+        let json_schema = guided_decoding.zip(request.json_schema.as_ref());
+        SMPredictionRequest {
+            inputs: request.prompt.to_owned(),
+            parameters: Some(PredictParams {
+                top_p: request.top_p,
+                top_k: request.top_k,
+                temperature: request.temperature,
+                max_new_tokens: request.max_tokens,
+                do_sample: request.do_sample,
+                details: if details { Some(true) } else { None },
+                top_n_tokens: request.top_logprobs.filter(|top_logprobs| request.logprobs && *top_logprobs > 0),
+                stop: if request.stop.is_empty() { None } else { Some(request.stop.to_owned()) },
+                seed: request.seed,
+                repetition_penalty: request.repetition_penalty,
+                frequency_penalty: request.frequency_penalty,
+                grammar: match json_schema {
+                    Some((GuidedDecoding::Grammar, schema)) => Some(json!({"type": "json", "value": schema})),
+                    _ => None,
+                },
+                guided_json: match json_schema {
+                    Some((GuidedDecoding::GuidedJson, schema)) => Some(schema.to_owned()),
+                    _ => None,
+                },
+            }),
+        }.serialize()
+    }
+
+    /// Build the chat completions payload for multimodal models, which take the messages with
+    /// their images instead of the rendered prompt. The JSON schema of the request is sent as
+    /// `guided_json`, or as a TGI `response_format` grammar, following the endpoint's `guided_decoding`.
+    pub fn build_chat_request(request: &GenerateRequest, stream: bool, guided_decoding: Option<GuidedDecoding>) -> Blob {
+        let json_schema = guided_decoding.zip(request.json_schema.as_ref());
+        LmiChatRequest {
+            messages: request.messages.to_owned(),
+            max_tokens: request.max_tokens,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            do_sample: request.do_sample,
+            stop: if request.stop.is_empty() { None } else { Some(request.stop.to_owned()) },
+            seed: request.seed,
+            repetition_penalty: request.repetition_penalty,
+            frequency_penalty: request.frequency_penalty,
+            logprobs: if request.logprobs { Some(true) } else { None },
+            top_logprobs: request.top_logprobs.filter(|_| request.logprobs),
+            response_format: match json_schema {
+                Some((GuidedDecoding::Grammar, schema)) => Some(json!({"type": "json", "value": schema})),
+                _ => None,
+            },
+            guided_json: match json_schema {
+                Some((GuidedDecoding::GuidedJson, schema)) => Some(schema.to_owned()),
+                _ => None,
+            },
+            stream: if stream { Some(true) } else { None },
+        }.serialize()
+    }
+
+    pub fn parse_chat_response(body: &[u8]) -> Result<Generation> {
+        let response: LmiChatResponse = serde_json::from_slice(body)?;
+        let choice = response.choices.into_iter().next().ok_or_else(|| anyhow!("chat completion has no choices"))?;
+
+        Ok(Generation {
+            text: choice.message.and_then(|message| message.content).unwrap_or_default(),
+            finish_reason: choice.finish_reason.as_deref().map(finish_reason),
+            usage: response.usage.map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+            }).unwrap_or_default(),
+            logprobs: choice.logprobs.map(chat_logprobs).unwrap_or_default(),
+        })
+    }
+
+    /// Parse a chat completion chunk of a chat completions stream.
+    pub fn parse_chat_event(json: &[u8]) -> Result<GenerationChunk> {
+        let response: LmiChatResponse = serde_json::from_slice(json)?;
+        let usage = response.usage.map(|usage| Usage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }).unwrap_or_default();
+        let Some(choice) = response.choices.into_iter().next() else {
+            return Ok(GenerationChunk { usage, ..Default::default() });
+        };
+
+        Ok(GenerationChunk {
+            text: choice.delta.and_then(|delta| delta.content).unwrap_or_default(),
+            finish_reason: choice.finish_reason.as_deref().map(finish_reason),
+            usage,
+            logprobs: choice.logprobs.map(chat_logprobs).unwrap_or_default(),
+        })
+    }
+
+    /// Parse a token event of a TGI stream or of an LMI JSON lines stream. Special tokens are not
+    /// part of the text, and error events fail the stream.
+    pub fn parse_token_event(json: &[u8]) -> Result<GenerationChunk> {
+        if let Ok(SMStreamError { error }) = serde_json::from_slice(json) {
+            return Err(Error::Model(error).into());
+        }
+        let event: SMStreamToken = serde_json::from_slice(json)?;
+        let details = event.details.unwrap_or_default();
+
+        Ok(GenerationChunk {
+            text: if event.token.special { String::new() } else { event.token.text.to_owned() },
+            finish_reason: details.finish_reason.as_deref().map(finish_reason),
+            usage: Usage {
+                prompt_tokens: details.prompt_tokens,
+                completion_tokens: details.generated_tokens,
+            },
+            logprobs: if event.token.special { vec![] } else { vec![token_logprob(event.token, event.top_tokens)] },
+        })
+    }
+
+    /// Convert a decoded stream event into a generation chunk.
+    fn parse_event(event: StreamEvent, chat: bool) -> Result<GenerationChunk> {
+        match event {
+            StreamEvent::Text(text) => Ok(GenerationChunk { text, ..Default::default() }),
+            StreamEvent::Json(json) if chat => Self::parse_chat_event(&json),
+            StreamEvent::Json(json) => Self::parse_token_event(&json),
+        }
+    }
+
+    pub fn parse_response(body: &[u8]) -> Result<Generation> {
+        let output: SMPredictionOutput = serde_json::from_slice(body)?;
+        let details = output.details.unwrap_or_default();
+
+        let mut top_tokens = details.top_tokens.into_iter();
+        let logprobs = details.tokens.into_iter()
+            .map(|token| (token, top_tokens.next().unwrap_or_default()))
+            .filter(|(token, _)| !token.special)
+            .map(|(token, top_tokens)| token_logprob(token, top_tokens))
+            .collect();
+
+        Ok(Generation {
+            text: output.generated_text,
+            finish_reason: details.finish_reason.as_deref().map(finish_reason),
+            usage: Usage {
+                prompt_tokens: details.prompt_tokens,
+                completion_tokens: details.generated_tokens,
+            },
+            logprobs,
+        })
+    }
+}
+
+fn token_logprob(token: SMToken, top_tokens: Vec<SMToken>) -> TokenLogprob {
+    TokenLogprob {
+        token: token.text,
+        logprob: token.logprob,
+        top_logprobs: top_tokens.into_iter().map(|token| (token.text, token.logprob)).collect(),
+    }
+}
+
+fn chat_logprobs(logprobs: LmiChatLogprobs) -> Vec<TokenLogprob> {
+    logprobs.content.into_iter()
+        .map(|logprob| TokenLogprob {
+            token: logprob.token,
+            logprob: logprob.logprob,
+            top_logprobs: logprob.top_logprobs.into_iter().map(|top| (top.token, top.logprob)).collect(),
+        })
+        .collect()
+}
+
+/// Map LMI finish reasons, such as `eos_token`, onto OpenAI finish reasons.
+fn finish_reason(finish_reason: &str) -> String {
+    match finish_reason {
+        "length" => "length",
+        _ => "stop",
+    }.to_owned()
+}
+
+impl Backend for SageMakerLmiBackend {
+    fn sampling_params(&self) -> &'static [&'static str] {
+        &["seed", "repetition_penalty", "frequency_penalty", "logprobs"]
+    }
+
+    fn invoke<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+        async move {
+            let chat = request.has_images();
+            let body = if chat {
+                Self::build_chat_request(request, false, self.guided_decoding)
+            } else {
+                Self::build_request(request, true, self.guided_decoding)
+            };
+            let output = self.client.invoke_endpoint()
+                .set_inference_id(Some(request.request_id.to_owned()))
+                .set_endpoint_name(Some(self.endpoint_name.to_owned()))
+                .set_inference_component_name(self.inference_component.to_owned())
+                .set_target_model(self.target_model.to_owned())
+                .set_body(Some(body))
+                .set_content_type(Some("application/json".to_owned()))
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            let body = output.body.ok_or_else(|| anyhow!("empty response body"))?;
+            if chat {
+                Self::parse_chat_response(body.as_ref())
+            } else {
+                Self::parse_response(body.as_ref())
+            }
+        }.boxed()
+    }
+
+    fn invoke_stream<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+        async move {
+            let chat = request.has_images();
+            // Chat completion chunks are JSON lines or server-sent events whatever the stream format,
+            // and logprobs are only sent with the tokens of the `jsonlines` and `sse` formats.
+            let stream_format = match self.stream_format {
+                StreamFormat::Json | StreamFormat::Text if chat => StreamFormat::Jsonlines,
+                StreamFormat::Json | StreamFormat::Text if request.logprobs => {
+                    return Err(Error::invalid_param("logprobs", "Streaming logprobs is not supported by the model").into());
+                }
+                stream_format => stream_format,
+            };
+            let body = if chat {
+                Self::build_chat_request(request, true, self.guided_decoding)
+            } else {
+                Self::build_request(request, false, self.guided_decoding)
+            };
+            let mut output = self.client.invoke_endpoint_with_response_stream()
+                .set_inference_id(Some(request.request_id.to_owned()))
+                .set_endpoint_name(Some(self.endpoint_name.to_owned()))
+                .set_inference_component_name(self.inference_component.to_owned())
+                .set_body(Some(body))
+                .set_content_type(Some("application/json".to_owned()))
+                .send()
+                .await
+                .map_err(Error::from_sdk)?;
+
+            let stream: GenerationStream = Box::pin(try_stream! {
+                let mut decoder = StreamDecoder::new(stream_format);
+                while let Some(response_stream) = output.body.recv().await.map_err(Error::from_sdk)? {
+                    let payload_part = response_stream.as_payload_part()
+                        .map_err(|_| anyhow!("unexpected event in response stream"))?;
+                    let Some(bytes) = payload_part.bytes.as_ref() else { continue };
+                    for event in decoder.push(bytes.as_ref())? {
+                        yield Self::parse_event(event, chat)?;
+                    }
+                }
+                for event in decoder.finish()? {
+                    yield Self::parse_event(event, chat)?;
+                }
+            });
+            Ok(stream)
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::types::ChatCompletionsMessage;
+
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        let request = GenerateRequest {
+            prompt: "Hello".to_owned(),
+            temperature: Some(0.5),
+            max_tokens: Some(128),
+            ..Default::default()
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, None).as_ref()).unwrap();
+        assert_eq!(body, json!({
+            "inputs": "Hello",
+            "parameters": {"temperature": 0.5, "max_new_tokens": 128},
+        }));
+
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, true, None).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["details"], json!(true));
+
+        let request = GenerateRequest {
+            stop: vec!["<|eot_id|>".to_owned()],
+            ..request
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, None).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["stop"], json!(["<|eot_id|>"]));
+
+        let request = GenerateRequest {
+            seed: Some(42),
+            repetition_penalty: Some(1.5),
+            ..request
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, None).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["seed"], json!(42));
+        assert_eq!(body["parameters"]["repetition_penalty"], json!(1.5));
+
+        let request = GenerateRequest {
+            json_schema: Some(json!({"type": "object"})),
+            ..request
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, None).as_ref()).unwrap();
+        assert_eq!(body["parameters"].get("guided_json"), None);
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, Some(GuidedDecoding::GuidedJson)).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["guided_json"], json!({"type": "object"}));
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, false, Some(GuidedDecoding::Grammar)).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["grammar"], json!({"type": "json", "value": {"type": "object"}}));
+
+        let request = GenerateRequest {
+            logprobs: true,
+            top_logprobs: Some(2),
+            ..request
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_request(&request, true, None).as_ref()).unwrap();
+        assert_eq!(body["parameters"]["top_n_tokens"], json!(2));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let generation = SageMakerLmiBackend::parse_response(br#"{"generated_text": "Hi there<|eot_id|>"}"#).unwrap();
+        assert_eq!(generation.text, "Hi there<|eot_id|>");
+        assert_eq!(generation.finish_reason, None);
+        assert_eq!(generation.usage, Usage::default());
+
+        let generation = SageMakerLmiBackend::parse_response(br#"{"generated_text": "Hi", "details": {"finish_reason": "length", "generated_tokens": 1, "inputs": "Hello", "tokens": []}}"#).unwrap();
+        assert_eq!(generation.finish_reason, Some("length".to_owned()));
+        assert_eq!(generation.usage, Usage { prompt_tokens: None, completion_tokens: Some(1) });
+
+        let generation = SageMakerLmiBackend::parse_response(br#"{"generated_text": "Hi", "details": {"finish_reason": "eos_token", "generated_tokens": 2}}"#).unwrap();
+        assert_eq!(generation.finish_reason, Some("stop".to_owned()));
+    }
+
+    #[test]
+    fn test_build_chat_request() {
+        let request = GenerateRequest {
+            prompt: "ignored".to_owned(),
+            messages: vec![ChatCompletionsMessage::new("user", "Hello")],
+            max_tokens: Some(128),
+            ..Default::default()
+        };
+        assert!(!request.has_images());
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_chat_request(&request, true, None).as_ref()).unwrap();
+        assert_eq!(body, json!({
+            "messages": [{"role": "user", "content": "Hello"}],
+            "max_tokens": 128,
+            "stream": true,
+        }));
+
+        let request = GenerateRequest {
+            top_k: Some(40),
+            do_sample: Some(true),
+            json_schema: Some(json!({"type": "object"})),
+            ..request
+        };
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_chat_request(&request, false, Some(GuidedDecoding::GuidedJson)).as_ref()).unwrap();
+        assert_eq!((&body["top_k"], &body["do_sample"], &body["guided_json"]), (&json!(40), &json!(true), &json!({"type": "object"})));
+        let body: Value = serde_json::from_slice(SageMakerLmiBackend::build_chat_request(&request, false, Some(GuidedDecoding::Grammar)).as_ref()).unwrap();
+        assert_eq!(body["response_format"], json!({"type": "json", "value": {"type": "object"}}));
+        assert!(body.get("guided_json").is_none());
+    }
+
+    #[test]
+    fn test_parse_chat_response() {
+        let generation = SageMakerLmiBackend::parse_chat_response(br#"{"choices": [{"index": 0, "message": {"role": "assistant", "content": "A cat."}, "finish_reason": "eos_token"}], "usage": {"prompt_tokens": 20, "completion_tokens": 3, "total_tokens": 23}}"#).unwrap();
+        assert_eq!(generation, Generation {
+            text: "A cat.".to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(20), completion_tokens: Some(3) },
+            ..Default::default()
+        });
+
+        let chunk = SageMakerLmiBackend::parse_chat_event(br#"{"choices": [{"index": 0, "delta": {"content": "A"}, "finish_reason": null}]}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk { text: "A".to_owned(), ..Default::default() });
+        let chunk = SageMakerLmiBackend::parse_chat_event(br#"{"choices": [{"index": 0, "delta": {}, "finish_reason": "length"}]}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk { finish_reason: Some("length".to_owned()), ..Default::default() });
+    }
+
+    #[test]
+    fn test_parse_response_logprobs() {
+        let generation = SageMakerLmiBackend::parse_response(br#"{"generated_text": "Hi!", "details": {"finish_reason": "eos_token", "generated_tokens": 3, "tokens": [
+            {"id": 13347, "text": "Hi", "log_prob": -0.25, "special": false},
+            {"id": 0, "text": "!", "logprob": -0.5, "special": false},
+            {"id": 128009, "text": "<|eot_id|>", "log_prob": 0.0, "special": true}
+        ], "top_tokens": [[{"text": "Hi", "logprob": -0.25}, {"text": "Hello", "logprob": -1.5}], [], []]}}"#).unwrap();
+        assert_eq!(generation.logprobs, vec![
+            TokenLogprob { token: "Hi".to_owned(), logprob: -0.25, top_logprobs: vec![("Hi".to_owned(), -0.25), ("Hello".to_owned(), -1.5)] },
+            TokenLogprob { token: "!".to_owned(), logprob: -0.5, top_logprobs: vec![] },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_token_event() {
+        let chunk = SageMakerLmiBackend::parse_token_event(br#"{"token": {"id": 13347, "text": "Hi", "logprob": -0.25, "special": false}, "top_tokens": [{"text": "Hello", "logprob": -1.5}]}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk {
+            text: "Hi".to_owned(),
+            logprobs: vec![TokenLogprob { token: "Hi".to_owned(), logprob: -0.25, top_logprobs: vec![("Hello".to_owned(), -1.5)] }],
+            ..Default::default()
+        });
+
+        let chunk = SageMakerLmiBackend::parse_token_event(br#"{"token": {"id": 128009, "text": "<|eot_id|>", "log_prob": 0.0, "special": true}, "generated_text": "Hi", "details": {"finish_reason": "eos_token", "generated_tokens": 2}}"#).unwrap();
+        assert_eq!(chunk, GenerationChunk {
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: None, completion_tokens: Some(2) },
+            ..Default::default()
+        });
+
+        let err = SageMakerLmiBackend::parse_token_event(br#"{"error": "Input validation error", "error_type": "validation"}"#).unwrap_err();
+        assert!(matches!(err.downcast::<Error>(), Ok(Error::Model(_))));
+    }
+}
@@ -1,6 +1,22 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
 use anyhow::{anyhow, Result};
+use minijinja::{context, Environment, ErrorKind};
+use serde::{Deserialize, Serialize};
+use serde_json::ser::PrettyFormatter;
+use serde_json::Value;
+
+use crate::endpoint_loader::{ChatTemplateConfig, ChatTemplatePreset, EndpointLoader};
+use crate::types::{ChatCompletionsMessage, Tool};
 
-use crate::types::ChatCompletionsMessage;
+/// Instruction prepended to the first user message when tools are given to Llama 3.1,
+/// following the JSON based tool calling format of the model card.
+const LLAMA31_TOOLS_PROMPT: &str = "Given the following functions, please respond with a JSON for a function call \
+    with its proper arguments that best answers the given prompt.\n\n\
+    Respond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}. \
+    Do not use variables.\n\n";
 
 pub fn apply_chat_template_llama3(messages: &[ChatCompletionsMessage]) -> String {
     let mut s = String::new();
@@ -10,13 +26,60 @@ pub fn apply_chat_template_llama3(messages: &[ChatCompletionsMessage]) -> String
         s.push_str(&m.role);
         s.push_str("<|end_header_id|>");
         s.push_str("\n\n");
-        s.push_str(&m.content);
+        s.push_str(&m.text());
         s.push_str("<|eot_id|>")
     }
     s.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
     s
 }
 
+/// Convert OpenAI tool calling messages into the Llama 3.1 format.
+///
+/// Tool results are sent with the `ipython` role, assistant tool calls are written as the JSON
+/// the model generates, and the tool definitions are prepended to the first user message.
+pub fn llama31_messages(messages: &[ChatCompletionsMessage], tools: Option<&[Tool]>) -> Result<Vec<ChatCompletionsMessage>> {
+    let mut messages: Vec<ChatCompletionsMessage> = messages.iter()
+        .map(|m| match (m.role.as_str(), m.tool_calls.as_ref()) {
+            ("tool", _) => ChatCompletionsMessage::new("ipython", m.text().as_str()),
+            ("assistant", Some(tool_calls)) => {
+                let content = tool_calls.iter()
+                    .map(|tool_call| {
+                        let parameters = serde_json::from_str::<Value>(&tool_call.function.arguments)
+                            .unwrap_or_else(|_| Value::String(tool_call.function.arguments.to_owned()));
+                        serde_json::json!({"name": tool_call.function.name, "parameters": parameters}).to_string()
+                    })
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                ChatCompletionsMessage::new("assistant", content.as_str())
+            }
+            _ => m.clone(),
+        })
+        .collect();
+
+    if let Some(tools) = tools.filter(|tools| !tools.is_empty()) {
+        let user = messages.iter_mut()
+            .find(|m| m.role == "user")
+            .ok_or_else(|| anyhow!("a user message is required to call tools"))?;
+        let mut content = LLAMA31_TOOLS_PROMPT.to_owned();
+        for tool in tools {
+            content.push_str(&to_pretty_json(tool)?);
+            content.push_str("\n\n");
+        }
+        content.push_str(&user.text());
+        user.set_text(content);
+    }
+
+    Ok(messages)
+}
+
+/// Serialize `value` with 4 space indentation as in the Llama 3.1 prompt examples.
+fn to_pretty_json<T: Serialize>(value: &T) -> Result<String> {
+    let mut json = vec![];
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, PrettyFormatter::with_indent(b"    "));
+    value.serialize(&mut serializer)?;
+    Ok(String::from_utf8(json)?)
+}
+
 pub fn apply_chat_template_phi3(messages: &[ChatCompletionsMessage]) -> String {
     let mut s = String::new();
     for m in messages {
@@ -27,7 +90,7 @@ pub fn apply_chat_template_phi3(messages: &[ChatCompletionsMessage]) -> String {
         } else {
             continue;
         }
-        s.push_str(m.content.as_str());
+        s.push_str(&m.text());
         s.push_str("<|end|>\n<|assistant|>\n");
     }
     s
@@ -44,7 +107,7 @@ pub fn apply_chat_template_nvidia_llama3_chatqa<S: AsRef<str>>(
         match m.role.as_str() {
             "system" => {
                 s.push_str("System: ");
-                s.push_str(m.content.as_str());
+                s.push_str(&m.text());
                 s.push_str("\n\n");
                 if let Some(context) = context.as_ref() {
                     let context = context.as_ref();
@@ -54,12 +117,12 @@ pub fn apply_chat_template_nvidia_llama3_chatqa<S: AsRef<str>>(
             }
             "user" => {
                 s.push_str("User: ");
-                s.push_str(m.content.as_str());
+                s.push_str(&m.text());
                 s.push_str("\n\n");
             }
             "assistant" => {
                 s.push_str("Assistant: ");
-                s.push_str(m.content.as_str());
+                s.push_str(&m.text());
                 s.push_str("\n\n");
             }
             role => return Err(anyhow!(format!("unknown role: {}", role)))
@@ -74,32 +137,265 @@ pub fn apply_chat_template_nvidia_llama3_chatqa<S: AsRef<str>>(
 pub fn apply_chat_template<S>(
     model: S,
     messages: &[ChatCompletionsMessage],
+    tools: Option<&[Tool]>,
     context: Option<String>) -> Result<String>
 where
     S: AsRef<str>,
 {
-    if model.as_ref().to_lowercase().starts_with("llama-3") {
-        Ok(apply_chat_template_llama3(messages))
+    let preset = if model.as_ref().to_lowercase().starts_with("llama-3") {
+        ChatTemplatePreset::Llama3
     } else if model.as_ref().to_lowercase().starts_with("phi-3") {
-        Ok(apply_chat_template_phi3(messages))
+        ChatTemplatePreset::Phi3
     } else if model.as_ref().to_lowercase() == "llama3-chatqa-1.5-8b" {
-        apply_chat_template_nvidia_llama3_chatqa(messages, context)
+        ChatTemplatePreset::ChatQa
+    } else {
+        return Err(anyhow!(format!("Unknown model {}", model.as_ref())));
+    };
+    ChatTemplate::Preset(preset).render(messages, tools, context)
+}
+
+/// Render chat messages into the prompt sent to the model.
+///
+/// Instruction-tuned models use their chat template while base models get the message
+/// contents concatenated.
+pub fn render_prompt<S: AsRef<str>>(
+    model: S,
+    messages: &[ChatCompletionsMessage],
+    tools: Option<&[Tool]>,
+    context: Option<String>) -> Result<String> {
+    let model = model.as_ref();
+    if model.to_lowercase().contains("-instruct") || model.eq("Llama3-ChatQA-1.5-8B") {
+        apply_chat_template(model, messages, tools, context)
+    } else if tools.is_some() {
+        Err(anyhow!("tools are not supported by the model"))
     } else {
-        Err(anyhow!(format!("Unknown model {}", model.as_ref())))
+        Ok(messages.iter().map(|m| m.text()).collect::<Vec<String>>().join("\n"))
+    }
+}
+
+/// End of turn token emitted by the model.
+pub fn eos_token<S: AsRef<str>>(model: S) -> Option<&'static str> {
+    if model.as_ref().starts_with("Llama") {
+        Some("<|eot_id|>")
+    } else if model.as_ref().starts_with("Phi-3") {
+        Some("<|end|>")
+    } else {
+        None
+    }
+}
+
+/// Chat template of a model, either built in or a Jinja template.
+#[derive(Debug)]
+pub enum ChatTemplate {
+    Preset(ChatTemplatePreset),
+    Jinja(Box<JinjaTemplate>),
+}
+
+impl ChatTemplate {
+    pub fn from_config(config: &ChatTemplateConfig) -> Result<ChatTemplate> {
+        match (config.preset, config.template.as_ref(), config.tokenizer_config.as_ref()) {
+            (Some(preset), None, None) => Ok(ChatTemplate::Preset(preset)),
+            (None, Some(template), None) => {
+                let template = JinjaTemplate::new(template.to_owned(), config.bos_token.to_owned(), config.eos_token.to_owned())?;
+                Ok(ChatTemplate::Jinja(Box::new(template)))
+            }
+            (None, None, Some(path)) => {
+                let tokenizer_config = TokenizerConfig::from_file(path)?;
+                let tool_use = tokenizer_config.chat_template("tool_use");
+                let mut template = JinjaTemplate::new(
+                    tokenizer_config.chat_template("default")
+                        .ok_or_else(|| anyhow!("chat_template is not found in {}", path))?,
+                    config.bos_token.to_owned().or_else(|| tokenizer_config.bos_token.map(|token| token.content())),
+                    config.eos_token.to_owned().or_else(|| tokenizer_config.eos_token.map(|token| token.content())),
+                )?;
+                if let Some(source) = tool_use {
+                    template.add_tool_use(source)?;
+                }
+                Ok(ChatTemplate::Jinja(Box::new(template)))
+            }
+            _ => Err(anyhow!("exactly one of preset, template and tokenizer_config must be set for chat_template")),
+        }
+    }
+
+    pub fn render(&self, messages: &[ChatCompletionsMessage], tools: Option<&[Tool]>, context: Option<String>) -> Result<String> {
+        match self {
+            ChatTemplate::Preset(ChatTemplatePreset::Llama3) => Ok(apply_chat_template_llama3(&llama31_messages(messages, tools)?)),
+            ChatTemplate::Preset(_) if tools.is_some() => Err(anyhow!("tools are not supported by the chat template")),
+            ChatTemplate::Preset(ChatTemplatePreset::Phi3) => Ok(apply_chat_template_phi3(messages)),
+            ChatTemplate::Preset(ChatTemplatePreset::ChatQa) => apply_chat_template_nvidia_llama3_chatqa(messages, context),
+            ChatTemplate::Jinja(template) => template.render(messages, tools, context),
+        }
+    }
+
+    /// End of turn token emitted by the model.
+    pub fn eos_token(&self) -> Option<&str> {
+        match self {
+            ChatTemplate::Preset(ChatTemplatePreset::Llama3 | ChatTemplatePreset::ChatQa) => Some("<|eot_id|>"),
+            ChatTemplate::Preset(ChatTemplatePreset::Phi3) => Some("<|end|>"),
+            ChatTemplate::Jinja(template) => template.eos_token.as_deref(),
+        }
+    }
+}
+
+/// HuggingFace chat template rendered the same way as `transformers` does.
+#[derive(Debug)]
+pub struct JinjaTemplate {
+    env: Environment<'static>,
+    /// Whether a `tool_use` template is used instead of the chat template for requests with tools.
+    tool_use: bool,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+}
+
+impl JinjaTemplate {
+    pub fn new(source: String, bos_token: Option<String>, eos_token: Option<String>) -> Result<JinjaTemplate> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_template_owned("chat_template", source)?;
+
+        Ok(JinjaTemplate {
+            env,
+            tool_use: false,
+            bos_token,
+            eos_token,
+        })
+    }
+
+    /// Add the template rendering requests with tools, as `transformers` does with the `tool_use` template.
+    pub fn add_tool_use(&mut self, source: String) -> Result<()> {
+        self.env.add_template_owned("tool_use", source)?;
+        self.tool_use = true;
+        Ok(())
+    }
+
+    pub fn render(&self, messages: &[ChatCompletionsMessage], tools: Option<&[Tool]>, context: Option<String>) -> Result<String> {
+        let name = if tools.is_some() && self.tool_use { "tool_use" } else { "chat_template" };
+        let template = self.env.get_template(name)?;
+        let prompt = template.render(context! {
+            messages,
+            tools,
+            context,
+            bos_token => self.bos_token.as_deref().unwrap_or_default(),
+            eos_token => self.eos_token.as_deref().unwrap_or_default(),
+            add_generation_prompt => true,
+        })?;
+
+        Ok(prompt)
+    }
+}
+
+/// Fields of a HuggingFace `tokenizer_config.json` used to render the chat template.
+#[derive(Deserialize, Debug)]
+struct TokenizerConfig {
+    chat_template: Option<TokenizerChatTemplate>,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TokenizerChatTemplate {
+    Template(String),
+    /// Named templates, of which `tool_use` is used for requests with tools and `default` otherwise.
+    Named(Vec<NamedChatTemplate>),
+}
+
+#[derive(Deserialize, Debug)]
+struct NamedChatTemplate {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    AddedToken { content: String },
+}
+
+impl SpecialToken {
+    fn content(self) -> String {
+        match self {
+            SpecialToken::Content(content) | SpecialToken::AddedToken { content } => content,
+        }
+    }
+}
+
+impl TokenizerConfig {
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<TokenizerConfig> {
+        let config = fs::read_to_string(path.as_ref())
+            .map_err(|err| anyhow!("unable to read {}: {}", path.as_ref().display(), err))?;
+        Ok(serde_json::from_str(&config)?)
+    }
+
+    /// Chat template with the given name. A single template is the `default` one.
+    fn chat_template(&self, name: &str) -> Option<String> {
+        match self.chat_template.as_ref()? {
+            TokenizerChatTemplate::Template(template) if name == "default" => Some(template.to_owned()),
+            TokenizerChatTemplate::Template(_) => None,
+            TokenizerChatTemplate::Named(templates) => templates.iter()
+                .find(|template| template.name == name)
+                .map(|template| template.template.to_owned()),
+        }
+    }
+}
+
+/// Chat templates keyed by model name.
+#[derive(Debug, Default)]
+pub struct ChatTemplates {
+    templates: HashMap<String, ChatTemplate>,
+}
+
+impl ChatTemplates {
+    pub fn from_endpoints(endpoints: &EndpointLoader) -> Result<ChatTemplates> {
+        let mut templates = HashMap::new();
+        for endpoint in endpoints.endpoints() {
+            if let Some(config) = endpoint.chat_template.as_ref() {
+                let template = ChatTemplate::from_config(config)
+                    .map_err(|err| anyhow!("invalid chat_template of {}: {}", endpoint.model, err))?;
+                templates.insert(endpoint.model.to_owned(), template);
+            }
+        }
+
+        Ok(ChatTemplates { templates })
+    }
+
+    /// Render the prompt with the model's configured chat template, falling back to the
+    /// built-in template matched by the model name.
+    pub fn render(&self, model: &str, messages: &[ChatCompletionsMessage], tools: Option<&[Tool]>, context: Option<String>) -> Result<String> {
+        match self.templates.get(model) {
+            Some(template) => template.render(messages, tools, context),
+            None => render_prompt(model, messages, tools, context),
+        }
+    }
+
+    /// End of turn token of the model's chat template.
+    pub fn eos_token(&self, model: &str) -> Option<String> {
+        match self.templates.get(model) {
+            Some(template) => template.eos_token().map(|token| token.to_owned()),
+            None => eos_token(model).map(|token| token.to_owned()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::types::{FunctionCall, ToolCall};
+
     use super::*;
 
     #[test]
     fn test_apply_chat_template_llama3() {
-        let mut messages = &[
+        let messages = &[
             ChatCompletionsMessage::new("system", "You are a pirate chatbot who always responds in pirate speak!"),
             ChatCompletionsMessage::new("user", "Who are you?"),
         ];
-        let mut expected = "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n\
+        let expected = "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n\
             You are a pirate chatbot who always responds in pirate speak!<|eot_id|>\
             <|start_header_id|>user<|end_header_id|>\n\n\
             Who are you?<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n".to_owned();
@@ -109,7 +405,7 @@ mod tests {
 
     #[test]
     fn test_apply_chat_template_llama31_tool_call() {
-        let mut messages = &[
+        let messages = &[
             ChatCompletionsMessage::new("system", "You are a helpful assistant with tool calling capabilities. When you receive a tool call response, use the output to format an answer to the orginal use question."),
             ChatCompletionsMessage::new("user", r#"Given the following functions, please respond with a JSON for a function call with its proper arguments that best answers the given prompt.
 
@@ -178,11 +474,61 @@ Question: what is the weather like in San Fransisco?<|eot_id|><|start_header_id|
 
 {"name": "get_current_conditions", "parameters": {"location": "San Francisco, CA", "unit": "Fahrenheit"}}<|eot_id|><|start_header_id|>ipython<|end_header_id|>
 
-{"output": "Clouds giving way to sun Hi: 76° Tonight: Mainly clear early, then areas of low clouds forming Lo: 56°"}<|eot_id|>"#;
+{"output": "Clouds giving way to sun Hi: 76° Tonight: Mainly clear early, then areas of low clouds forming Lo: 56°"}<|eot_id|><|start_header_id|>assistant<|end_header_id|>
+
+"#;
         println!("{}", apply_chat_template_llama3(messages));
         assert_eq!(apply_chat_template_llama3(messages), expected);
     }
 
+    #[test]
+    fn test_llama31_messages() {
+        let tools: Vec<Tool> = vec![serde_json::from_value(serde_json::json!({
+            "type": "function",
+            "function": {"name": "get_weather", "parameters": {"type": "object"}},
+        })).unwrap()];
+        let mut assistant = ChatCompletionsMessage::new("assistant", "");
+        assistant.content = None;
+        assistant.tool_calls = Some(vec![ToolCall {
+            index: None,
+            id: "call_1".to_owned(),
+            tool_type: "function".to_owned(),
+            function: FunctionCall { name: "get_weather".to_owned(), arguments: r#"{"location":"Paris"}"#.to_owned() },
+        }]);
+        let mut tool = ChatCompletionsMessage::new("tool", r#"{"temperature": 20}"#);
+        tool.tool_call_id = Some("call_1".to_owned());
+        let messages = &[
+            ChatCompletionsMessage::new("system", "You are a helpful assistant."),
+            ChatCompletionsMessage::new("user", "Weather in Paris?"),
+            assistant,
+            tool,
+        ];
+
+        assert_eq!(llama31_messages(messages, Some(&tools)).unwrap(), vec![
+            ChatCompletionsMessage::new("system", "You are a helpful assistant."),
+            ChatCompletionsMessage::new("user", r#"Given the following functions, please respond with a JSON for a function call with its proper arguments that best answers the given prompt.
+
+Respond in the format {"name": function name, "parameters": dictionary of argument name and its value}. Do not use variables.
+
+{
+    "type": "function",
+    "function": {
+        "name": "get_weather",
+        "parameters": {
+            "type": "object"
+        }
+    }
+}
+
+Weather in Paris?"#),
+            ChatCompletionsMessage::new("assistant", r#"{"name":"get_weather","parameters":{"location":"Paris"}}"#),
+            ChatCompletionsMessage::new("ipython", r#"{"temperature": 20}"#),
+        ]);
+
+        assert!(llama31_messages(&messages[..1], Some(&tools)).is_err());
+        assert!(ChatTemplate::Preset(ChatTemplatePreset::Phi3).render(&messages[..2], Some(&tools), None).is_err());
+    }
+
     #[test]
     fn test_apply_chat_template_nvidia_llama3_chatqa() {
         let messages = &[
@@ -230,4 +576,71 @@ User: Nice. What else can you do?
 Assistant: ";
         assert_eq!(apply_chat_template_nvidia_llama3_chatqa(messages, context.clone()).unwrap(), expected);
     }
+
+    const MISTRAL_TEMPLATE: &str = "{{ bos_token }}{% for message in messages %}\
+        {% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}\
+        {{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}\
+        {% if message['role'] == 'user' %}{{ '[INST] ' + message['content'].strip() + ' [/INST]' }}\
+        {% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token }}{% endif %}{% endfor %}";
+
+    #[test]
+    fn test_jinja_template() {
+        let template = JinjaTemplate::new(MISTRAL_TEMPLATE.to_owned(), Some("<s>".to_owned()), Some("</s>".to_owned())).unwrap();
+        let messages = &[
+            ChatCompletionsMessage::new("user", "Hello. "),
+            ChatCompletionsMessage::new("assistant", "Hi."),
+            ChatCompletionsMessage::new("user", "Who are you?"),
+        ];
+        assert_eq!(template.render(messages, None, None).unwrap(), "<s>[INST] Hello. [/INST]Hi.</s>[INST] Who are you? [/INST]");
+
+        let messages = &[ChatCompletionsMessage::new("assistant", "Hi.")];
+        let err = template.render(messages, None, None).unwrap_err();
+        assert!(err.to_string().contains("Conversation roles must alternate"));
+    }
+
+    #[test]
+    fn test_jinja_template_tools() {
+        let source = "{% if tools is not none %}{% for tool in tools %}{{ tool | tojson }}\n{% endfor %}{% endif %}\
+            {% for message in messages %}{{ message.content }}{% endfor %}\
+            {% if add_generation_prompt %}>{% endif %}";
+        let template = JinjaTemplate::new(source.to_owned(), None, None).unwrap();
+        let messages = &[ChatCompletionsMessage::new("user", "Weather?")];
+        let tools: Vec<Tool> = vec![serde_json::from_value(serde_json::json!({"type": "function", "function": {"name": "get_weather"}})).unwrap()];
+
+        assert_eq!(template.render(messages, Some(&tools), None).unwrap(), "{\"type\":\"function\",\"function\":{\"name\":\"get_weather\"}}\nWeather?>");
+        assert_eq!(template.render(messages, None, None).unwrap(), "Weather?>");
+    }
+
+    #[test]
+    fn test_chat_template_from_tokenizer_config() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("tokenizer_config.json");
+        let tokenizer_config = serde_json::json!({
+            "bos_token": {"content": "<s>", "lstrip": false, "normalized": false},
+            "eos_token": "</s>",
+            "chat_template": [
+                {"name": "default", "template": MISTRAL_TEMPLATE},
+                {"name": "tool_use", "template": "{% for tool in tools %}{{ tool.function.name }} {% endfor %}{{ messages[0].content }}"},
+            ],
+        });
+        fs::write(&path, tokenizer_config.to_string()).unwrap();
+
+        let config = ChatTemplateConfig {
+            tokenizer_config: Some(path.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let template = ChatTemplate::from_config(&config).unwrap();
+        let messages = &[ChatCompletionsMessage::new("user", "Hello.")];
+        assert_eq!(template.render(messages, None, None).unwrap(), "<s>[INST] Hello. [/INST]");
+        assert_eq!(template.eos_token(), Some("</s>"));
+        let tools: Vec<Tool> = vec![serde_json::from_value(serde_json::json!({"type": "function", "function": {"name": "get_weather"}})).unwrap()];
+        assert_eq!(template.render(messages, Some(&tools), None).unwrap(), "get_weather Hello.");
+
+        let config = ChatTemplateConfig {
+            preset: Some(ChatTemplatePreset::Phi3),
+            template: Some(MISTRAL_TEMPLATE.to_owned()),
+            ..Default::default()
+        };
+        assert!(ChatTemplate::from_config(&config).is_err());
+    }
 }
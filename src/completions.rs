@@ -0,0 +1,399 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_stream::stream as async_stream;
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures::Stream;
+use futures_util::StreamExt;
+use tokio::time::Instant;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::backend::{Backend, check_sampling_params, GenerateRequest, Generation, GenerationStream};
+use crate::error::{Error, Json};
+use crate::fallback::{self, FallbackRequest, Invocations};
+use crate::{logprobs, sse, stop, timeout};
+use crate::logprobs::LogprobsMatcher;
+use crate::stop::StopMatcher;
+use crate::timeout::Timeouts;
+use crate::tokenizer::{self, TokenCounter};
+use crate::types::{ChatCompletionsUsage, Completions, CompletionsChoice, CompletionsLogprobs, CompletionsResponse};
+
+/// Maximum number of most likely alternatives returned for each token with `logprobs`.
+const MAX_LOGPROBS: i32 = 5;
+
+/// Legacy text completion endpoint which sends the prompt to the model as it is.
+#[tracing::instrument(name = "Start Completion", skip(state, headers))]
+pub async fn completions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<Completions>,
+) -> Result<Response, Error> {
+    let req_id = Uuid::new_v4();
+    let started = Instant::now();
+    state.model(&payload.model)?;
+    let timeouts = Timeouts::from_headers(&headers)?;
+    if payload.suffix.as_ref().is_some_and(|suffix| !suffix.is_empty()) {
+        return Err(Error::invalid_param("suffix", "suffix is not supported"));
+    }
+    if payload.logprobs.is_some_and(|logprobs| !(0..=MAX_LOGPROBS).contains(&logprobs)) {
+        return Err(Error::invalid_param("logprobs", format!("logprobs must be between 0 and {}", MAX_LOGPROBS)));
+    }
+    let logprobs = payload.logprobs.is_some();
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let request_id = req_id.to_string();
+    let echo = payload.echo.unwrap_or(false);
+
+    if payload.stream.unwrap_or(false) {
+        if payload.prompt.prompts().len() != 1 {
+            return Err(Error::invalid_param("prompt", "Streaming supports a single prompt only"));
+        }
+        let (model, completion_request, generation_streams) = fallback::invoke_stream(&state.endpoints, &payload.model, started, |model| {
+            completion_request(&state, model, &payload, &request_id, timeouts)
+        }).await?;
+        let generation_stream = generation_streams.into_iter().next().expect("a generation stream per prompt");
+        let request = &completion_request.requests[0];
+        let generation_stream = timeout::stream_until(generation_stream, request.timeouts.total_only(), started);
+
+        let chunk = CompletionsResponse {
+            id: format!("cmpl-{}", req_id),
+            object: "text_completion".to_owned(),
+            created,
+            model: model.to_owned(),
+            system_fingerprint: None,
+            choices: vec![],
+            usage: None,
+        };
+        let options = CompletionStreamOptions {
+            stop: completion_request.stop,
+            echo: echo.then(|| request.prompt.to_owned()),
+            logprobs,
+        };
+        let stream_responder = completion_chunks(generation_stream, chunk, options);
+
+        Ok(fallback::served_by(sse::openai_sse(stream_responder).into_response(), &model))
+    } else {
+        let (model, completion_request, generations) = fallback::invoke(&state.endpoints, &payload.model, started, |model| {
+            completion_request(&state, model, &payload, &request_id, timeouts)
+        }).await?;
+
+        let mut usage = ChatCompletionsUsage::default();
+        let choices = generations.into_iter().enumerate()
+            .map(|(index, generation)| {
+                let (choice, choice_usage) = completion_choice(&completion_request, index, generation, echo, logprobs);
+                usage.prompt_tokens += choice_usage.prompt_tokens;
+                usage.completion_tokens += choice_usage.completion_tokens;
+                usage.total_tokens += choice_usage.total_tokens;
+                choice
+            })
+            .collect();
+
+        Ok(fallback::served_by(Json(CompletionsResponse {
+            id: format!("cmpl-{}", req_id),
+            object: "text_completion".to_owned(),
+            created,
+            model: model.to_owned(),
+            system_fingerprint: None,
+            choices,
+            usage: Some(usage),
+        }).into_response(), &model))
+    }
+}
+
+/// Text completion request to one of the models which may serve it.
+struct CompletionRequest {
+    backend: Arc<dyn Backend>,
+    /// Requests of the prompts, in order.
+    requests: Vec<GenerateRequest>,
+    /// Stop strings of the model and stop sequences of the request.
+    stop: Vec<String>,
+    tokenizer: Option<Arc<TokenCounter>>,
+}
+
+/// Build the requests of the prompts of `payload` to `model`, which fails if the model does not
+/// support one of its parameters. Prompts are sent to each model as they are.
+fn completion_request(state: &AppState, model: &str, payload: &Completions, request_id: &str, timeouts: Timeouts) -> Result<CompletionRequest, Error> {
+    let (endpoint, backend) = state.model(model)?;
+    if backend.accepts_messages() {
+        return Err(Error::invalid_param("model", "Text completion is not supported by the model"));
+    }
+
+    let model_stop = stop::model_stop(endpoint, &state.templates);
+    let request_stop = stop::request_stop(payload.stop.as_ref())?;
+    let backend_stop = stop::backend_stop(endpoint, &model_stop, &request_stop);
+    let requests: Vec<GenerateRequest> = payload.prompt.prompts().into_iter()
+        .map(|prompt| GenerateRequest {
+            request_id: request_id.to_owned(),
+            prompt,
+            temperature: payload.temperature,
+            top_k: payload.top_k,
+            top_p: payload.top_p,
+            max_tokens: payload.max_tokens,
+            do_sample: payload.do_sample,
+            stop: backend_stop.to_owned(),
+            seed: payload.seed,
+            presence_penalty: payload.presence_penalty,
+            frequency_penalty: payload.frequency_penalty,
+            repetition_penalty: payload.repetition_penalty,
+            logit_bias: payload.logit_bias.to_owned(),
+            logprobs: payload.logprobs.is_some(),
+            top_logprobs: payload.logprobs.map(|logprobs| logprobs as u32),
+            timeouts: timeouts.or_config(&endpoint.timeouts),
+            ..Default::default()
+        })
+        .collect();
+    for request in &requests {
+        check_sampling_params(backend.as_ref(), request)?;
+    }
+
+    Ok(CompletionRequest {
+        backend,
+        requests,
+        stop: [model_stop, request_stop].concat(),
+        tokenizer: state.tokenizers.get(model),
+    })
+}
+
+impl FallbackRequest for CompletionRequest {
+    fn timeouts(&self) -> Timeouts {
+        self.requests[0].timeouts
+    }
+}
+
+impl Invocations for CompletionRequest {
+    fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    fn requests(&self) -> Vec<&GenerateRequest> {
+        self.requests.iter().collect()
+    }
+}
+
+/// Choice `index` of a non-streaming text completion from the generation of its prompt, with its usage.
+///
+/// With `echo`, the text of the choice starts with the prompt, and the offsets of the tokens of `logprobs`
+/// are counted from the start of the prompt.
+fn completion_choice(completion_request: &CompletionRequest, index: usize, mut generation: Generation, echo: bool, logprobs: bool) -> (CompletionsChoice, ChatCompletionsUsage) {
+    let request = &completion_request.requests[index];
+    let generation_usage = generation.usage;
+    let mut logprobs_matcher = LogprobsMatcher::default();
+    logprobs_matcher.push(std::mem::take(&mut generation.logprobs));
+    let (text, finish_reason) = stop::truncate(generation, &completion_request.stop);
+    let usage = tokenizer::usage(&generation_usage, completion_request.tokenizer.as_deref(), &request.prompt, &text);
+    let text_offset = if echo { request.prompt.len() } else { 0 };
+
+    (CompletionsChoice {
+        logprobs: logprobs.then(|| logprobs::completion_logprobs(logprobs_matcher.take(&text), text_offset)),
+        text: if echo { format!("{}{}", request.prompt, text) } else { text },
+        index: index as i32,
+        finish_reason: Some(finish_reason),
+    }, usage)
+}
+
+struct CompletionStreamOptions {
+    /// Stop strings of the model and stop sequences of the request.
+    stop: Vec<String>,
+    /// Prompt sent as the first chunk, with `echo`.
+    echo: Option<String>,
+    /// Whether chunks carry the log probabilities of the tokens of their text.
+    logprobs: bool,
+}
+
+/// Convert a generation stream into text completion chunks based on `chunk`.
+///
+/// The prompt is sent first with `echo`, with empty `logprobs`, and the offsets of the tokens of the
+/// following chunks are counted from its start. The last chunk carries `finish_reason`, which is
+/// `length` when the generation stream ends without one. The stream ends with the error instead if
+/// the generation stream fails.
+fn completion_chunks(
+    mut generation_stream: GenerationStream,
+    chunk: CompletionsResponse,
+    options: CompletionStreamOptions,
+) -> impl Stream<Item = Result<CompletionsResponse, Error>> {
+    let completion_chunk = move |text: String, logprobs: Option<CompletionsLogprobs>, finish_reason: Option<String>| CompletionsResponse {
+        choices: vec![
+            CompletionsChoice {
+                text,
+                index: 0,
+                logprobs,
+                finish_reason,
+            }
+        ],
+        ..chunk.clone()
+    };
+    async_stream! {
+        // Offset of the next token in the text of the choice.
+        let mut text_offset = 0;
+        if let Some(text) = options.echo {
+            text_offset = text.len();
+            yield Ok(completion_chunk(text, options.logprobs.then(CompletionsLogprobs::default), None));
+        }
+        let mut stop_matcher = StopMatcher::new(options.stop);
+        let mut logprobs_matcher = LogprobsMatcher::default();
+        loop {
+            let (text, finish_reason) = match generation_stream.next().await {
+                Some(Ok(chunk)) => {
+                    logprobs_matcher.push(chunk.logprobs);
+                    match (stop_matcher.push(&chunk.text), chunk.finish_reason) {
+                        ((text, Some(_)), _) => (text, Some("stop".to_owned())),
+                        ((text, None), Some(finish_reason)) => (text + &stop_matcher.flush(), Some(finish_reason)),
+                        ((text, None), None) => (text, None),
+                    }
+                }
+                Some(Err(err)) => {
+                    error!("invoke_stream error: {:?}", err);
+                    yield Err(Error::from(err));
+                    break;
+                }
+                None => (stop_matcher.flush(), Some("length".to_owned())),
+            };
+            let done = finish_reason.is_some();
+            let logprobs = options.logprobs.then(|| {
+                let tokens = logprobs_matcher.take(&text);
+                let logprobs = logprobs::completion_logprobs(tokens, text_offset);
+                text_offset += logprobs.tokens.iter().map(String::len).sum::<usize>();
+                logprobs
+            });
+
+            yield Ok(completion_chunk(text, logprobs, finish_reason));
+
+            if done {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use futures::future::BoxFuture;
+    use futures::stream;
+
+    use crate::backend::{GenerationChunk, TokenLogprob, Usage};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct UnusedBackend;
+
+    impl Backend for UnusedBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, anyhow::Result<Generation>> {
+            unimplemented!()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, anyhow::Result<GenerationStream>> {
+            unimplemented!()
+        }
+    }
+
+    fn token(token: &str) -> TokenLogprob {
+        TokenLogprob { token: token.to_owned(), logprob: -0.5, top_logprobs: vec![] }
+    }
+
+    fn text_chunk(text: &str) -> anyhow::Result<GenerationChunk> {
+        Ok(GenerationChunk { text: text.to_owned(), logprobs: vec![token(text)], ..Default::default() })
+    }
+
+    fn chunk() -> CompletionsResponse {
+        CompletionsResponse {
+            id: "cmpl-1".to_owned(),
+            object: "text_completion".to_owned(),
+            created: 0,
+            model: "Llama-3-8B".to_owned(),
+            system_fingerprint: None,
+            choices: vec![],
+            usage: None,
+        }
+    }
+
+    fn options() -> CompletionStreamOptions {
+        CompletionStreamOptions {
+            stop: vec!["<|eot_id|>".to_owned()],
+            echo: None,
+            logprobs: false,
+        }
+    }
+
+    async fn collect(chunks: Vec<anyhow::Result<GenerationChunk>>, options: CompletionStreamOptions) -> Vec<Result<CompletionsChoice, Error>> {
+        completion_chunks(Box::pin(stream::iter(chunks)), chunk(), options)
+            .map(|chunk| chunk.map(|chunk| chunk.choices.into_iter().next().unwrap()))
+            .collect()
+            .await
+    }
+
+    fn texts(choices: &[Result<CompletionsChoice, Error>]) -> Vec<(&str, Option<&str>)> {
+        choices.iter()
+            .map(|choice| choice.as_ref().unwrap())
+            .map(|choice| (choice.text.as_str(), choice.finish_reason.as_deref()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_completion_chunks_stop() {
+        let choices = collect(vec![text_chunk("Hi <|eot"), text_chunk("_id|>ignored"), text_chunk("ignored")], options()).await;
+        assert_eq!(texts(&choices), vec![("Hi ", None), ("", Some("stop"))]);
+
+        let choices = collect(vec![text_chunk("Hi <|"), text_chunk("there")], options()).await;
+        assert_eq!(texts(&choices), vec![("Hi ", None), ("<|there", None), ("", Some("length"))]);
+
+        let finish = Ok(GenerationChunk { text: "!".to_owned(), finish_reason: Some("stop".to_owned()), ..Default::default() });
+        let choices = collect(vec![text_chunk("Hi"), finish, text_chunk("ignored")], options()).await;
+        assert_eq!(texts(&choices), vec![("Hi", None), ("!", Some("stop"))]);
+    }
+
+    #[tokio::test]
+    async fn test_completion_chunks_error() {
+        let choices = collect(vec![text_chunk("Hi"), Err(anyhow!("connection reset")), text_chunk("ignored")], options()).await;
+        assert_eq!(choices.len(), 2);
+        assert_eq!(choices[0].as_ref().unwrap().text, "Hi");
+        assert_eq!(choices[1].as_ref().unwrap_err().to_string(), "connection reset");
+    }
+
+    #[tokio::test]
+    async fn test_completion_chunks_echo_logprobs() {
+        let options = CompletionStreamOptions { echo: Some("Say hi:".to_owned()), logprobs: true, ..options() };
+        let choices = collect(vec![text_chunk(" Hi"), text_chunk(" there"), text_chunk("<|eot_id|>")], options).await;
+        assert_eq!(texts(&choices), vec![("Say hi:", None), (" Hi", None), (" there", None), ("", Some("stop"))]);
+
+        let logprobs: Vec<&CompletionsLogprobs> = choices.iter().map(|choice| choice.as_ref().unwrap().logprobs.as_ref().unwrap()).collect();
+        assert_eq!(logprobs[0], &CompletionsLogprobs::default());
+        assert_eq!((logprobs[1].tokens.as_slice(), logprobs[1].text_offset.as_slice()), ([" Hi".to_owned()].as_slice(), [7].as_slice()));
+        assert_eq!((logprobs[2].tokens.as_slice(), logprobs[2].text_offset.as_slice()), ([" there".to_owned()].as_slice(), [10].as_slice()));
+        assert!(logprobs[3].tokens.is_empty());
+    }
+
+    #[test]
+    fn test_completion_choice() {
+        let request = GenerateRequest { prompt: "Say hi:".to_owned(), ..Default::default() };
+        let completion_request = CompletionRequest {
+            backend: Arc::new(UnusedBackend),
+            requests: vec![request],
+            stop: vec!["<|eot_id|>".to_owned()],
+            tokenizer: None,
+        };
+        let generation = Generation {
+            text: " Hi<|eot_id|>".to_owned(),
+            finish_reason: None,
+            usage: Usage { prompt_tokens: Some(3), completion_tokens: Some(2) },
+            logprobs: vec![token(" Hi"), token("<|eot_id|>")],
+        };
+
+        let (choice, usage) = completion_choice(&completion_request, 0, generation.clone(), false, true);
+        assert_eq!((choice.text.as_str(), choice.finish_reason.as_deref()), (" Hi", Some("stop")));
+        assert_eq!(choice.logprobs.as_ref().unwrap().text_offset, vec![0]);
+        assert_eq!(usage.total_tokens, 5);
+
+        let (choice, _) = completion_choice(&completion_request, 0, generation, true, true);
+        assert_eq!(choice.text, "Say hi: Hi");
+        let logprobs = choice.logprobs.unwrap();
+        assert_eq!((logprobs.tokens, logprobs.text_offset), (vec![" Hi".to_owned()], vec![7]));
+    }
+}
@@ -0,0 +1,232 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::FutureExt;
+use tokio::time::Instant;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::backend::{Backend, EmbedRequest};
+use crate::error::{Error, Json};
+use crate::fallback::{self, FallbackRequest};
+use crate::timeout::Timeouts;
+use crate::types::{Embedding, EmbeddingsRequest, EmbeddingsResponse, EmbeddingsUsage, EmbeddingVector};
+
+/// OpenAI embeddings compatible endpoint.
+#[tracing::instrument(name = "Start Embeddings", skip(state, headers))]
+pub async fn embeddings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<EmbeddingsRequest>,
+) -> Result<Response, Error> {
+    let req_id = Uuid::new_v4();
+    let started = Instant::now();
+    state.model(&payload.model)?;
+    let timeouts = Timeouts::from_headers(&headers)?;
+    let base64 = match payload.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(_) => return Err(Error::invalid_param("encoding_format", "encoding_format must be float or base64")),
+    };
+    let inputs = payload.input.inputs();
+    if inputs.is_empty() {
+        return Err(Error::invalid_param("input", "input must not be empty"));
+    }
+
+    let (model, embedding_request, output) = fallback::with_fallbacks_until(&state.endpoints, &payload.model, started, |model| {
+        let (endpoint, backend) = state.model(model)?;
+        let request = EmbedRequest {
+            request_id: req_id.to_string(),
+            inputs: inputs.to_owned(),
+        };
+        Ok(EmbeddingRequest { backend, request, timeouts: timeouts.or_config(&endpoint.timeouts) })
+    }, |embedding_request| {
+        embedding_request.backend.embed(&embedding_request.request)
+            .map(|output| output.map_err(|err| {
+                error!("embed error: {:?}", err);
+                Error::from(err)
+            }))
+            .boxed()
+    }).await?;
+
+    let tokenizer = state.tokenizers.get(&model);
+    let prompt_tokens = output.usage.prompt_tokens.unwrap_or_else(|| {
+        tokenizer.map(|tokenizer| embedding_request.request.inputs.iter().map(|input| tokenizer.count(input)).sum()).unwrap_or(0)
+    });
+
+    let mut data = vec![];
+    for (index, embedding) in output.embeddings.into_iter().enumerate() {
+        let embedding = match payload.dimensions {
+            Some(dimensions) if dimensions == 0 || dimensions > embedding.len() => {
+                let message = format!("dimensions must be between 1 and {}", embedding.len());
+                return Err(Error::invalid_param("dimensions", message));
+            }
+            Some(dimensions) => truncate(embedding, dimensions),
+            None => embedding,
+        };
+        data.push(Embedding {
+            object: "embedding".to_owned(),
+            embedding: if base64 { encode_base64(&embedding) } else { EmbeddingVector::Float(embedding) },
+            index: index as i32,
+        });
+    }
+
+    Ok(fallback::served_by(Json(EmbeddingsResponse {
+        object: "list".to_owned(),
+        data,
+        model: model.to_owned(),
+        usage: EmbeddingsUsage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }).into_response(), &model))
+}
+
+/// Embeddings request to one of the models which may serve it.
+struct EmbeddingRequest {
+    backend: Arc<dyn Backend>,
+    request: EmbedRequest,
+    timeouts: Timeouts,
+}
+
+impl FallbackRequest for EmbeddingRequest {
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+}
+
+/// Shorten the embedding to the first `dimensions` elements and normalize it again.
+fn truncate(mut embedding: Vec<f32>, dimensions: usize) -> Vec<f32> {
+    if dimensions == embedding.len() {
+        return embedding;
+    }
+    embedding.truncate(dimensions);
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+fn encode_base64(embedding: &[f32]) -> EmbeddingVector {
+    let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+    EmbeddingVector::Base64(STANDARD.encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use axum::body::to_bytes;
+    use futures::future::BoxFuture;
+    use futures::FutureExt;
+    use tempfile::TempDir;
+
+    use crate::backend::{Backend, Backends, Embeddings, GenerateRequest, Generation, GenerationStream};
+    use crate::endpoint_loader::EndpointLoader;
+    use crate::fallback::SERVED_BY_HEADER;
+    use crate::timeout::TIMEOUT_HEADER;
+    use crate::types::EmbeddingsInput;
+
+    use super::*;
+
+    #[derive(Debug)]
+    enum EmbedBackend {
+        Ok,
+        Throttled,
+        Stalled,
+    }
+
+    impl Backend for EmbedBackend {
+        fn invoke<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<Generation>> {
+            unimplemented!()
+        }
+
+        fn invoke_stream<'a>(&'a self, _request: &'a GenerateRequest) -> BoxFuture<'a, Result<GenerationStream>> {
+            unimplemented!()
+        }
+
+        fn embed<'a>(&'a self, request: &'a EmbedRequest) -> BoxFuture<'a, Result<Embeddings>> {
+            async move {
+                match self {
+                    EmbedBackend::Ok => Ok(Embeddings { embeddings: vec![vec![1.0, 0.0]; request.inputs.len()], ..Default::default() }),
+                    EmbedBackend::Throttled => Err(Error::RateLimited("throttled".to_owned()).into()),
+                    EmbedBackend::Stalled => futures::future::pending().await,
+                }
+            }.boxed()
+        }
+    }
+
+    fn state(backends: Vec<(&str, EmbedBackend)>) -> AppState {
+        let temp = TempDir::new().unwrap();
+        let config_path = temp.path().join("config.yaml");
+        fs::write(config_path.as_path(), r"models:
+  - model: bge-large-en-v1.5
+    endpoint_name: tei-bge-large-en-v1-5
+    backend: SageMaker
+    kind: embeddings
+    fallbacks: [bge-base-en-v1.5]
+  - model: bge-base-en-v1.5
+    endpoint_name: tei-bge-base-en-v1-5
+    backend: SageMaker
+    kind: embeddings
+").unwrap();
+        let backends = backends.into_iter().map(|(model, backend)| (model, Arc::new(backend) as Arc<dyn Backend>)).collect();
+        AppState {
+            endpoints: Arc::new(EndpointLoader::load(config_path.as_path()).unwrap()),
+            backends: Arc::new(Backends::from_backends(backends)),
+            tokenizers: Default::default(),
+            templates: Default::default(),
+            allow_file_images: false,
+        }
+    }
+
+    fn payload() -> Json<EmbeddingsRequest> {
+        Json(EmbeddingsRequest {
+            model: "bge-large-en-v1.5".to_owned(),
+            input: EmbeddingsInput::Text("Hello".to_owned()),
+            encoding_format: None,
+            dimensions: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_fallbacks() {
+        let state = state(vec![("bge-large-en-v1.5", EmbedBackend::Throttled), ("bge-base-en-v1.5", EmbedBackend::Ok)]);
+        let response = embeddings(State(state), HeaderMap::new(), payload()).await.unwrap();
+        assert_eq!(response.headers()[SERVED_BY_HEADER], "bge-base-en-v1.5");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["model"], "bge-base-en-v1.5");
+        assert_eq!(body["data"][0]["embedding"], serde_json::json!([1.0, 0.0]));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate(vec![3.0, 4.0, 12.0], 2), vec![0.6, 0.8]);
+        assert_eq!(truncate(vec![3.0, 4.0, 12.0], 3), vec![3.0, 4.0, 12.0]);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(&[1.0, -2.0]), EmbeddingVector::Base64("AACAPwAAAMA=".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_timeout() {
+        let state = state(vec![("bge-large-en-v1.5", EmbedBackend::Stalled), ("bge-base-en-v1.5", EmbedBackend::Stalled)]);
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, "10".parse().unwrap());
+        let err = embeddings(State(state), headers, payload()).await.unwrap_err();
+        assert_eq!(err.to_string(), "The request did not complete within 10 ms");
+        assert_eq!(err.into_response().status(), 504);
+    }
+}
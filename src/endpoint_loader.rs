@@ -1,17 +1,308 @@
-use std::error::Error;
 use std::fs;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// SageMaker endpoint served by Large Model Inference (LMI) or TGI containers, or by TEI and
+    /// HuggingFace containers for embedding models.
+    #[serde(rename = "LMI", alias = "SageMaker")]
+    Lmi,
+    /// Bedrock InvokeModel API with Llama prompt format.
+    Bedrock,
+    /// Bedrock Converse API, which applies the model's chat template on Bedrock side.
+    BedrockConverse,
+}
+
+impl BackendKind {
+    /// Owner reported in the OpenAI model object.
+    pub fn owned_by(&self) -> &'static str {
+        match self {
+            BackendKind::Lmi => "sagemaker",
+            BackendKind::Bedrock | BackendKind::BedrockConverse => "bedrock",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+    /// Text generation model served by chat and text completion APIs.
+    #[default]
+    Chat,
+    /// Embedding model served by the embeddings API.
+    Embeddings,
+}
+
+/// Optional model information surfaced by `/v1/models`.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ModelMetadata {
+    pub context_length: Option<u64>,
+    pub description: Option<String>,
+    /// Unix timestamp reported as `created`. Defaults to the time the config was loaded.
+    pub created: Option<u64>,
+}
+
+/// Chat templates built into the proxy.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTemplatePreset {
+    Llama3,
+    Phi3,
+    /// nvidia/Llama3-ChatQA-1.5 format, which places the request `context` after the system prompt.
+    ChatQa,
+}
+
+/// Chat template used to render messages into the prompt. Exactly one of `preset`, `template`
+/// and `tokenizer_config` must be set.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct ChatTemplateConfig {
+    pub preset: Option<ChatTemplatePreset>,
+    /// Inline Jinja template in the HuggingFace `chat_template` format.
+    pub template: Option<String>,
+    /// Path to a HuggingFace `tokenizer_config.json` providing `chat_template`, `bos_token` and
+    /// `eos_token`.
+    pub tokenizer_config: Option<String>,
+    /// Overrides the BOS token of `tokenizer_config`.
+    pub bos_token: Option<String>,
+    /// Overrides the EOS token of `tokenizer_config`.
+    pub eos_token: Option<String>,
+}
+
+/// Guided decoding parameter supported by an LMI/TGI endpoint.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GuidedDecoding {
+    /// TGI `grammar` parameter.
+    Grammar,
+    /// `guided_json` parameter of LMI with vLLM or lmi-dist.
+    GuidedJson,
+}
+
+/// Format of the response streams of an LMI/TGI endpoint.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamFormat {
+    /// `{"generated_text": "..."}` document streamed as the text is generated, as sent by LMI
+    /// with the default `json` output formatter.
+    #[default]
+    Json,
+    /// One JSON token event per line, as sent by LMI with the `jsonlines` output formatter.
+    Jsonlines,
+    /// Server-sent events whose `data` are JSON token events, as sent by TGI.
+    Sse,
+    /// Generated text without any framing.
+    Text,
+}
+
+/// Upstream resource serving a model. Models served by several targets balance requests across them.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// Name of the target in logs. Defaults to the endpoint name or Bedrock model id, and the region.
+    pub name: Option<String>,
+    pub endpoint_name: Option<String>,
+    pub inference_component: Option<String>,
+    pub target_model: Option<String>,
+    /// AWS region of the target. Defaults to the region of the proxy.
+    pub region: Option<String>,
+    /// Share of the requests sent to the target. Targets with weight 0 get no requests.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Target {
+    pub fn name(&self) -> String {
+        if let Some(name) = self.name.as_ref() {
+            return name.to_owned();
+        }
+        let resource = [self.endpoint_name.as_deref(), self.inference_component.as_deref(), self.target_model.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<&str>>()
+            .join("/");
+        match self.region.as_ref() {
+            Some(region) => format!("{}:{}", region, resource),
+            None => resource,
+        }
+    }
+}
+
+/// Strategy choosing the target of each request among the targets of a model.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    /// Each target in turn.
+    #[default]
+    RoundRobin,
+    /// A target at random, with a probability proportional to its weight.
+    WeightedRandom,
+    /// The target with the fewest requests in flight.
+    LeastOutstanding,
+}
+
+/// Class of errors on which a request is sent to the fallbacks of the model.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackOn {
+    /// The backend throttled the request.
+    Throttling,
+    /// The model container or the backend failed.
+    ServerError,
+    Timeout,
+    /// The model is not ready, e.g. while scaling from zero, or the service is unavailable.
+    ModelNotReady,
+}
+
+fn default_fallback_on() -> Vec<FallbackOn> {
+    vec![FallbackOn::Throttling, FallbackOn::ServerError, FallbackOn::Timeout, FallbackOn::ModelNotReady]
+}
+
+/// Retries of backend calls failing with throttling or unavailability errors.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Maximum number of attempts, including the first one. 1 disables retries.
+    pub max_attempts: u32,
+    /// Maximum delay before the first retry in milliseconds, doubled on each retry. The delay is
+    /// drawn at random up to the maximum.
+    pub base_delay_ms: u64,
+    /// Time in milliseconds after the first attempt after which no retry is made.
+    pub deadline_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 100,
+            deadline_ms: 10_000,
+        }
+    }
+}
+
+/// Circuit breaker of each target of a model, which stops sending requests to a target whose
+/// recent calls mostly failed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Number of recent calls of a target the failure rate is computed on.
+    pub window: u32,
+    /// Minimum number of calls in the window before the circuit may open.
+    pub min_calls: u32,
+    /// Failure rate of the window, between 0 and 1, from which the circuit opens.
+    pub failure_rate: f64,
+    /// Time in milliseconds after which an open circuit lets trial calls through.
+    pub cooldown_ms: u64,
+    /// Maximum number of concurrent trial calls of a half-open circuit.
+    pub trial_calls: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            cooldown_ms: 30_000,
+            trial_calls: 1,
+        }
+    }
+}
+
+/// Timeouts of the requests to a model in milliseconds, which are disabled when not set.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Time from the start of a request until the model completes it, including retries and the
+    /// time spent on models it fell back from.
+    pub total_ms: Option<u64>,
+    /// Time until the first token of a streaming request.
+    pub first_token_ms: Option<u64>,
+    /// Maximum time between two chunks of a streaming request.
+    pub idle_ms: Option<u64>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Endpoint {
     pub model: String,
     pub endpoint_name: Option<String>,
     pub target_model: Option<String>,
     pub inference_component: Option<String>,
-    pub backend: String,
+    /// Targets serving the model, instead of `endpoint_name`, `inference_component` and `target_model`.
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    pub backend: BackendKind,
+    #[serde(default)]
+    pub kind: ModelKind,
+    /// Maximum number of inputs sent to an embedding endpoint in one invocation.
+    pub batch_size: Option<usize>,
+    /// Path to a HuggingFace `tokenizer.json` to count tokens the backend does not report.
+    pub tokenizer: Option<String>,
+    /// Chat template of the model. Models without one are matched by name to a built-in template.
+    pub chat_template: Option<ChatTemplateConfig>,
+    /// Strings which end the generation. Defaults to the EOS token of the chat template.
+    pub stop: Option<Vec<String>>,
+    /// Send `stop` to the endpoint as the `stop` parameter so that it stops generating at them,
+    /// in addition to truncating the output.
+    #[serde(default)]
+    pub forward_stop: bool,
+    /// The model accepts images in chat messages. Images sent to other models are rejected.
+    #[serde(default)]
+    pub vision: bool,
+    /// Guided decoding used to constrain the output to the `response_format` JSON schema.
+    pub guided_decoding: Option<GuidedDecoding>,
+    /// Number of times a generation which does not follow `response_format` is retried.
+    #[serde(default)]
+    pub response_format_retries: u32,
+    /// Format of the response streams of LMI/TGI endpoints.
+    #[serde(default)]
+    pub stream_format: StreamFormat,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Models serving the request in order when the model fails with an error of `fallback_on`.
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    #[serde(default = "default_fallback_on")]
+    pub fallback_on: Vec<FallbackOn>,
+    /// Circuit breaker of the targets, disabled by default.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub metadata: ModelMetadata,
+}
+
+impl Endpoint {
+    /// Targets of the model, which is a single target given by the fields of the model unless
+    /// `targets` is set.
+    pub fn targets(&self) -> Result<Vec<Target>> {
+        if self.targets.is_empty() {
+            return Ok(vec![Target {
+                name: None,
+                endpoint_name: self.endpoint_name.to_owned(),
+                inference_component: self.inference_component.to_owned(),
+                target_model: self.target_model.to_owned(),
+                region: None,
+                weight: 1,
+            }]);
+        }
+        if self.endpoint_name.is_some() || self.inference_component.is_some() || self.target_model.is_some() {
+            return Err(anyhow!("targets can not be set with endpoint_name, inference_component or target_model: {}", self.model));
+        }
+        if self.targets.iter().all(|target| target.weight == 0) {
+            return Err(anyhow!("at least one target must have a positive weight: {}", self.model));
+        }
+        Ok(self.targets.to_owned())
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -22,6 +313,7 @@ pub struct ModelEndpoints {
 #[derive(Debug)]
 pub struct EndpointLoader {
     endpoints: ModelEndpoints,
+    loaded_at: u64,
 }
 
 
@@ -29,12 +321,41 @@ impl EndpointLoader {
     pub fn load<P: AsRef<Path>>(config_file: P) -> Result<EndpointLoader> {
         let config = fs::read_to_string(config_file)?;
         let endpoints: ModelEndpoints = serde_yaml::from_str(config.as_str())?;
+        for endpoint in &endpoints.models {
+            if let Some(config) = endpoint.circuit_breaker {
+                let valid = config.window > 0
+                    && config.min_calls <= config.window
+                    && config.failure_rate > 0.0 && config.failure_rate <= 1.0
+                    && config.trial_calls > 0;
+                if !valid {
+                    return Err(anyhow!("invalid circuit_breaker: {}", endpoint.model));
+                }
+            }
+            for fallback in &endpoint.fallbacks {
+                match endpoints.models.iter().find(|x| &x.model == fallback) {
+                    Some(x) if x.model == endpoint.model => return Err(anyhow!("model can not fall back to itself: {}", endpoint.model)),
+                    Some(x) if x.kind != endpoint.kind => return Err(anyhow!("fallback {} is not a {:?} model: {}", fallback, endpoint.kind, endpoint.model)),
+                    Some(_) => {}
+                    None => return Err(anyhow!("unknown fallback {}: {}", fallback, endpoint.model)),
+                }
+            }
+        }
 
         Ok(EndpointLoader {
             endpoints,
+            loaded_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
         })
     }
 
+    /// Unix timestamp when the config file was loaded.
+    pub fn loaded_at(&self) -> u64 {
+        self.loaded_at
+    }
+
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints.models
+    }
+
     pub fn get_endpoint<S: AsRef<str>>(&self, model: S) -> Option<&Endpoint> {
         self.endpoints.models.iter().find(|x| x.model.as_str() == model.as_ref())
     }
@@ -48,7 +369,15 @@ mod tests {
     use anyhow::Result;
     use tempfile::TempDir;
 
-    use super::EndpointLoader;
+    use super::{BackendKind, ChatTemplatePreset, CircuitBreakerConfig, EndpointLoader, FallbackOn, GuidedDecoding, LoadBalancing, ModelKind, RetryConfig, StreamFormat, TimeoutConfig};
+
+    /// Load the endpoints of the config file `config`.
+    fn load(config: &str) -> Result<EndpointLoader> {
+        let temp = TempDir::new()?;
+        let config_path = temp.path().join("config.yaml");
+        fs::write(config_path.as_path(), config)?;
+        EndpointLoader::load(config_path.as_path())
+    }
 
     #[test]
     fn test_load_endpoints() -> Result<()> {
@@ -68,12 +397,321 @@ mod tests {
     backend: LMI
 ")?;
         let endpoints = EndpointLoader::load(config_path.as_path())?;
-        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().endpoint_name, "lmi-llama-3-70B-Instruct");
-        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().endpoint_name, "lmi-mme-20240627093303");
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().endpoint_name, Some("lmi-llama-3-70B-Instruct".to_owned()));
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().endpoint_name, Some("lmi-mme-20240627093303".to_owned()));
         assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().target_model, Some("phi-3-mini-4k-instruct.tar.gz".to_owned()));
-        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().endpoint_name, "lmi-mme-20240627093303");
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().endpoint_name, Some("lmi-mme-20240627093303".to_owned()));
         assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().target_model, Some("phi-3-mini-4k-instruct.tar.gz".to_owned()));
 
         Ok(())
     }
+
+    #[test]
+    fn test_load_backend() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+  - model: Llama-3.1-8B-Instruct
+    target_model: meta.llama3-1-8b-instruct-v1:0
+    backend: Bedrock
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().backend, BackendKind::Lmi);
+        assert_eq!(endpoints.get_endpoint("Llama-3.1-8B-Instruct").unwrap().backend, BackendKind::Bedrock);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_embeddings() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+  - model: bge-large-en-v1.5
+    endpoint_name: tei-bge-large-en-v1-5
+    backend: SageMaker
+    kind: embeddings
+    batch_size: 16
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().kind, ModelKind::Chat);
+        let bge = endpoints.get_endpoint("bge-large-en-v1.5").unwrap();
+        assert_eq!(bge.backend, BackendKind::Lmi);
+        assert_eq!(bge.kind, ModelKind::Embeddings);
+        assert_eq!(bge.batch_size, Some(16));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_metadata() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+  - model: Phi-3-medium-4k-instruct
+    endpoint_name: lmi-phi-3-medium
+    backend: LMI
+    metadata:
+      context_length: 4096
+      description: Phi-3 medium
+")?;
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().metadata.context_length, Some(4096));
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().metadata.description, Some("Phi-3 medium".to_owned()));
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().metadata.context_length, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_chat_template() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+    chat_template:
+      preset: llama3
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().chat_template.as_ref().unwrap().preset, Some(ChatTemplatePreset::Llama3));
+        assert!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().chat_template.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_stop() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+    stop:
+      - '<|end|>'
+      - '<|endoftext|>'
+    forward_stop: true
+  - model: Phi-3-medium-4k-instruct
+    endpoint_name: lmi-phi-3-medium
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().stop, Some(vec!["<|end|>".to_owned(), "<|endoftext|>".to_owned()]));
+        assert!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().forward_stop);
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().stop, None);
+        assert!(!endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().forward_stop);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_vision() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3.2-11B-Vision-Instruct
+    endpoint_name: lmi-llama-3-2-11b-vision
+    backend: LMI
+    vision: true
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+")?;
+        assert!(endpoints.get_endpoint("Llama-3.2-11B-Vision-Instruct").unwrap().vision);
+        assert!(!endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().vision);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_guided_decoding() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+    guided_decoding: guided_json
+    response_format_retries: 2
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: tgi-phi-3-mini
+    backend: LMI
+    guided_decoding: grammar
+  - model: Phi-3-medium-4k-instruct
+    endpoint_name: lmi-phi-3-medium
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().guided_decoding, Some(GuidedDecoding::GuidedJson));
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().response_format_retries, 2);
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().guided_decoding, Some(GuidedDecoding::Grammar));
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().guided_decoding, None);
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().response_format_retries, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_stream_format() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: tgi-llama-3-70B-Instruct
+    backend: LMI
+    stream_format: sse
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().stream_format, StreamFormat::Sse);
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().stream_format, StreamFormat::Json);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_targets() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-mme-20240627093303
+    target_model: phi-3-mini-4k-instruct.tar.gz
+    backend: LMI
+  - model: Llama-3.1-8B-Instruct
+    backend: LMI
+    load_balancing: least_outstanding
+    targets:
+      - endpoint_name: llama-3-1-8b
+        inference_component: llama-3-1-8b-instruct
+        weight: 3
+      - name: west
+        endpoint_name: llama-3-1-8b
+        region: us-west-2
+        weight: 0
+  - model: Llama-3.1-70B-Instruct
+    endpoint_name: llama-3-1-70b
+    backend: LMI
+    targets:
+      - endpoint_name: llama-3-1-70b
+        region: us-west-2
+")?;
+        let phi = endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap();
+        assert_eq!(phi.load_balancing, LoadBalancing::RoundRobin);
+        let targets = phi.targets()?;
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].name(), "lmi-mme-20240627093303/phi-3-mini-4k-instruct.tar.gz");
+
+        let llama = endpoints.get_endpoint("Llama-3.1-8B-Instruct").unwrap();
+        assert_eq!(llama.load_balancing, LoadBalancing::LeastOutstanding);
+        let targets = llama.targets()?;
+        assert_eq!(targets.iter().map(|target| target.name()).collect::<Vec<String>>(), vec!["llama-3-1-8b/llama-3-1-8b-instruct", "west"]);
+        assert_eq!(targets.iter().map(|target| target.weight).collect::<Vec<u32>>(), vec![3, 0]);
+        assert_eq!(targets[1].region.as_deref(), Some("us-west-2"));
+
+        assert!(endpoints.get_endpoint("Llama-3.1-70B-Instruct").unwrap().targets().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_retry() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+    retry:
+      max_attempts: 5
+      base_delay_ms: 50
+  - model: Phi-3-medium-4k-instruct
+    endpoint_name: lmi-phi-3-medium
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().retry, RetryConfig { max_attempts: 5, base_delay_ms: 50, deadline_ms: 10_000 });
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().retry, RetryConfig::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_fallbacks() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+    fallbacks: [Phi-3-medium-4k-instruct]
+    fallback_on: [throttling, model_not_ready]
+  - model: Phi-3-medium-4k-instruct
+    endpoint_name: lmi-phi-3-medium
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().fallbacks, vec!["Phi-3-medium-4k-instruct".to_owned()]);
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().fallback_on, vec![FallbackOn::Throttling, FallbackOn::ModelNotReady]);
+        assert!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().fallbacks.is_empty());
+        assert_eq!(endpoints.get_endpoint("Phi-3-medium-4k-instruct").unwrap().fallback_on.len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_invalid_fallbacks() {
+        for fallbacks in ["[Llama-3-8B]", "[Llama-3-70B]", "[bge-large-en-v1.5]"] {
+            let endpoints = load(&format!(r"models:
+  - model: Llama-3-8B
+    endpoint_name: lmi-llama-3-8b
+    backend: LMI
+    fallbacks: {}
+  - model: bge-large-en-v1.5
+    endpoint_name: tei-bge-large-en-v1-5
+    backend: SageMaker
+    kind: embeddings
+", fallbacks));
+            assert!(endpoints.is_err(), "{} is not a valid fallback", fallbacks);
+        }
+    }
+
+    #[test]
+    fn test_load_circuit_breaker() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+    circuit_breaker:
+      failure_rate: 0.25
+      cooldown_ms: 5000
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().circuit_breaker, Some(CircuitBreakerConfig {
+            failure_rate: 0.25,
+            cooldown_ms: 5000,
+            ..Default::default()
+        }));
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().circuit_breaker, None);
+
+        assert!(load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+    circuit_breaker:
+      failure_rate: 1.5
+").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_timeouts() -> Result<()> {
+        let endpoints = load(r"models:
+  - model: Llama-3-70B-instruct
+    endpoint_name: lmi-llama-3-70B-Instruct
+    backend: LMI
+    timeouts:
+      total_ms: 60000
+      idle_ms: 5000
+  - model: Phi-3-mini-4k-instruct
+    endpoint_name: lmi-phi-3-mini
+    backend: LMI
+")?;
+        assert_eq!(endpoints.get_endpoint("Llama-3-70B-instruct").unwrap().timeouts, TimeoutConfig {
+            total_ms: Some(60000),
+            first_token_ms: None,
+            idle_ms: Some(5000),
+        });
+        assert_eq!(endpoints.get_endpoint("Phi-3-mini-4k-instruct").unwrap().timeouts, TimeoutConfig::default());
+
+        Ok(())
+    }
 }
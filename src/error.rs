@@ -0,0 +1,293 @@
+use std::error::Error as StdError;
+use std::fmt::Debug;
+
+use aws_sdk_sagemakerruntime::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request, rejection::JsonRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+/// Errors returned to API clients in the OpenAI error format.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The request is malformed or not supported by the model.
+    #[error("{message}")]
+    InvalidRequest { message: String, param: Option<String> },
+    #[error("The model `{0}` does not exist")]
+    ModelNotFound(String),
+    /// The backend throttled the request.
+    #[error("{0}")]
+    RateLimited(String),
+    /// The model container failed to process the request.
+    #[error("{0}")]
+    Model(String),
+    /// The model or the service is temporarily unavailable, and the request may be retried.
+    #[error("{0}")]
+    Unavailable(String),
+    /// The circuit breakers of all targets of the model are open after repeated failures.
+    #[error("{0}")]
+    CircuitOpen(String),
+    /// The backend failed or returned an unexpected response.
+    #[error("{0}")]
+    Upstream(String),
+    #[error("{0}")]
+    Timeout(String),
+    /// The model output does not follow the requested `response_format`.
+    #[error("{0}")]
+    InvalidOutput(String),
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorBody {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+/// Error response of the Anthropic Messages API.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MessagesErrorResponse {
+    #[serde(rename = "type")]
+    pub response_type: String,
+    pub error: MessagesErrorBody,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MessagesErrorBody {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn invalid_request<S: Into<String>>(message: S) -> Error {
+        Error::InvalidRequest { message: message.into(), param: None }
+    }
+
+    pub fn invalid_param<S: Into<String>>(param: &str, message: S) -> Error {
+        Error::InvalidRequest { message: message.into(), param: Some(param.to_owned()) }
+    }
+
+    /// Classify an AWS SDK error by its error code, which is shared by SageMaker and Bedrock
+    /// operations and their event streams.
+    pub fn from_sdk<E, R>(err: SdkError<E, R>) -> Error
+    where
+        E: ProvideErrorMetadata + StdError + 'static,
+        R: Debug,
+    {
+        let message = err.message().map(|message| message.to_owned())
+            .unwrap_or_else(|| DisplayErrorContext(&err).to_string());
+        match &err {
+            SdkError::TimeoutError(_) => Error::Timeout(message),
+            SdkError::DispatchFailure(failure) if failure.is_timeout() => Error::Timeout(message),
+            SdkError::DispatchFailure(failure) if failure.is_io() => Error::Unavailable(message),
+            SdkError::ServiceError(_) => match err.code() {
+                Some("ThrottlingException" | "ServiceQuotaExceededException") => Error::RateLimited(message),
+                Some("ModelError" | "ModelErrorException" | "ModelStreamError" | "ModelStreamErrorException") => Error::Model(message),
+                Some("ModelNotReadyException" | "ServiceUnavailable" | "ServiceUnavailableException") => Error::Unavailable(message),
+                Some("ModelTimeoutException") => Error::Timeout(message),
+                Some("ValidationError" | "ValidationException") => Error::invalid_request(message),
+                _ => Error::Upstream(message),
+            },
+            _ => Error::Upstream(message),
+        }
+    }
+
+    /// Whether the error is transient, so that the request may succeed if retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::RateLimited(_) | Error::Unavailable(_))
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            Error::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            Error::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Model(_) => StatusCode::FAILED_DEPENDENCY,
+            Error::Unavailable(_) | Error::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::InvalidOutput(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    pub fn to_response(&self) -> ErrorResponse {
+        let (error_type, param, code) = match self {
+            Error::InvalidRequest { param, .. } => ("invalid_request_error", param.to_owned(), None),
+            Error::ModelNotFound(_) => ("invalid_request_error", Some("model".to_owned()), Some("model_not_found")),
+            Error::RateLimited(_) => ("rate_limit_error", None, Some("rate_limit_exceeded")),
+            Error::Model(_) => ("server_error", None, Some("model_error")),
+            Error::Unavailable(_) => ("server_error", None, Some("service_unavailable")),
+            Error::CircuitOpen(_) => ("server_error", None, Some("circuit_open")),
+            Error::Upstream(_) => ("server_error", None, Some("upstream_error")),
+            Error::Timeout(_) => ("server_error", None, Some("timeout")),
+            Error::InvalidOutput(_) => ("server_error", Some("response_format".to_owned()), Some("invalid_output")),
+        };
+
+        ErrorResponse {
+            error: ErrorBody {
+                message: self.to_string(),
+                error_type: error_type.to_owned(),
+                param,
+                code: code.map(|code| code.to_owned()),
+            },
+        }
+    }
+}
+
+impl Error {
+    /// Error in the Anthropic Messages API format.
+    pub fn to_messages_error(&self) -> MessagesErrorBody {
+        let error_type = match self {
+            Error::InvalidRequest { .. } => "invalid_request_error",
+            Error::ModelNotFound(_) => "not_found_error",
+            Error::RateLimited(_) => "rate_limit_error",
+            Error::Unavailable(_) | Error::CircuitOpen(_) => "overloaded_error",
+            Error::Model(_) | Error::Upstream(_) | Error::Timeout(_) | Error::InvalidOutput(_) => "api_error",
+        };
+
+        MessagesErrorBody {
+            error_type: error_type.to_owned(),
+            message: self.to_string(),
+        }
+    }
+}
+
+/// Error of the Messages API, which is returned to clients in the Anthropic error format.
+#[derive(Debug)]
+pub struct MessagesError(pub Error);
+
+impl From<Error> for MessagesError {
+    fn from(err: Error) -> MessagesError {
+        MessagesError(err)
+    }
+}
+
+impl IntoResponse for MessagesError {
+    fn into_response(self) -> Response {
+        let response = MessagesErrorResponse {
+            response_type: "error".to_owned(),
+            error: self.0.to_messages_error(),
+        };
+        (self.0.status_code(), axum::Json(response)).into_response()
+    }
+}
+
+/// Backends return `anyhow` errors, which wrap an `Error` when the failure is classified.
+/// Anything else is an unexpected response from the backend.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        match err.downcast::<Error>() {
+            Ok(err) => err,
+            Err(err) => Error::Upstream(err.to_string()),
+        }
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Error {
+        Error::invalid_request(rejection.body_text())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (self.status_code(), axum::Json(self.to_response())).into_response()
+    }
+}
+
+/// JSON extractor and response which rejects malformed requests with an OpenAI error.
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    axum::Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use aws_sdk_sagemakerruntime::error::ErrorMetadata;
+    use aws_sdk_sagemakerruntime::operation::invoke_endpoint::InvokeEndpointError;
+
+    use super::*;
+
+    fn service_error(code: &str) -> Error {
+        let err = InvokeEndpointError::generic(ErrorMetadata::builder().code(code).message("failed").build());
+        Error::from_sdk(SdkError::service_error(err, ()))
+    }
+
+    #[test]
+    fn test_from_sdk() {
+        assert_eq!(service_error("ThrottlingException").status_code(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(service_error("ModelError").status_code(), StatusCode::FAILED_DEPENDENCY);
+        assert_eq!(service_error("ModelTimeoutException").status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(service_error("ValidationError").status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(service_error("InternalFailure").status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(service_error("ModelNotReadyException").status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(service_error("ThrottlingException").is_retryable());
+        assert!(service_error("ModelNotReadyException").is_retryable());
+        assert!(!service_error("ModelError").is_retryable());
+        assert_eq!(service_error("ThrottlingException").to_string(), "failed");
+
+        let err: SdkError<InvokeEndpointError, ()> = SdkError::timeout_error("timed out");
+        assert_eq!(Error::from_sdk(err).status_code(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_from_anyhow() {
+        let err = Error::from(anyhow::Error::from(Error::invalid_request("unknown role: tool")));
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(Error::from(anyhow!("unexpected response")).status_code(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_to_messages_error() {
+        assert_eq!(Error::ModelNotFound("claude".to_owned()).to_messages_error(), MessagesErrorBody {
+            error_type: "not_found_error".to_owned(),
+            message: "The model `claude` does not exist".to_owned(),
+        });
+        assert_eq!(Error::invalid_param("max_tokens", "max_tokens must be positive").to_messages_error().error_type, "invalid_request_error");
+        assert_eq!(Error::RateLimited("throttled".to_owned()).to_messages_error().error_type, "rate_limit_error");
+        assert_eq!(Error::Unavailable("scaling from zero".to_owned()).to_messages_error().error_type, "overloaded_error");
+        assert_eq!(Error::Model("model failed".to_owned()).to_messages_error().error_type, "api_error");
+    }
+
+    #[test]
+    fn test_to_response() {
+        assert_eq!(Error::ModelNotFound("gpt-4".to_owned()).to_response(), ErrorResponse {
+            error: ErrorBody {
+                message: "The model `gpt-4` does not exist".to_owned(),
+                error_type: "invalid_request_error".to_owned(),
+                param: Some("model".to_owned()),
+                code: Some("model_not_found".to_owned()),
+            },
+        });
+    }
+}
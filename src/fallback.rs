@@ -0,0 +1,240 @@
+use std::future::Future;
+
+use axum::http::HeaderValue;
+use axum::response::Response;
+use futures::future::{BoxFuture, try_join_all};
+use futures::{FutureExt, TryFutureExt};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use crate::backend::{Backend, GenerateRequest, Generation, GenerationStream};
+use crate::endpoint_loader::{EndpointLoader, FallbackOn};
+use crate::error::Error;
+use crate::timeout::{self, Timeouts};
+
+/// Response header reporting the model which served the request.
+pub const SERVED_BY_HEADER: &str = "x-msgapi-served-by";
+
+/// Class of `err` for `fallback_on`, if it may trigger fallbacks.
+fn error_class(err: &Error) -> Option<FallbackOn> {
+    match err {
+        Error::RateLimited(_) => Some(FallbackOn::Throttling),
+        Error::Model(_) | Error::Upstream(_) | Error::CircuitOpen(_) => Some(FallbackOn::ServerError),
+        Error::Timeout(_) => Some(FallbackOn::Timeout),
+        Error::Unavailable(_) => Some(FallbackOn::ModelNotReady),
+        _ => None,
+    }
+}
+
+/// Run `call` with `model`, then with the fallbacks of the model in order as long as it fails
+/// with an error of the `fallback_on` classes of the model.
+///
+/// Fallbacks which can not serve the request, such as models not supporting one of its parameters,
+/// are skipped. When all fallbacks fail, the error of `model` is returned. Returns the model which
+/// served the request with the output of `call`.
+pub async fn with_fallbacks<T, F, Fut>(endpoints: &EndpointLoader, model: &str, mut call: F) -> Result<(String, T), Error>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let endpoint = endpoints.get_endpoint(model).ok_or_else(|| Error::ModelNotFound(model.to_owned()))?;
+    let falls_back = |err: &Error| error_class(err).is_some_and(|class| endpoint.fallback_on.contains(&class));
+
+    let err = match call(model.to_owned()).await {
+        Ok(output) => return Ok((model.to_owned(), output)),
+        Err(err) if falls_back(&err) => err,
+        Err(err) => return Err(err),
+    };
+    for fallback in &endpoint.fallbacks {
+        warn!("falling back from {} to {}: {}", model, fallback, err);
+        match call(fallback.to_owned()).await {
+            Ok(output) => return Ok((fallback.to_owned(), output)),
+            Err(fallback_err) if falls_back(&fallback_err) || matches!(fallback_err, Error::InvalidRequest { .. }) => {
+                warn!("fallback {} failed: {}", fallback, fallback_err);
+            }
+            Err(fallback_err) => return Err(fallback_err),
+        }
+    }
+    Err(err)
+}
+
+/// Request to one of the models which may serve a request, built for the requested model and
+/// then for each of its fallbacks.
+pub trait FallbackRequest: Send + Sync {
+    /// Timeouts of the request to the model.
+    fn timeouts(&self) -> Timeouts;
+}
+
+/// Request invoking the backend of a model with generation requests.
+pub trait Invocations: FallbackRequest {
+    fn backend(&self) -> &dyn Backend;
+    /// Generation requests, which are invoked concurrently.
+    fn requests(&self) -> Vec<&GenerateRequest>;
+}
+
+/// Run `call` with the request built by `model_request` for `model`, then for its fallbacks as
+/// `with_fallbacks` does. Each call fails with a timeout error once the total timeout of its
+/// request passes, measured from `started`. Returns the model which served the request with its
+/// request and the output of `call`.
+pub async fn with_fallbacks_until<R, T, B, F>(endpoints: &EndpointLoader, model: &str, started: Instant, mut model_request: B, call: F) -> Result<(String, R, T), Error>
+where
+    R: FallbackRequest,
+    T: Send,
+    B: FnMut(&str) -> Result<R, Error>,
+    F: for<'a> Fn(&'a R) -> BoxFuture<'a, Result<T, Error>>,
+{
+    let (model, (request, output)) = with_fallbacks(endpoints, model, |model| {
+        let request = model_request(&model);
+        let call = &call;
+        async move {
+            let request = request?;
+            let timeouts = request.timeouts();
+            let output = timeout::until(timeouts.deadline(started), timeouts.total_message(), call(&request)).await?;
+            Ok((request, output))
+        }
+    }).await?;
+    Ok((model, request, output))
+}
+
+/// Invoke the requests built by `model_request` as `with_fallbacks_until` does, returning their
+/// generations in order.
+pub async fn invoke<R, B>(endpoints: &EndpointLoader, model: &str, started: Instant, model_request: B) -> Result<(String, R, Vec<Generation>), Error>
+where
+    R: Invocations,
+    B: FnMut(&str) -> Result<R, Error>,
+{
+    with_fallbacks_until(endpoints, model, started, model_request, |request| {
+        let backend = request.backend();
+        try_join_all(request.requests().into_iter().map(|request| backend.invoke(request)))
+            .map_err(|err| {
+                error!("invoke error: {:?}", err);
+                Error::from(err)
+            })
+            .boxed()
+    }).await
+}
+
+/// Invoke the requests built by `model_request` with streaming as `with_fallbacks_until` does,
+/// returning their generation streams in order. The total timeout applies until the streams
+/// start, and must be applied to the streams by the caller.
+pub async fn invoke_stream<R, B>(endpoints: &EndpointLoader, model: &str, started: Instant, model_request: B) -> Result<(String, R, Vec<GenerationStream>), Error>
+where
+    R: Invocations,
+    B: FnMut(&str) -> Result<R, Error>,
+{
+    with_fallbacks_until(endpoints, model, started, model_request, |request| {
+        let backend = request.backend();
+        try_join_all(request.requests().into_iter().map(|request| backend.invoke_stream(request)))
+            .map_err(|err| {
+                error!("invoke_stream error: {:?}", err);
+                Error::from(err)
+            })
+            .boxed()
+    }).await
+}
+
+/// Report the model which served the request in the response headers.
+pub fn served_by(mut response: Response, model: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(model) {
+        response.headers_mut().insert(SERVED_BY_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn endpoints() -> EndpointLoader {
+        let temp = TempDir::new().unwrap();
+        let config_path = temp.path().join("config.yaml");
+        fs::write(config_path.as_path(), r"models:
+  - model: Llama-3-8B
+    endpoint_name: lmi-llama-3-8b
+    backend: LMI
+    fallbacks: [Llama-3.1-8B, Llama-3.1-70B]
+    fallback_on: [throttling, model_not_ready]
+  - model: Llama-3.1-8B
+    target_model: meta.llama3-1-8b-instruct-v1:0
+    backend: Bedrock
+  - model: Llama-3.1-70B
+    target_model: meta.llama3-1-70b-instruct-v1:0
+    backend: Bedrock
+").unwrap();
+        EndpointLoader::load(config_path.as_path()).unwrap()
+    }
+
+    /// Run `with_fallbacks` with models failing with the errors of `errors`, returning the model
+    /// which served the request and the models called.
+    async fn serve(errors: fn(&str) -> Option<Error>) -> (Result<String, Error>, Vec<String>) {
+        let mut called = vec![];
+        let result = with_fallbacks(&endpoints(), "Llama-3-8B", |model| {
+            called.push(model.to_owned());
+            async move {
+                match errors(&model) {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                }
+            }
+        }).await;
+        (result.map(|(model, _)| model), called)
+    }
+
+    struct TimedRequest(Timeouts);
+
+    impl FallbackRequest for TimedRequest {
+        fn timeouts(&self) -> Timeouts {
+            self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_fallbacks_until() {
+        let timeouts = Timeouts { total: Some(std::time::Duration::from_millis(10)), ..Default::default() };
+        let result = with_fallbacks_until(&endpoints(), "Llama-3-8B", Instant::now(), |model| match model {
+            "Llama-3-8B" => Err(Error::RateLimited("throttled".to_owned())),
+            _ => Ok(TimedRequest(timeouts)),
+        }, |_| futures::future::pending::<Result<(), Error>>().boxed()).await;
+        assert_eq!(result.err().unwrap().to_string(), "The request did not complete within 10 ms");
+
+        let result = with_fallbacks_until(&endpoints(), "Llama-3-8B", Instant::now(), |_| Ok(TimedRequest(timeouts)), |request| {
+            async move { Ok(request.timeouts().total) }.boxed()
+        }).await;
+        let (model, _, total) = result.unwrap();
+        assert_eq!((model.as_str(), total), ("Llama-3-8B", timeouts.total));
+    }
+
+    #[tokio::test]
+    async fn test_with_fallbacks() {
+        let (served, called) = serve(|_| None).await;
+        assert_eq!(served.unwrap(), "Llama-3-8B");
+        assert_eq!(called, vec!["Llama-3-8B"]);
+
+        let (served, called) = serve(|model| match model {
+            "Llama-3-8B" => Some(Error::Unavailable("scaling from zero".to_owned())),
+            "Llama-3.1-8B" => Some(Error::invalid_param("logprobs", "logprobs is not supported by the model")),
+            _ => None,
+        }).await;
+        assert_eq!(served.unwrap(), "Llama-3.1-70B");
+        assert_eq!(called, vec!["Llama-3-8B", "Llama-3.1-8B", "Llama-3.1-70B"]);
+
+        let (served, called) = serve(|_| Some(Error::RateLimited("throttled".to_owned()))).await;
+        assert_eq!(served.unwrap_err().to_string(), "throttled");
+        assert_eq!(called.len(), 3);
+
+        let (served, called) = serve(|_| Some(Error::Timeout("timed out".to_owned()))).await;
+        assert!(matches!(served, Err(Error::Timeout(_))));
+        assert_eq!(called, vec!["Llama-3-8B"]);
+
+        let (served, called) = serve(|model| match model {
+            "Llama-3-8B" => Some(Error::RateLimited("throttled".to_owned())),
+            _ => Some(Error::Model("model failed".to_owned())),
+        }).await;
+        assert!(matches!(served, Err(Error::Model(_))));
+        assert_eq!(called, vec!["Llama-3-8B", "Llama-3.1-8B"]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::Path;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use crate::error::Error;
+use crate::types::{ChatCompletionsContent, ChatCompletionsContentPart, ChatCompletionsMessage};
+
+/// Image formats accepted by the vision models of Bedrock and LMI.
+const MEDIA_TYPES: [(&str, &str); 4] = [
+    ("image/png", "png"),
+    ("image/jpeg", "jpeg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+/// Decoded image of an `image_url` content part.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Decode a base64 `data:` URL, or read a local file when `allow_files` is set.
+    pub fn from_url(url: &str, allow_files: bool) -> Result<Image, Error> {
+        if let Some(data_url) = url.strip_prefix("data:") {
+            let (media_type, data) = data_url.split_once(";base64,")
+                .ok_or_else(|| Error::invalid_param("messages", "Image data URLs must be base64 encoded"))?;
+            let data = STANDARD.decode(data)
+                .map_err(|err| Error::invalid_param("messages", format!("Invalid base64 image data: {}", err)))?;
+            return Image::new(media_type, data);
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Err(Error::invalid_param("messages", "Image URLs are not supported, send images as base64 data URLs"));
+        }
+        if !allow_files {
+            return Err(Error::invalid_param("messages", "Images must be base64 data URLs"));
+        }
+
+        let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default().to_lowercase();
+        let media_type = match extension.as_str() {
+            "jpg" => "image/jpeg".to_owned(),
+            extension => format!("image/{}", extension),
+        };
+        let data = fs::read(path)
+            .map_err(|err| Error::invalid_param("messages", format!("Unable to read image {}: {}", path.display(), err)))?;
+        Image::new(&media_type, data)
+    }
+
+    fn new(media_type: &str, data: Vec<u8>) -> Result<Image, Error> {
+        if !MEDIA_TYPES.iter().any(|(supported, _)| *supported == media_type) {
+            return Err(Error::invalid_param("messages", format!("Unsupported image type: {}", media_type)));
+        }
+        Ok(Image { media_type: media_type.to_owned(), data })
+    }
+
+    /// Format name of the image, such as `png`.
+    pub fn format(&self) -> &'static str {
+        MEDIA_TYPES.iter()
+            .find(|(media_type, _)| *media_type == self.media_type)
+            .map(|(_, format)| *format)
+            .unwrap_or_default()
+    }
+
+    pub fn to_data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, STANDARD.encode(&self.data))
+    }
+}
+
+/// Validate the images of the messages and replace file paths with data URLs, so that
+/// backends only get valid data URLs.
+pub fn inline_images(messages: &mut [ChatCompletionsMessage], allow_files: bool) -> Result<(), Error> {
+    for message in messages {
+        if let Some(ChatCompletionsContent::Parts(parts)) = message.content.as_mut() {
+            for part in parts {
+                if let ChatCompletionsContentPart::ImageUrl { image_url } = part {
+                    let image = Image::from_url(&image_url.url, allow_files)?;
+                    if !image_url.url.starts_with("data:") {
+                        image_url.url = image.to_data_url();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const PNG: &str = "data:image/png;base64,iVBORw0KGgo=";
+
+    #[test]
+    fn test_from_url() {
+        let image = Image::from_url(PNG, false).unwrap();
+        assert_eq!(image.media_type, "image/png");
+        assert_eq!(image.format(), "png");
+        assert_eq!(image.data, b"\x89PNG\r\n\x1a\n");
+        assert_eq!(image.to_data_url(), PNG);
+
+        assert!(Image::from_url("data:image/png;base64,???", false).is_err());
+        assert!(Image::from_url("data:image/tiff;base64,iVBORw0KGgo=", false).is_err());
+        assert!(Image::from_url("https://example.com/cat.png", true).is_err());
+        assert!(Image::from_url("/tmp/cat.png", false).is_err());
+    }
+
+    #[test]
+    fn test_inline_images() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("cat.jpg");
+        fs::write(&path, b"\xff\xd8\xff").unwrap();
+
+        let mut messages: Vec<ChatCompletionsMessage> = serde_json::from_value(json!([
+            {"role": "user", "content": "Hello."},
+            {"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": path.to_str().unwrap()}},
+                {"type": "image_url", "image_url": {"url": PNG}},
+            ]},
+        ])).unwrap();
+        assert!(inline_images(&mut messages, false).is_err());
+        inline_images(&mut messages, true).unwrap();
+
+        let urls: Vec<&str> = messages[1].images().map(|image| image.url.as_str()).collect();
+        assert_eq!(urls, vec!["data:image/jpeg;base64,/9j/", PNG]);
+        assert_eq!(messages[1].text(), "What is this?");
+    }
+}
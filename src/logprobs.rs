@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+use crate::backend::TokenLogprob;
+use crate::types::{ChatCompletionsLogprobs, ChatCompletionsTokenLogprob, ChatCompletionsTopLogprob, CompletionsLogprobs};
+
+/// Matches token log probabilities with the text sent to the client.
+///
+/// Text is held back or cut at stop strings, so the tokens of a generation are returned once
+/// the text they make up has been sent, and the tokens of text which is never sent are dropped.
+#[derive(Debug, Default)]
+pub struct LogprobsMatcher {
+    pending: VecDeque<TokenLogprob>,
+    /// Length of the sent text not covered by the returned tokens yet.
+    sent: usize,
+}
+
+impl LogprobsMatcher {
+    pub fn push(&mut self, logprobs: Vec<TokenLogprob>) {
+        self.pending.extend(logprobs);
+    }
+
+    /// Take the tokens making up the text sent so far, including `text`.
+    pub fn take(&mut self, text: &str) -> Vec<TokenLogprob> {
+        self.sent += text.len();
+        let mut logprobs = vec![];
+        while let Some(token) = self.pending.front() {
+            if token.token.len() > self.sent {
+                break;
+            }
+            self.sent -= token.token.len();
+            logprobs.extend(self.pending.pop_front());
+        }
+        logprobs
+    }
+}
+
+/// Convert token log probabilities into the OpenAI format.
+pub fn chat_logprobs(logprobs: Vec<TokenLogprob>) -> ChatCompletionsLogprobs {
+    ChatCompletionsLogprobs {
+        content: logprobs.into_iter()
+            .map(|logprob| ChatCompletionsTokenLogprob {
+                bytes: Some(logprob.token.as_bytes().to_vec()),
+                token: logprob.token,
+                logprob: logprob.logprob,
+                top_logprobs: logprob.top_logprobs.into_iter()
+                    .map(|(token, logprob)| ChatCompletionsTopLogprob {
+                        bytes: Some(token.as_bytes().to_vec()),
+                        token,
+                        logprob,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+/// Convert token log probabilities into the format of the text completion API, with the first
+/// token at `text_offset` of the text of the choice.
+pub fn completion_logprobs(logprobs: Vec<TokenLogprob>, mut text_offset: usize) -> CompletionsLogprobs {
+    let mut completion_logprobs = CompletionsLogprobs::default();
+    for logprob in logprobs {
+        completion_logprobs.text_offset.push(text_offset);
+        text_offset += logprob.token.len();
+        completion_logprobs.token_logprobs.push(logprob.logprob);
+        completion_logprobs.top_logprobs.push(logprob.top_logprobs.into_iter().collect());
+        completion_logprobs.tokens.push(logprob.token);
+    }
+    completion_logprobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(token: &str) -> TokenLogprob {
+        TokenLogprob { token: token.to_owned(), logprob: -0.5, top_logprobs: vec![] }
+    }
+
+    #[test]
+    fn test_logprobs_matcher() {
+        let mut matcher = LogprobsMatcher::default();
+        matcher.push(vec![token("Hi"), token(" there"), token("<|eot_id|>")]);
+        assert_eq!(matcher.take("Hi th"), vec![token("Hi")]);
+        assert_eq!(matcher.take("ere"), vec![token(" there")]);
+        assert_eq!(matcher.take(""), vec![]);
+    }
+
+    #[test]
+    fn test_chat_logprobs() {
+        let logprobs = chat_logprobs(vec![TokenLogprob {
+            token: "Hi".to_owned(),
+            logprob: -0.1,
+            top_logprobs: vec![("Hi".to_owned(), -0.1), ("Hello".to_owned(), -2.5)],
+        }]);
+        assert_eq!(logprobs.content[0].bytes, Some(b"Hi".to_vec()));
+        assert_eq!(logprobs.content[0].top_logprobs[1].token, "Hello");
+        assert_eq!(logprobs.content[0].top_logprobs[1].logprob, -2.5);
+    }
+
+    #[test]
+    fn test_completion_logprobs() {
+        let logprobs = completion_logprobs(vec![
+            TokenLogprob { token: "Hi".to_owned(), logprob: -0.1, top_logprobs: vec![("Hello".to_owned(), -2.5)] },
+            token(" there"),
+        ], 5);
+        assert_eq!(logprobs.tokens, vec!["Hi", " there"]);
+        assert_eq!(logprobs.token_logprobs, vec![-0.1, -0.5]);
+        assert_eq!(logprobs.top_logprobs[0].get("Hello"), Some(&-2.5));
+        assert!(logprobs.top_logprobs[1].is_empty());
+        assert_eq!(logprobs.text_offset, vec![5, 7]);
+    }
+}
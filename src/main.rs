@@ -1,39 +1,23 @@
-extern crate core;
-
-use std::fmt::Error;
-use std::io::Write;
-use std::pin::Pin;
-use std::str::from_utf8;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::bail;
 use async_stream::stream as async_stream;
 use aws_sdk_sagemakerruntime as sagemakerruntime;
-use aws_sdk_sagemakerruntime::primitives::Blob;
 use axum::{
     extract::State,
-    http::{HeaderValue, Method, StatusCode},
-    Json,
-    response::IntoResponse,
-    response::sse::{Event, KeepAlive, Sse},
+    http::{HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
     Router,
     routing::{get, post},
 };
-use bytes::{Buf, BufMut, BytesMut};
-use clap::builder::Str;
 use clap::Parser;
-use futures::FutureExt;
-use futures::stream::{self as stream, Stream};
-use futures::task::Poll;
-use futures_util::{pin_mut, StreamExt};
-use opentelemetry::trace::TracerProvider as _;
-use opentelemetry_sdk::trace::TracerProvider;
-use serde::Deserialize;
-use serde_json::json;
+use futures::future::try_join_all;
+use futures::{FutureExt, Stream};
+use futures::stream::select_all;
+use futures_util::StreamExt;
+use tokio::time::Instant;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{error, error_span, event, info, info_span, instrument, Level, warn, warn_span};
-use tracing_subscriber;
+use tracing::{error, info, info_span, warn};
 use tracing_subscriber::{
     EnvFilter,
     layer::SubscriberExt,
@@ -41,14 +25,46 @@ use tracing_subscriber::{
 };
 use uuid::Uuid;
 
-use crate::chat_template::{apply_chat_template, apply_chat_template_llama3};
-use crate::endpoint_loader::EndpointLoader;
-use crate::types::{BedrockRequest, BedrockResponse, BedrockStreamResponse, ChatCompletions, ChatCompletionsChoice, ChatCompletionsChoiceDelta, ChatCompletionsMessage, ChatCompletionsResponse, ChatCompletionsUsage, PredictParams, SMPredictionOutput, SMPredictionRequest};
-
+use crate::backend::{Backend, BackendClients, Backends, check_sampling_params, GenerateRequest, GenerationStream, Usage};
+use crate::chat_template::ChatTemplates;
+use crate::endpoint_loader::{Endpoint, EndpointLoader};
+use crate::error::{Error, Json};
+use crate::fallback::{FallbackRequest, Invocations};
+use crate::logprobs::LogprobsMatcher;
+use crate::response_format::JsonOutput;
+use crate::stop::StopMatcher;
+use crate::timeout::Timeouts;
+use crate::tokenizer::{TokenCounter, Tokenizers};
+use crate::tools::{RequestTools, ToolCallParser};
+use crate::types::{ChatCompletions, ChatCompletionsChoice, ChatCompletionsChoiceDelta, ChatCompletionsMessage, ChatCompletionsResponse, ChatCompletionsUsage, ToolCall};
+
+mod admin;
+mod backend;
+mod completions;
+mod embeddings;
 mod chat_template;
 mod types;
+#[allow(dead_code)]
 mod sagemaker_endpoint_loader;
 mod endpoint_loader;
+mod error;
+mod fallback;
+mod image;
+mod logprobs;
+mod messages;
+mod models;
+mod response_format;
+mod sse;
+mod stop;
+mod timeout;
+mod tokenizer;
+mod tools;
+
+/// Maximum number of choices of a chat completion, which are generated by concurrent invocations.
+const MAX_CHOICES: u32 = 128;
+
+/// Maximum number of alternatives of `top_logprobs`, as in the OpenAI API.
+const MAX_TOP_LOGPROBS: u32 = 20;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -64,13 +80,28 @@ struct Args {
     /// A path to SageMaker inference endpoints config file.
     #[arg(short, long)]
     config: String,
+
+    /// Accept local file paths as image URLs, for local testing.
+    #[arg(long)]
+    allow_file_images: bool,
 }
 
 #[derive(Clone, Debug)]
 struct AppState {
-    smr_client: Arc<sagemakerruntime::Client>,
-    bedrock_client: Arc<aws_sdk_bedrockruntime::Client>,
     endpoints: Arc<EndpointLoader>,
+    backends: Arc<Backends>,
+    tokenizers: Arc<Tokenizers>,
+    templates: Arc<ChatTemplates>,
+    allow_file_images: bool,
+}
+
+impl AppState {
+    /// Look up the endpoint config and backend of the requested model.
+    fn model(&self, model: &str) -> Result<(&Endpoint, Arc<dyn Backend>), Error> {
+        self.endpoints.get_endpoint(model)
+            .and_then(|endpoint| Some((endpoint, self.backends.get(&endpoint.model)?)))
+            .ok_or_else(|| Error::ModelNotFound(model.to_owned()))
+    }
 }
 
 
@@ -78,311 +109,446 @@ async fn health() -> &'static str {
     "ok"
 }
 
-#[tracing::instrument]
+#[tracing::instrument(skip(state, headers))]
 async fn chat_completions(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<ChatCompletions>,
-) -> impl IntoResponse {
+) -> Result<Response, Error> {
     let req_id = Uuid::new_v4();
     let span = info_span!("Start Chat completion");
     let _ = span.enter();
-    let endpoint = match state.endpoints.get_endpoint(&payload.model) {
-        Some(endpoint) => endpoint,
-        None => return (StatusCode::BAD_REQUEST, "Unsupported model").into_response(),
+    let started = Instant::now();
+    state.model(&payload.model)?;
+    // Headers override the timeouts of each model.
+    let timeouts = Timeouts::from_headers(&headers)?;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let n = payload.n.unwrap_or(1);
+    if !(1..=MAX_CHOICES).contains(&n) {
+        return Err(Error::invalid_param("n", format!("n must be between 1 and {}", MAX_CHOICES)));
+    }
+    let logprobs = payload.logprobs.unwrap_or(false);
+    match payload.top_logprobs {
+        Some(top_logprobs) if top_logprobs > MAX_TOP_LOGPROBS => return Err(Error::invalid_param(
+            "top_logprobs", format!("top_logprobs must be between 0 and {}", MAX_TOP_LOGPROBS))),
+        Some(_) if !logprobs => return Err(Error::invalid_param("top_logprobs", "top_logprobs requires logprobs to be true")),
+        _ => {}
+    }
+    let tools = tools::request_tools(payload.tools.as_ref(), payload.tool_choice.as_ref())?;
+    let json_output = JsonOutput::new(payload.response_format.as_ref())?;
+    let mut messages = match json_output.as_ref() {
+        Some(json_output) => json_output.apply(&payload.messages),
+        None => payload.messages.to_owned(),
     };
-    let content_type = "application/json".to_owned();
-    let prompt = if payload.model.to_lowercase().contains("-instruct") ||
-        payload.model.eq("Llama3-ChatQA-1.5-8B") {
-        apply_chat_template(&payload.model, &payload.messages, payload.context.to_owned()).unwrap()
-    } else {
-        payload.messages.iter().map(|m| m.content.to_owned()).collect::<Vec<String>>().join("\n")
+    if let Some(tools) = tools.as_ref() {
+        messages = tools.apply(&messages);
+    }
+    image::inline_images(&mut messages, state.allow_file_images)?;
+    let input = ChatInput {
+        request_id: req_id.to_string(),
+        payload: &payload,
+        messages,
+        tools: tools.as_ref(),
+        json_output: json_output.as_ref(),
+        logprobs,
+        timeouts,
     };
 
-    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    if payload.stream.unwrap_or(false) {
+        let (model, model_request, generation_streams) = fallback::invoke_stream(&state.endpoints, &payload.model, started, |model| {
+            model_request(&state, model, &input)
+        }).await?;
+        let stream_timeouts = model_request.request.timeouts.total_only();
+
+        let chunk = ChatCompletionsResponse {
+            id: req_id.to_string(),
+            object: "chat.completion.chunk".to_owned(),
+            created,
+            model: model.to_owned(),
+            ..Default::default()
+        };
+        let options = ChatStreamOptions {
+            index: 0,
+            stop: model_request.stop,
+            include_usage: payload.stream_options.as_ref().and_then(|options| options.include_usage).unwrap_or(false),
+            tokenizer: model_request.tokenizer,
+            prompt: model_request.request.prompt,
+            tools: tools.is_some(),
+            json_output: json_output.map(Arc::new),
+            logprobs,
+        };
+        let choice_streams = generation_streams.into_iter().enumerate()
+            .map(|(index, generation_stream)| {
+                let options = ChatStreamOptions { index: index as i32, ..options.clone() };
+                let generation_stream = timeout::stream_until(generation_stream, stream_timeouts, started);
+                Box::pin(chat_completion_chunks(generation_stream, chunk.clone(), options))
+            })
+            .collect();
+
+        Ok(fallback::served_by(sse::openai_sse(merge_chat_completion_chunks(choice_streams, chunk)).into_response(), &model))
+    } else {
+        let (model, _, choices) = fallback::with_fallbacks_until(&state.endpoints, &payload.model, started, |model| {
+            model_request(&state, model, &input)
+        }, |model_request| {
+            try_join_all((0..model_request.n).map(|index| chat_completion_choice(model_request, index))).boxed()
+        }).await?;
+        let (choices, usages): (Vec<ChatCompletionsChoice>, Vec<ChatCompletionsUsage>) = choices.into_iter().unzip();
+
+        let output = ChatCompletionsResponse {
+            id: req_id.to_string(),
+            object: "chat.completion".to_owned(),
+            created,
+            model: model.to_owned(),
+            choices,
+            system_fingerprint: None,
+            usage: Some(merge_usage(&usages)),
+        };
+
+        Ok(fallback::served_by(Json(output).into_response(), &model))
+    }
+}
 
-    // EOS token
-    let eot = if payload.model.starts_with("Llama") {
-        "<|eot_id|>"
-    } else if payload.model.starts_with("Phi-3") {
-        "<|end|>"
+/// Model-independent input of a chat completion, validated once for the requested model and its fallbacks.
+struct ChatInput<'a> {
+    request_id: String,
+    payload: &'a ChatCompletions,
+    /// Messages with the response_format and tool_choice instructions and inline images.
+    messages: Vec<ChatCompletionsMessage>,
+    tools: Option<&'a RequestTools>,
+    json_output: Option<&'a JsonOutput>,
+    logprobs: bool,
+    /// Timeouts set by the request headers.
+    timeouts: Timeouts,
+}
+
+/// Chat completion request to one of the models which may serve it.
+struct ModelRequest<'a> {
+    backend: Arc<dyn Backend>,
+    request: GenerateRequest,
+    /// Number of choices, each generated by its own invocation of `request`.
+    n: u32,
+    /// Whether tools are given, in which case generations are parsed as tool calls.
+    tools: bool,
+    json_output: Option<&'a JsonOutput>,
+    /// Stop strings of the model and stop sequences of the request.
+    stop: Vec<String>,
+    tokenizer: Option<Arc<TokenCounter>>,
+    /// Attempts of a generation until it follows response_format.
+    attempts: u32,
+}
+
+/// Build the request of `input` to `model`, which fails if the model does not support one of its parameters.
+fn model_request<'a>(state: &AppState, model: &str, input: &ChatInput<'a>) -> Result<ModelRequest<'a>, Error> {
+    let (endpoint, backend) = state.model(model)?;
+    let payload = input.payload;
+    if input.tools.is_some() && backend.accepts_messages() {
+        return Err(Error::invalid_param("tools", "Tool calling is not supported by the model"));
+    }
+    if input.messages.iter().any(|message| message.images().next().is_some()) && !endpoint.vision {
+        return Err(Error::invalid_param("messages", format!("The model `{}` does not support image inputs", model)));
+    }
+
+    let prompt = if backend.accepts_messages() {
+        String::new()
     } else {
-        return (StatusCode::BAD_REQUEST, "Unsupported model").into_response();
+        state.templates.render(model, &input.messages, input.tools.map(|tools| tools.tools.as_slice()), payload.context.to_owned())
+            .map_err(|err| Error::invalid_param("messages", err.to_string()))?
     };
+    let model_stop = stop::model_stop(endpoint, &state.templates);
+    let request_stop = stop::request_stop(payload.stop.as_ref())?;
+
+    let request = GenerateRequest {
+        request_id: input.request_id.to_owned(),
+        prompt,
+        messages: input.messages.to_owned(),
+        temperature: payload.temperature,
+        top_k: payload.top_k,
+        top_p: payload.top_p,
+        max_tokens: payload.max_tokens,
+        do_sample: payload.do_sample,
+        stop: stop::backend_stop(endpoint, &model_stop, &request_stop),
+        json_schema: input.tools.and_then(RequestTools::call_schema)
+            .or_else(|| input.json_output.map(|json_output| json_output.schema().to_owned())),
+        seed: payload.seed,
+        presence_penalty: payload.presence_penalty,
+        frequency_penalty: payload.frequency_penalty,
+        repetition_penalty: payload.repetition_penalty,
+        logit_bias: payload.logit_bias.to_owned(),
+        logprobs: input.logprobs,
+        top_logprobs: payload.top_logprobs,
+        timeouts: input.timeouts.or_config(&endpoint.timeouts),
+    };
+    check_sampling_params(backend.as_ref(), &request)?;
+
+    Ok(ModelRequest {
+        backend,
+        request,
+        n: payload.n.unwrap_or(1),
+        tools: input.tools.is_some(),
+        json_output: input.json_output,
+        stop: [model_stop, request_stop].concat(),
+        tokenizer: state.tokenizers.get(model),
+        // Generations which do not follow response_format are retried up to response_format_retries times.
+        attempts: if input.json_output.is_some() { endpoint.response_format_retries + 1 } else { 1 },
+    })
+}
 
-    if endpoint.backend == "Bedrock" {
-        let body = BedrockRequest {
-            prompt,
-            top_p: payload.top_p,
-            temperature: payload.temperature,
-            max_gen_len: payload.max_tokens,
-        }.serialize();
-
-        if payload.stream.unwrap_or(false) {
-            let mut output = state.bedrock_client.invoke_model_with_response_stream()
-                .set_model_id(Some(endpoint.target_model.to_owned().expect("target_model must be set for Bedrock backend")))
-                .set_accept(Some("application/json".to_owned()))
-                .set_content_type(Some("application/json".to_owned()))
-                .set_body(Some(body))
-                .send()
-                .await
-                .expect("invoke_model_with_response_stream error");
-
-
-            let stream_responder = Box::pin(async_stream! {
-                let object = "chat.completion.chunk";
-                loop {
-                    match output.body.recv().await {
-                        Ok(Some(response_stream)) => {
-                            let payload_part = response_stream.as_chunk().unwrap();
-                            let chunk = payload_part.bytes.as_ref().unwrap().as_ref();
-                            let resp: BedrockStreamResponse = serde_json::from_slice(chunk).expect("BedrockStreamRespoonse deserialization error");
-                            let data = ChatCompletionsResponse {
-                                id: req_id.to_string(),
-                                object: object.to_owned(),
-                                created,
-                                model: payload.model.to_owned(),
-                                system_fingerprint: None,
-                                choices: vec![
-                                    ChatCompletionsChoice {
-                                        index: 0,
-                                        message: None,
-                                        delta: Some(ChatCompletionsChoiceDelta {
-                                            role: Some("assistant".to_owned()),
-                                            content: Some(resp.generation),
-                                        }),
-                                        logprobs: None,
-                                        finish_reason: resp.stop_reason,
-                                    }
-                                ],
-                                usage: None,
-                            };
-
-                            let event: Result<Event, Error> = Ok(Event::default().json_data(data).unwrap());
-                            yield event;
-                        }
-                        Ok(None) => {
-                            break
-                        }
-                        Err(err) => {
-                            error!("inference_with_response_stream error: {}", err.to_string())
-                        }
-                    }
-                }
-            });
+impl FallbackRequest for ModelRequest<'_> {
+    fn timeouts(&self) -> Timeouts {
+        self.request.timeouts
+    }
+}
 
-            Sse::new(stream_responder)
-                .keep_alive(KeepAlive::default())
-                .into_response()
-        } else {
-            let mut output = state.bedrock_client.invoke_model()
-                .set_model_id(Some(endpoint.target_model.to_owned().expect("target_model must be set for Bedrock backend")))
-                .set_content_type(Some("application/json".to_owned()))
-                .set_accept(Some("application/json".to_owned()))
-                .set_body(Some(body))
-                .send()
-                .await
-                .expect("invoke_model error");
-
-            let predict_output: BedrockResponse = serde_json::from_slice(output.body.as_ref()).unwrap();
-
-            let output = ChatCompletionsResponse {
-                id: req_id.to_string(),
-                object: "chat.completion".to_owned(),
-                created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                model: payload.model.to_owned(),
-                choices: vec![
-                    ChatCompletionsChoice {
-                        index: 0,
-                        message: Some(ChatCompletionsMessage {
-                            role: "assistant".to_owned(),
-                            content: predict_output.generation,
-                        }),
-                        delta: None,
-                        finish_reason: Some(predict_output.stop_reason),
-                        logprobs: None,
-                    }
-                ],
-                system_fingerprint: None,
-                usage: Some(ChatCompletionsUsage::default()),
-            };
+impl Invocations for ModelRequest<'_> {
+    fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    fn requests(&self) -> Vec<&GenerateRequest> {
+        vec![&self.request; self.n as usize]
+    }
+}
 
-            Json(output).into_response()
+/// Generate the choice `index` of a non-streaming chat completion.
+async fn chat_completion_choice(model_request: &ModelRequest<'_>, index: u32) -> Result<(ChatCompletionsChoice, ChatCompletionsUsage), Error> {
+    let ModelRequest { backend, request, stop, tokenizer, tools, json_output, attempts, .. } = model_request;
+    let mut attempt = 0;
+    let (message, finish_reason, usage, logprobs) = loop {
+        attempt += 1;
+        let mut generation = backend.invoke(request).await.map_err(|err| {
+            error!("invoke error: {:?}", err);
+            Error::from(err)
+        })?;
+
+        let generation_usage = generation.usage;
+        let mut logprobs_matcher = LogprobsMatcher::default();
+        logprobs_matcher.push(std::mem::take(&mut generation.logprobs));
+        let (assistant_output, finish_reason) = stop::truncate(generation, stop);
+        let usage = tokenizer::usage(&generation_usage, tokenizer.as_deref(), &request.prompt, &assistant_output);
+        let logprobs = request.logprobs.then(|| logprobs::chat_logprobs(logprobs_matcher.take(&assistant_output)));
+
+        if let Some(tool_calls) = tools.then(|| tools::parse_tool_calls(&assistant_output)).flatten() {
+            let message = ChatCompletionsMessage {
+                content: None,
+                tool_calls: Some(tool_calls),
+                ..ChatCompletionsMessage::new("assistant", "")
+            };
+            break (message, "tool_calls".to_owned(), usage, logprobs);
         }
-    } else {
-        let body = SMPredictionRequest {
-            inputs: prompt,
-            parameters: Some(PredictParams {
-                top_p: payload.top_p,
-                top_k: payload.top_k,
-                temperature: payload.temperature,
-                max_new_tokens: payload.max_tokens,
-                do_sample: payload.do_sample,
-            }),
-        }.serialize();
-
-        if payload.stream.unwrap_or(false) {
-            let mut output = state.smr_client.invoke_endpoint_with_response_stream()
-                .set_inference_id(Some(req_id.to_string()))
-                .set_endpoint_name(Some(endpoint.endpoint_name.to_owned().expect("endpoint_name must be set")))
-                .set_inference_component_name(
-                    if let Some(inference_component) = &endpoint.inference_component {
-                        Some(inference_component.to_owned())
-                    } else {
-                        None
-                    }
-                )
-                .set_body(Some(body))
-                .set_content_type(Some(content_type))
-                .send()
-                .await
-                .unwrap();
-
-
-            let mut stream_response = Box::pin(async_stream! {
-            let start_seq = "{\"generated_text\": \"";
-            let stop_seq = "\"}";
-
-            let mut buf = BytesMut::new();
-            loop {
-                match output.body.recv().await {
-                    Ok(Some(response_stream)) => {
-                        let payload_part = response_stream.as_payload_part().unwrap();
-                        let payload_blob = payload_part.bytes.as_ref().unwrap().as_ref();
-                        buf.put(payload_blob);
-
-                        if buf.starts_with(start_seq.as_bytes()) {
-                            buf.advance(start_seq.len());
-                        } else if buf.ends_with(stop_seq.as_bytes()) {
-                            unsafe {
-                                buf.set_len(buf.len() - stop_seq.as_bytes().len());
-                            }
-                        }
-                        let chunk = buf.chunk();
-                        let content = String::from_utf8(chunk.to_vec()).expect("payload is not UTF-8");
-                        yield Ok(Some(content));
-                        buf.advance(chunk.len());
+        match json_output.map(|json_output| json_output.validate(&assistant_output)) {
+            None => break (ChatCompletionsMessage::new("assistant", assistant_output.as_str()), finish_reason, usage, logprobs),
+            Some(Ok(json)) => break (ChatCompletionsMessage::new("assistant", json.as_str()), finish_reason, usage, logprobs),
+            Some(Err(err)) if attempt < *attempts => warn!("retrying output not following response_format: {}", err),
+            Some(Err(err)) => return Err(Error::InvalidOutput(format!(
+                "The model failed to follow response_format in {} attempt(s): {}", attempts, err))),
+        }
+    };
+
+    Ok((ChatCompletionsChoice {
+        index: index as i32,
+        message: Some(message),
+        delta: None,
+        finish_reason: Some(finish_reason),
+        logprobs,
+    }, usage))
+}
+
+/// Usage of a request with multiple choices, which counts the shared prompt once.
+fn merge_usage(usages: &[ChatCompletionsUsage]) -> ChatCompletionsUsage {
+    let prompt_tokens = usages.iter().map(|usage| usage.prompt_tokens).max().unwrap_or_default();
+    let completion_tokens = usages.iter().map(|usage| usage.completion_tokens).sum();
+    ChatCompletionsUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+#[derive(Clone)]
+struct ChatStreamOptions {
+    /// Index of the choice generated by the stream.
+    index: i32,
+    stop: Vec<String>,
+    include_usage: bool,
+    tokenizer: Option<Arc<TokenCounter>>,
+    prompt: String,
+    /// Whether tools are given, in which case text which may be a tool call is held back
+    /// until it is parsed as one or turns out not to be.
+    tools: bool,
+    /// Output format the completion is validated against at the end of the stream.
+    json_output: Option<Arc<JsonOutput>>,
+    /// Whether chunks carry the log probabilities of the tokens of their content.
+    logprobs: bool,
+}
+
+/// Convert a generation stream into chat completion chunks based on `chunk`.
+///
+/// The last chunk carries `finish_reason` and is followed by a usage chunk with empty `choices`
+/// when `include_usage` is set. The stream ends with the error instead if the generation stream fails.
+///
+/// Tool calls are sent as `tool_calls` deltas as they are parsed, the name of each tool call first and then
+/// its arguments, and the last chunk has `finish_reason: "tool_calls"`. Tool calls which can not be parsed
+/// incrementally are sent as a single delta at the end of the generation. Otherwise, a completion which
+/// does not follow `json_output` ends the stream with an error.
+///
+/// With `logprobs`, each chunk carries the tokens of the content sent so far, so that tokens of
+/// text held back are sent with the chunk releasing it.
+fn chat_completion_chunks(
+    mut generation_stream: GenerationStream,
+    chunk: ChatCompletionsResponse,
+    options: ChatStreamOptions,
+) -> impl Stream<Item = Result<ChatCompletionsResponse, Error>> {
+    async_stream! {
+        let mut first_response = true;
+        let mut usage = Usage::default();
+        let mut completion = String::new();
+        let mut stop_matcher = StopMatcher::new(options.stop);
+        let mut buffering = options.tools;
+        let mut buffer = String::new();
+        let mut called_tools = false;
+        let mut tool_call_parser = ToolCallParser::default();
+        let mut logprobs_matcher = LogprobsMatcher::default();
+        loop {
+            let (mut content, mut finish_reason) = match generation_stream.next().await {
+                Some(Ok(generation_chunk)) => {
+                    usage.merge(&generation_chunk.usage);
+                    logprobs_matcher.push(generation_chunk.logprobs);
+                    match (stop_matcher.push(&generation_chunk.text), generation_chunk.finish_reason) {
+                        ((text, Some(_)), _) => (Some(text), Some("stop".to_owned())),
+                        ((text, None), Some(finish_reason)) => (Some(text + &stop_matcher.flush()), Some(finish_reason)),
+                        ((text, None), None) => (Some(text), None),
                     }
-                    Ok(None) => yield Ok(None),
-                    Err(err) => yield Err(err),
                 }
+                Some(Err(err)) => {
+                    error!("invoke_stream error: {:?}", err);
+                    yield Err(Error::from(err));
+                    return;
+                }
+                None => {
+                    let text = stop_matcher.flush();
+                    (if text.is_empty() { None } else { Some(text) }, Some("length".to_owned()))
+                }
+            };
+            if let Some(content) = content.as_ref() {
+                completion.push_str(content);
             }
-        });
-
-            let object = "chat.completion.chunk";
-            let mut first_response = true;
-            let mut done = false;
-            let stream_responder = Box::pin(async_stream! {
-                while let Some(Ok(mut chunk)) = stream_response.next().await {
-                    let mut finish_reason: Option<String> = None;
-                    if chunk.as_ref().is_some() {
-                        if let Some(end_pos) = chunk.as_ref().unwrap().find(eot) {
-                            chunk = Some(chunk.as_ref().unwrap()[0..end_pos].to_owned());
-                            finish_reason = Some("stop".to_owned());
-                            done = true;
-                        }
-                    } else {
-                        finish_reason = Some("length".to_owned());
-                        done = true;
-                    }
-                    let role = if first_response {
-                        first_response = false;
-                        Some("assistant".to_owned())
-                    } else {
-                        None
-                    };
-
-                    let data = ChatCompletionsResponse {
-                        id: req_id.to_string(),
-                        object: object.to_owned(),
-                        created,
-                        model: payload.model.to_owned(),
-                        system_fingerprint: None,
-                        choices: vec![
-                            ChatCompletionsChoice {
-                                index: 0,
-                                message: None,
-                                delta: Some(ChatCompletionsChoiceDelta {
-                                    role,
-                                    content: chunk,
-                                }),
-                                logprobs: None,
-                                finish_reason,
-                            }
-                        ],
-                        usage: None,
-                    };
-
-                    let event: Result<Event, Error> = Ok(Event::default().json_data(data).unwrap());
-                    yield event;
-
-                    if done {
-                        break;
+            let mut tool_calls = None;
+            // Text of the tool calls, which is not sent as content.
+            let mut tool_calls_text = String::new();
+            if buffering {
+                let text = content.as_deref().unwrap_or_default();
+                buffer.push_str(text);
+                let deltas = tool_call_parser.push(text);
+                if tool_call_parser.called() {
+                    content = None;
+                    if deltas.is_empty() && finish_reason.is_none() {
+                        continue;
                     }
-                }
-            });
-
-            Sse::new(stream_responder)
-                .keep_alive(KeepAlive::default())
-                .into_response()
-        } else {
-            let output = state.smr_client.invoke_endpoint()
-                .set_inference_id(Some(req_id.to_string()))
-                .set_endpoint_name(Some(endpoint.endpoint_name.to_owned().expect("endpoint_name must be set")))
-                .set_inference_component_name(
-                    if let Some(inference_component) = &endpoint.inference_component {
-                        Some(inference_component.to_owned())
-                    } else {
-                        None
+                    tool_calls = (!deltas.is_empty()).then_some(deltas);
+                    tool_calls_text = std::mem::take(&mut buffer);
+                    if finish_reason.is_some() {
+                        finish_reason = Some("tool_calls".to_owned());
                     }
-                )
-                .set_target_model(
-                    if let Some(target_model) = &endpoint.target_model {
-                        Some(target_model.to_owned())
-                    } else {
-                        None
+                } else if finish_reason.is_none() && tools::may_be_tool_call(&buffer) {
+                    continue;
+                } else {
+                    buffering = false;
+                    let text = std::mem::take(&mut buffer);
+                    tool_calls = finish_reason.as_ref().and_then(|_| tools::parse_tool_calls(&text));
+                    if tool_calls.is_some() {
+                        content = None;
+                        tool_calls_text = text;
+                        finish_reason = Some("tool_calls".to_owned());
+                    } else if !text.is_empty() {
+                        content = Some(text);
                     }
-                )
-                .set_body(Some(body))
-                .set_content_type(Some(content_type))
-                .send()
-                .await
-                .expect("invoke error");
-
-            let predict_output: SMPredictionOutput = serde_json::from_slice(output.body.unwrap().as_ref()).unwrap();
-            let eot_pos = predict_output.generated_text.find(eot);
-
-            let mut finish_reason: String;
-            let mut assistant_output: String;
-            if let Some(pos) = eot_pos {
-                finish_reason = "stop".to_owned();
-                assistant_output = predict_output.generated_text[0..pos].to_owned()
+                }
+            }
+            let role = if first_response {
+                first_response = false;
+                Some("assistant".to_owned())
             } else {
-                finish_reason = "length".to_owned();
-                assistant_output = predict_output.generated_text;
+                None
             };
+            let done = finish_reason.is_some();
+            called_tools |= tool_calls.is_some() || tool_call_parser.called();
+            let logprobs = options.logprobs.then(|| {
+                logprobs::chat_logprobs(logprobs_matcher.take(content.as_deref().unwrap_or(&tool_calls_text)))
+            });
 
-            let output = ChatCompletionsResponse {
-                id: req_id.to_string(),
-                object: "chat.completion".to_owned(),
-                created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                model: payload.model.to_owned(),
+            yield Ok(ChatCompletionsResponse {
                 choices: vec![
                     ChatCompletionsChoice {
-                        index: 0,
-                        message: Some(ChatCompletionsMessage {
-                            role: "assistant".to_owned(),
-                            content: assistant_output,
+                        index: options.index,
+                        message: None,
+                        delta: Some(ChatCompletionsChoiceDelta {
+                            role,
+                            content,
+                            tool_calls: tool_calls.map(|tool_calls: Vec<ToolCall>| tool_calls.into_iter()
+                                .enumerate()
+                                .map(|(index, tool_call)| ToolCall { index: tool_call.index.or(Some(index as i32)), ..tool_call })
+                                .collect()),
                         }),
-                        delta: None,
-                        finish_reason: Some(finish_reason),
-                        logprobs: None,
+                        logprobs,
+                        finish_reason,
                     }
                 ],
-                system_fingerprint: None,
-                usage: Some(ChatCompletionsUsage::default()),
-            };
+                ..chunk.clone()
+            });
+
+            if done {
+                break;
+            }
+        }
 
-            Json(output).into_response()
+        if let Some(json_output) = options.json_output.as_ref().filter(|_| !called_tools) {
+            if let Err(err) = json_output.validate(&completion) {
+                yield Err(Error::InvalidOutput(format!("The model failed to follow response_format: {}", err)));
+                return;
+            }
+        }
+
+        if options.include_usage {
+            yield Ok(ChatCompletionsResponse {
+                choices: vec![],
+                usage: Some(tokenizer::usage(&usage, options.tokenizer.as_deref(), &options.prompt, &completion)),
+                ..chunk.clone()
+            });
+        }
+    }
+}
+
+/// Interleave the chunks of the choice streams as they arrive.
+///
+/// The usage chunks of the choices are merged into a single usage chunk sent after all choices finish.
+/// The first error ends the stream.
+fn merge_chat_completion_chunks<S>(
+    choice_streams: Vec<S>,
+    chunk: ChatCompletionsResponse,
+) -> impl Stream<Item = Result<ChatCompletionsResponse, Error>>
+where
+    S: Stream<Item = Result<ChatCompletionsResponse, Error>> + Unpin,
+{
+    async_stream! {
+        let mut chunks = select_all(choice_streams);
+        let mut usages = vec![];
+        while let Some(choice_chunk) = chunks.next().await {
+            match choice_chunk {
+                Ok(ChatCompletionsResponse { usage: Some(usage), .. }) => usages.push(usage),
+                Ok(choice_chunk) => yield Ok(choice_chunk),
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            }
+        }
+
+        if !usages.is_empty() {
+            yield Ok(ChatCompletionsResponse {
+                choices: vec![],
+                usage: Some(merge_usage(&usages)),
+                ..chunk
+            });
         }
     }
 }
@@ -403,15 +569,33 @@ async fn main() {
         .init();
 
     let args = Args::parse();
-    let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+    // Backend calls are retried by the retry policy of each model instead of the SDK.
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .retry_config(aws_config::retry::RetryConfig::disabled())
+        .load()
+        .await;
+    let endpoints = EndpointLoader::load(args.config).expect("unable to load config file");
+    let clients = BackendClients {
+        sagemaker: Arc::new(sagemakerruntime::Client::new(&config)),
+        bedrock: Arc::new(aws_sdk_bedrockruntime::Client::new(&config)),
+    };
 
     let app = Router::new()
         .route("/health", get(health))
+        .route("/admin/targets", get(admin::list_targets))
+        .route("/metrics", get(admin::metrics))
         .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/completions", post(completions::completions))
+        .route("/v1/embeddings", post(embeddings::embeddings))
+        .route("/v1/messages", post(messages::messages))
+        .route("/v1/models", get(models::list_models))
+        .route("/v1/models/*id", get(models::retrieve_model))
         .with_state(AppState {
-            smr_client: Arc::new(sagemakerruntime::Client::new(&config)),
-            bedrock_client: Arc::new(aws_sdk_bedrockruntime::Client::new(&config)),
-            endpoints: Arc::new(EndpointLoader::load(args.config).expect("unable to load config file")),
+            backends: Arc::new(Backends::from_endpoints(&endpoints, &clients).expect("invalid endpoints config")),
+            tokenizers: Arc::new(Tokenizers::from_endpoints(&endpoints).expect("unable to load tokenizers")),
+            templates: Arc::new(ChatTemplates::from_endpoints(&endpoints).expect("unable to load chat templates")),
+            allow_file_images: args.allow_file_images,
+            endpoints: Arc::new(endpoints),
         })
         .layer(
             CorsLayer::new()
@@ -424,3 +608,277 @@ async fn main() {
     info!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use futures::stream;
+
+    use crate::backend::{GenerationChunk, TokenLogprob};
+
+    use super::*;
+
+    fn generation_stream(chunks: Vec<anyhow::Result<GenerationChunk>>) -> GenerationStream {
+        Box::pin(stream::iter(chunks))
+    }
+
+    fn text_chunk(text: &str) -> anyhow::Result<GenerationChunk> {
+        Ok(GenerationChunk { text: text.to_owned(), ..Default::default() })
+    }
+
+    fn options(include_usage: bool) -> ChatStreamOptions {
+        ChatStreamOptions {
+            index: 0,
+            stop: vec!["<|eot_id|>".to_owned()],
+            include_usage,
+            tokenizer: None,
+            prompt: "Hello".to_owned(),
+            tools: false,
+            json_output: None,
+            logprobs: false,
+        }
+    }
+
+    async fn collect(chunks: Vec<anyhow::Result<GenerationChunk>>, options: ChatStreamOptions) -> Vec<(Option<String>, Option<String>)> {
+        chat_completion_chunks(generation_stream(chunks), ChatCompletionsResponse::default(), options)
+            .map(|chunk| match chunk {
+                Ok(chunk) => match chunk.choices.as_slice() {
+                    [choice] => (choice.delta.as_ref().and_then(|delta| delta.content.to_owned()), choice.finish_reason.to_owned()),
+                    _ => (None, None),
+                },
+                Err(err) => (Some(err.to_string()), Some("error".to_owned())),
+            })
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_eot() {
+        let chunks = vec![text_chunk("Hi"), text_chunk(" there<|eot_id|>"), text_chunk("ignored")];
+        assert_eq!(collect(chunks, options(false)).await, vec![
+            (Some("Hi".to_owned()), None),
+            (Some(" there".to_owned()), Some("stop".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_split_stop() {
+        let chunks = vec![text_chunk("Hi<|eo"), text_chunk("t_id|>ignored")];
+        assert_eq!(collect(chunks, options(false)).await, vec![
+            (Some("Hi".to_owned()), None),
+            (Some("".to_owned()), Some("stop".to_owned())),
+        ]);
+
+        let chunks = vec![text_chunk("Hi <|"), text_chunk("there")];
+        assert_eq!(collect(chunks, options(false)).await, vec![
+            (Some("Hi ".to_owned()), None),
+            (Some("<|there".to_owned()), None),
+            (None, Some("length".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_finish_reason() {
+        let finish = Ok(GenerationChunk { text: "!".to_owned(), finish_reason: Some("stop".to_owned()), ..Default::default() });
+        let chunks = vec![text_chunk("Hi"), finish, text_chunk("ignored")];
+        assert_eq!(collect(chunks, options(false)).await, vec![
+            (Some("Hi".to_owned()), None),
+            (Some("!".to_owned()), Some("stop".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_end_of_stream() {
+        assert_eq!(collect(vec![text_chunk("Hi")], options(false)).await, vec![
+            (Some("Hi".to_owned()), None),
+            (None, Some("length".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_error() {
+        let chunks = vec![text_chunk("Hi"), Err(anyhow!("connection reset")), text_chunk("ignored")];
+        assert_eq!(collect(chunks, options(true)).await, vec![
+            (Some("Hi".to_owned()), None),
+            (Some("connection reset".to_owned()), Some("error".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_tool_calls() {
+        let options = || ChatStreamOptions { tools: true, ..options(false) };
+        let finish = |text: &str| Ok(GenerationChunk { text: text.to_owned(), finish_reason: Some("stop".to_owned()), ..Default::default() });
+        let chunks = vec![
+            text_chunk("<|python_tag|>{\"name\": \"get_weather\", "),
+            text_chunk("\"parameters\": {\"location\": "),
+            text_chunk("\"Paris\""),
+            finish("}}"),
+        ];
+        let chunks: Vec<ChatCompletionsResponse> = chat_completion_chunks(generation_stream(chunks), ChatCompletionsResponse::default(), options())
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+
+        // The name is sent once parsed, followed by the arguments as they are generated.
+        assert_eq!(chunks.len(), 3);
+        let deltas: Vec<&ChatCompletionsChoiceDelta> = chunks.iter().map(|chunk| chunk.choices[0].delta.as_ref().unwrap()).collect();
+        assert!(deltas.iter().all(|delta| delta.content.is_none()));
+        let tool_calls = deltas[0].tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].index, Some(0));
+        assert!(tool_calls[0].id.starts_with("call_"));
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(serde_json::to_value(&deltas[1].tool_calls).unwrap(), serde_json::json!([
+            {"index": 0, "function": {"arguments": "\"Paris\""}},
+        ]));
+        let arguments: String = deltas.iter().map(|delta| delta.tool_calls.as_ref().unwrap()[0].function.arguments.as_str()).collect();
+        assert_eq!(arguments, r#"{"location": "Paris"}"#);
+        assert_eq!(chunks[1].choices[0].finish_reason, None);
+        assert_eq!(chunks[2].choices[0].finish_reason.as_deref(), Some("tool_calls"));
+
+        // Tool calls which can not be parsed incrementally are sent at the end of the generation.
+        let chunks = vec![text_chunk("{\"parameters\": {}, "), finish("\"name\": \"get_time\"}")];
+        let chunks: Vec<ChatCompletionsResponse> = chat_completion_chunks(generation_stream(chunks), ChatCompletionsResponse::default(), options())
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].choices[0].finish_reason.as_deref(), Some("tool_calls"));
+        let tool_calls = chunks[0].choices[0].delta.as_ref().unwrap().tool_calls.as_ref().unwrap();
+        assert_eq!((tool_calls[0].index, tool_calls[0].function.name.as_str()), (Some(0), "get_time"));
+
+        let chunks = vec![text_chunk(" "), text_chunk("Hi"), text_chunk(" there")];
+        assert_eq!(collect(chunks, options()).await, vec![
+            (Some(" Hi".to_owned()), None),
+            (Some(" there".to_owned()), None),
+            (None, Some("length".to_owned())),
+        ]);
+
+        let chunks = vec![text_chunk("{not json"), text_chunk("}<|eot_id|>")];
+        assert_eq!(collect(chunks, options()).await, vec![
+            (Some("{not json}".to_owned()), Some("stop".to_owned())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_json_output() {
+        let options = || ChatStreamOptions {
+            json_output: JsonOutput::new(Some(&types::ResponseFormat::JsonObject)).unwrap().map(Arc::new),
+            ..options(false)
+        };
+        let chunks = vec![text_chunk("{\"name\": "), text_chunk("\"Paris\"}<|eot_id|>")];
+        assert_eq!(collect(chunks, options()).await, vec![
+            (Some("{\"name\": ".to_owned()), None),
+            (Some("\"Paris\"}".to_owned()), Some("stop".to_owned())),
+        ]);
+
+        let chunks = vec![text_chunk("Paris<|eot_id|>")];
+        let result = collect(chunks, options()).await;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[1].1.as_deref(), Some("error"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_logprobs() {
+        let token = |token: &str| TokenLogprob { token: token.to_owned(), logprob: -0.5, top_logprobs: vec![] };
+        let chunk = |text: &str, finish_reason: Option<&str>| Ok(GenerationChunk {
+            text: text.to_owned(),
+            finish_reason: finish_reason.map(str::to_owned),
+            logprobs: vec![token(text)],
+            ..Default::default()
+        });
+        let chunks = vec![chunk("Hi", None), chunk("<|eot", None), chunk("_id|>", Some("stop"))];
+        let chunks: Vec<ChatCompletionsResponse> = chat_completion_chunks(generation_stream(chunks), ChatCompletionsResponse::default(), ChatStreamOptions { logprobs: true, ..options(false) })
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+
+        let tokens: Vec<Vec<String>> = chunks.iter()
+            .map(|chunk| chunk.choices[0].logprobs.as_ref().unwrap().content.iter().map(|logprob| logprob.token.to_owned()).collect())
+            .collect();
+        assert_eq!(tokens, vec![vec!["Hi".to_owned()], vec![], vec![]]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_chat_completion_chunks() {
+        let finish = |text: &str, completion_tokens| Ok(GenerationChunk {
+            text: text.to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(5), completion_tokens: Some(completion_tokens) },
+            ..Default::default()
+        });
+        let choice_streams = vec![
+            Box::pin(chat_completion_chunks(generation_stream(vec![text_chunk("Hi"), finish("!", 2)]), ChatCompletionsResponse::default(), options(true))),
+            Box::pin(chat_completion_chunks(generation_stream(vec![finish("Hello", 1)]), ChatCompletionsResponse::default(), ChatStreamOptions { index: 1, ..options(true) })),
+        ];
+        let chunks: Vec<ChatCompletionsResponse> = merge_chat_completion_chunks(choice_streams, ChatCompletionsResponse::default())
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(chunks.len(), 4);
+        let mut finished: Vec<i32> = chunks.iter()
+            .flat_map(|chunk| chunk.choices.iter())
+            .filter(|choice| choice.finish_reason.is_some())
+            .map(|choice| choice.index)
+            .collect();
+        finished.sort();
+        assert_eq!(finished, vec![0, 1]);
+        assert!(chunks[3].choices.is_empty());
+        assert_eq!(chunks[3].usage, Some(ChatCompletionsUsage { prompt_tokens: 5, completion_tokens: 3, total_tokens: 8 }));
+
+        let choice_streams = vec![
+            Box::pin(chat_completion_chunks(generation_stream(vec![Err(anyhow!("connection reset"))]), ChatCompletionsResponse::default(), options(true))),
+        ];
+        let chunks: Vec<Result<ChatCompletionsResponse, Error>> = merge_chat_completion_chunks(choice_streams, ChatCompletionsResponse::default()).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_include_usage() {
+        let finish = |text: &str| Ok(GenerationChunk {
+            text: text.to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(5), completion_tokens: Some(2) },
+            ..Default::default()
+        });
+        let chunks = |include_usage: bool| {
+            let choice_streams = vec![
+                Box::pin(chat_completion_chunks(generation_stream(vec![finish("Hi")]), ChatCompletionsResponse::default(), options(include_usage))),
+                Box::pin(chat_completion_chunks(generation_stream(vec![finish("Hello")]), ChatCompletionsResponse::default(), ChatStreamOptions { index: 1, ..options(include_usage) })),
+            ];
+            merge_chat_completion_chunks(choice_streams, ChatCompletionsResponse::default()).map(|chunk| chunk.unwrap()).collect::<Vec<_>>()
+        };
+
+        let with_usage = chunks(true).await;
+        assert_eq!(with_usage.len(), 3);
+        assert!(with_usage[..2].iter().all(|chunk| chunk.usage.is_none()));
+        let last = serde_json::to_value(&with_usage[2]).unwrap();
+        assert_eq!(last["choices"], serde_json::json!([]));
+        assert_eq!(last["usage"], serde_json::json!({"prompt_tokens": 5, "completion_tokens": 4, "total_tokens": 9}));
+
+        let without_usage = chunks(false).await;
+        assert_eq!(without_usage.len(), 2);
+        assert!(without_usage.iter().all(|chunk| chunk.usage.is_none() && chunk.choices.len() == 1));
+        assert!(serde_json::to_value(&without_usage[1]).unwrap().get("usage").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_chunks_usage() {
+        let finish = Ok(GenerationChunk {
+            text: "Hi".to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            usage: Usage { prompt_tokens: Some(5), completion_tokens: Some(1) },
+            ..Default::default()
+        });
+        let chunks: Vec<ChatCompletionsResponse> = chat_completion_chunks(generation_stream(vec![finish]), ChatCompletionsResponse::default(), options(true))
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].choices[0].delta.as_ref().unwrap().role.as_deref(), Some("assistant"));
+        assert!(chunks[1].choices.is_empty());
+        assert_eq!(chunks[1].usage.as_ref().map(|usage| usage.total_tokens), Some(6));
+    }
+}
@@ -0,0 +1,473 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use async_stream::stream as async_stream;
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream};
+use futures_util::StreamExt;
+use tokio::time::Instant;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::backend::{Backend, GenerateRequest, GenerationStream, Usage};
+use crate::error::{Error, Json, MessagesError};
+use crate::fallback::{self, FallbackRequest, Invocations};
+use crate::{image, stop, timeout, tokenizer};
+use crate::stop::StopMatcher;
+use crate::timeout::Timeouts;
+use crate::tokenizer::TokenCounter;
+use crate::types::{ChatCompletionsContent, ChatCompletionsContentPart, ChatCompletionsMessage, ImageUrl, Messages, MessagesContent, MessagesContentBlock, MessagesContentBlockDelta, MessagesDeltaUsage, MessagesImageSource, MessagesMessageDelta, MessagesResponse, MessagesStreamEvent, MessagesUsage};
+
+/// Anthropic Messages API compatible endpoint. Errors are returned in the Anthropic format.
+#[tracing::instrument(name = "Start Messages", skip(state, headers))]
+pub async fn messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<Messages>, Error>,
+) -> Result<Response, MessagesError> {
+    let Json(payload) = payload?;
+    let req_id = Uuid::new_v4();
+    let started = Instant::now();
+    state.model(&payload.model)?;
+
+    let mut messages = chat_messages(&payload);
+    image::inline_images(&mut messages, state.allow_file_images)?;
+    let input = MessagesInput {
+        request_id: req_id.to_string(),
+        payload: &payload,
+        messages,
+        stop_sequences: payload.stop_sequences.to_owned().unwrap_or_default(),
+        timeouts: Timeouts::from_headers(&headers)?,
+    };
+    let message = MessagesResponse {
+        id: format!("msg_{}", req_id.simple()),
+        object: "message".to_owned(),
+        role: "assistant".to_owned(),
+        content: vec![],
+        model: payload.model.to_owned(),
+        stop_reason: None,
+        stop_sequence: None,
+        usage: MessagesUsage::default(),
+    };
+
+    if payload.stream.unwrap_or(false) {
+        let (model, message_request, generation_streams) = fallback::invoke_stream(&state.endpoints, &payload.model, started, |model| {
+            message_request(&state, model, &input)
+        }).await?;
+        let generation_stream = generation_streams.into_iter().next().expect("a generation stream");
+        let generation_stream = timeout::stream_until(generation_stream, message_request.request.timeouts.total_only(), started);
+
+        let options = MessageStreamOptions {
+            model_stop: message_request.model_stop,
+            stop_sequences: input.stop_sequences,
+            tokenizer: message_request.tokenizer,
+            prompt: message_request.request.prompt,
+        };
+        let message = MessagesResponse { model: model.to_owned(), ..message };
+        let stream_responder = message_stream_events(generation_stream, message, options).map(sse_event);
+
+        Ok(fallback::served_by(Sse::new(stream_responder)
+            .keep_alive(KeepAlive::default())
+            .into_response(), &model))
+    } else {
+        let (model, message_request, generations) = fallback::invoke(&state.endpoints, &payload.model, started, |model| {
+            message_request(&state, model, &input)
+        }).await?;
+        let generation = generations.into_iter().next().expect("a generation");
+
+        let (text, (stop_reason, stop_sequence)) = match find_stop(&generation.text, &message_request.model_stop, &input.stop_sequences) {
+            Some((pos, stop_sequence)) => (generation.text[0..pos].to_owned(), stop_reason(None, stop_sequence)),
+            None => {
+                let finish_reason = generation.finish_reason.unwrap_or_else(|| "length".to_owned());
+                (generation.text, stop_reason(Some(finish_reason), None))
+            }
+        };
+        let usage = tokenizer::usage(&generation.usage, message_request.tokenizer.as_deref(), &message_request.request.prompt, &text);
+
+        Ok(fallback::served_by(Json(MessagesResponse {
+            content: vec![MessagesContentBlock::Text { text }],
+            model: model.to_owned(),
+            stop_reason: Some(stop_reason),
+            stop_sequence,
+            usage: MessagesUsage {
+                input_tokens: usage.prompt_tokens,
+                output_tokens: usage.completion_tokens,
+            },
+            ..message
+        }).into_response(), &model))
+    }
+}
+
+/// Model-independent input of a Messages API request, shared by the requested model and its fallbacks.
+struct MessagesInput<'a> {
+    request_id: String,
+    payload: &'a Messages,
+    messages: Vec<ChatCompletionsMessage>,
+    stop_sequences: Vec<String>,
+    /// Timeouts set by the request headers.
+    timeouts: Timeouts,
+}
+
+/// Messages request to one of the models which may serve it.
+struct MessageRequest {
+    backend: Arc<dyn Backend>,
+    request: GenerateRequest,
+    /// Stop strings of the model, which are not reported as stop sequences.
+    model_stop: Vec<String>,
+    tokenizer: Option<Arc<TokenCounter>>,
+}
+
+/// Build the request of `input` to `model`, rendering the messages with its chat template.
+fn message_request(state: &AppState, model: &str, input: &MessagesInput) -> Result<MessageRequest, Error> {
+    let (endpoint, backend) = state.model(model)?;
+    if input.messages.iter().any(|message| message.images().next().is_some()) && !endpoint.vision {
+        return Err(Error::invalid_param("messages", format!("The model `{}` does not support image inputs", model)));
+    }
+    let prompt = if backend.accepts_messages() {
+        String::new()
+    } else {
+        state.templates.render(model, &input.messages, None, None)
+            .map_err(|err| Error::invalid_param("messages", err.to_string()))?
+    };
+    let model_stop = stop::model_stop(endpoint, &state.templates);
+    let payload = input.payload;
+
+    let request = GenerateRequest {
+        request_id: input.request_id.to_owned(),
+        prompt,
+        messages: input.messages.to_owned(),
+        temperature: payload.temperature,
+        top_k: payload.top_k,
+        top_p: payload.top_p,
+        max_tokens: Some(payload.max_tokens),
+        do_sample: None,
+        stop: stop::backend_stop(endpoint, &model_stop, &input.stop_sequences),
+        timeouts: input.timeouts.or_config(&endpoint.timeouts),
+        ..Default::default()
+    };
+
+    Ok(MessageRequest {
+        backend,
+        request,
+        model_stop,
+        tokenizer: state.tokenizers.get(model),
+    })
+}
+
+impl FallbackRequest for MessageRequest {
+    fn timeouts(&self) -> Timeouts {
+        self.request.timeouts
+    }
+}
+
+impl Invocations for MessageRequest {
+    fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
+    }
+
+    fn requests(&self) -> Vec<&GenerateRequest> {
+        vec![&self.request]
+    }
+}
+
+struct MessageStreamOptions {
+    model_stop: Vec<String>,
+    stop_sequences: Vec<String>,
+    tokenizer: Option<Arc<TokenCounter>>,
+    prompt: String,
+}
+
+/// Convert a generation stream into the events of a streaming Messages API response.
+///
+/// `message_start` is sent with the input tokens once the first chunk arrives, as backends may
+/// report the prompt token count with it. The stream ends with an `error` event if the generation
+/// stream fails.
+fn message_stream_events(
+    mut generation_stream: GenerationStream,
+    message: MessagesResponse,
+    options: MessageStreamOptions,
+) -> impl Stream<Item = MessagesStreamEvent> {
+    async_stream! {
+        let first_chunk = generation_stream.next().await;
+        let first_usage = first_chunk.as_ref().and_then(|chunk| chunk.as_ref().ok()).map(|chunk| chunk.usage).unwrap_or_default();
+        let input_tokens = tokenizer::usage(&first_usage, options.tokenizer.as_deref(), &options.prompt, "").prompt_tokens;
+        let mut generation_stream = stream::iter(first_chunk).chain(generation_stream);
+
+        let mut usage = Usage::default();
+        let mut completion = String::new();
+        let mut stop_matcher = StopMatcher::new([options.model_stop, options.stop_sequences.to_owned()].concat());
+        yield MessagesStreamEvent::MessageStart {
+            message: MessagesResponse { usage: MessagesUsage { input_tokens, output_tokens: 0 }, ..message },
+        };
+        yield MessagesStreamEvent::ContentBlockStart {
+            index: 0,
+            content_block: MessagesContentBlock::Text { text: String::new() },
+        };
+
+        let (stop_reason, stop_sequence) = loop {
+            match generation_stream.next().await {
+                Some(Ok(chunk)) => {
+                    usage.merge(&chunk.usage);
+                    let (mut text, stop) = stop_matcher.push(&chunk.text);
+                    if stop.is_none() && chunk.finish_reason.is_some() {
+                        text.push_str(&stop_matcher.flush());
+                    }
+                    completion.push_str(&text);
+                    if !text.is_empty() {
+                        yield MessagesStreamEvent::ContentBlockDelta {
+                            index: 0,
+                            delta: MessagesContentBlockDelta::TextDelta { text },
+                        };
+                    }
+                    match (stop, chunk.finish_reason) {
+                        (Some(stop), _) => break stop_reason(None, options.stop_sequences.contains(&stop).then_some(stop)),
+                        (None, Some(finish_reason)) => break stop_reason(Some(finish_reason), None),
+                        (None, None) => continue,
+                    }
+                }
+                Some(Err(err)) => {
+                    error!("invoke_stream error: {:?}", err);
+                    yield MessagesStreamEvent::Error { error: Error::from(err).to_messages_error() };
+                    return;
+                }
+                None => {
+                    let text = stop_matcher.flush();
+                    completion.push_str(&text);
+                    if !text.is_empty() {
+                        yield MessagesStreamEvent::ContentBlockDelta {
+                            index: 0,
+                            delta: MessagesContentBlockDelta::TextDelta { text },
+                        };
+                    }
+                    break stop_reason(Some("length".to_owned()), None);
+                }
+            }
+        };
+
+        let usage = tokenizer::usage(&usage, options.tokenizer.as_deref(), &options.prompt, &completion);
+        yield MessagesStreamEvent::ContentBlockStop { index: 0 };
+        yield MessagesStreamEvent::MessageDelta {
+            delta: MessagesMessageDelta { stop_reason: Some(stop_reason), stop_sequence },
+            usage: MessagesDeltaUsage { output_tokens: usage.completion_tokens },
+        };
+        yield MessagesStreamEvent::MessageStop;
+    }
+}
+
+/// Flatten the top-level system prompt and content blocks into chat messages. Messages with
+/// images are converted into text and image parts.
+fn chat_messages(payload: &Messages) -> Vec<ChatCompletionsMessage> {
+    let system = payload.system.iter()
+        .map(|system| ChatCompletionsMessage::new("system".to_owned(), system.text()));
+    let messages = payload.messages.iter()
+        .map(|m| ChatCompletionsMessage {
+            content: Some(chat_content(&m.content)),
+            ..ChatCompletionsMessage::new(m.role.as_str(), "")
+        });
+
+    system.chain(messages).collect()
+}
+
+fn chat_content(content: &MessagesContent) -> ChatCompletionsContent {
+    match content {
+        MessagesContent::Blocks(blocks) if blocks.iter().any(|block| matches!(block, MessagesContentBlock::Image { .. })) => {
+            ChatCompletionsContent::Parts(blocks.iter()
+                .map(|block| match block {
+                    MessagesContentBlock::Text { text } => ChatCompletionsContentPart::Text { text: text.to_owned() },
+                    MessagesContentBlock::Image { source } => ChatCompletionsContentPart::ImageUrl {
+                        image_url: ImageUrl {
+                            url: match source {
+                                MessagesImageSource::Base64 { media_type, data } => format!("data:{};base64,{}", media_type, data),
+                                MessagesImageSource::Url { url } => url.to_owned(),
+                            },
+                            detail: None,
+                        },
+                    },
+                })
+                .collect())
+        }
+        content => ChatCompletionsContent::Text(content.text()),
+    }
+}
+
+/// Find the earliest stop string of the model or stop sequence in `text`.
+///
+/// Returns the position of the match and the matched stop sequence, which is `None` for the
+/// stop strings of the model.
+fn find_stop(text: &str, model_stop: &[String], stop_sequences: &[String]) -> Option<(usize, Option<String>)> {
+    let eot = stop::find_stop(text, model_stop).map(|(pos, _)| (pos, None));
+    let stop_sequence = stop::find_stop(text, stop_sequences).map(|(pos, stop)| (pos, Some(stop.to_owned())));
+
+    match (eot, stop_sequence) {
+        (Some(eot), Some(stop_sequence)) => Some(if stop_sequence.0 < eot.0 { stop_sequence } else { eot }),
+        (eot, stop_sequence) => eot.or(stop_sequence),
+    }
+}
+
+/// Map an OpenAI finish reason or a matched stop sequence to the Messages API stop reason.
+fn stop_reason(finish_reason: Option<String>, stop_sequence: Option<String>) -> (String, Option<String>) {
+    if stop_sequence.is_some() {
+        return ("stop_sequence".to_owned(), stop_sequence);
+    }
+    let stop_reason = match finish_reason.as_deref() {
+        Some("length") => "max_tokens",
+        Some("tool_calls") => "tool_use",
+        _ => "end_turn",
+    };
+    (stop_reason.to_owned(), None)
+}
+
+fn sse_event(event: MessagesStreamEvent) -> Result<Event, Infallible> {
+    Ok(Event::default().event(event.event_name()).json_data(event).expect("unable to serialize event"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::anyhow;
+    use axum::body::to_bytes;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use crate::backend::GenerationChunk;
+    use crate::endpoint_loader::EndpointLoader;
+
+    use super::*;
+
+    fn message() -> MessagesResponse {
+        MessagesResponse {
+            id: "msg_1".to_owned(),
+            object: "message".to_owned(),
+            role: "assistant".to_owned(),
+            content: vec![],
+            model: "Llama-3-70B-Instruct".to_owned(),
+            stop_reason: None,
+            stop_sequence: None,
+            usage: MessagesUsage::default(),
+        }
+    }
+
+    fn options() -> MessageStreamOptions {
+        MessageStreamOptions {
+            model_stop: vec!["<|eot_id|>".to_owned()],
+            stop_sequences: vec!["END".to_owned()],
+            tokenizer: None,
+            prompt: "Hello".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_error() {
+        let temp = TempDir::new().unwrap();
+        let config_path = temp.path().join("config.yaml");
+        fs::write(config_path.as_path(), "models: []\n").unwrap();
+        let state = AppState {
+            endpoints: Arc::new(EndpointLoader::load(config_path.as_path()).unwrap()),
+            backends: Default::default(),
+            tokenizers: Default::default(),
+            templates: Default::default(),
+            allow_file_images: false,
+        };
+        let payload: Messages = serde_json::from_value(json!({
+            "model": "claude-3-5-sonnet",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": "Hello."}],
+        })).unwrap();
+
+        let response = messages(State(state), HeaderMap::new(), Ok(Json(payload))).await.unwrap_err().into_response();
+        assert_eq!(response.status(), 404);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), json!({
+            "type": "error",
+            "error": {"type": "not_found_error", "message": "The model `claude-3-5-sonnet` does not exist"},
+        }));
+    }
+
+    #[test]
+    fn test_chat_messages() {
+        let payload: Messages = serde_json::from_value(json!({
+            "model": "Llama-3-70B-Instruct",
+            "max_tokens": 100,
+            "system": [{"type": "text", "text": "You are a pirate."}],
+            "messages": [
+                {"role": "user", "content": "Hello."},
+                {"role": "assistant", "content": [{"type": "text", "text": "Arr!"}, {"type": "text", "text": "Ahoy!"}]},
+            ],
+        })).unwrap();
+
+        assert_eq!(chat_messages(&payload), vec![
+            ChatCompletionsMessage::new("system", "You are a pirate."),
+            ChatCompletionsMessage::new("user", "Hello."),
+            ChatCompletionsMessage::new("assistant", "Arr!\nAhoy!"),
+        ]);
+
+        let payload: Messages = serde_json::from_value(json!({
+            "model": "Llama-3.2-11B-Vision-Instruct",
+            "max_tokens": 100,
+            "messages": [{"role": "user", "content": [
+                {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}},
+                {"type": "text", "text": "What is this?"},
+            ]}],
+        })).unwrap();
+        let messages = chat_messages(&payload);
+        assert_eq!(messages[0].images().map(|image| image.url.as_str()).collect::<Vec<_>>(), vec!["data:image/png;base64,iVBORw0KGgo="]);
+        assert_eq!(messages[0].text(), "What is this?");
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_events() {
+        let chunks = vec![
+            Ok(GenerationChunk { text: "Hi".to_owned(), usage: Usage { prompt_tokens: Some(12), completion_tokens: None }, ..Default::default() }),
+            Ok(GenerationChunk { text: " there END".to_owned(), ..Default::default() }),
+        ];
+        let events: Vec<MessagesStreamEvent> = message_stream_events(Box::pin(stream::iter(chunks)), message(), options()).collect().await;
+
+        let names: Vec<&str> = events.iter().map(MessagesStreamEvent::event_name).collect();
+        assert_eq!(names, vec!["message_start", "content_block_start", "content_block_delta", "content_block_delta", "content_block_stop", "message_delta", "message_stop"]);
+        match &events[0] {
+            MessagesStreamEvent::MessageStart { message } => assert_eq!(message.usage.input_tokens, 12),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        match &events[5] {
+            MessagesStreamEvent::MessageDelta { delta, .. } => assert_eq!(delta.stop_sequence.as_deref(), Some("END")),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_events_error() {
+        let chunks = vec![Ok(GenerationChunk { text: "Hi".to_owned(), ..Default::default() }), Err(anyhow!(Error::RateLimited("throttled".to_owned())))];
+        let events: Vec<MessagesStreamEvent> = message_stream_events(Box::pin(stream::iter(chunks)), message(), options()).collect().await;
+
+        let event = events.last().unwrap();
+        assert_eq!(event.event_name(), "error");
+        assert_eq!(serde_json::to_value(event).unwrap(), json!({
+            "type": "error",
+            "error": {"type": "rate_limit_error", "message": "throttled"},
+        }));
+    }
+
+    #[test]
+    fn test_find_stop() {
+        let stop_sequences = vec!["\n\nHuman:".to_owned(), "END".to_owned()];
+        let model_stop = vec!["<|eot_id|>".to_owned()];
+        assert_eq!(find_stop("Hello<|eot_id|>", &model_stop, &stop_sequences), Some((5, None)));
+        assert_eq!(find_stop("Hello END<|eot_id|>", &model_stop, &stop_sequences), Some((6, Some("END".to_owned()))));
+        assert_eq!(find_stop("Hello", &model_stop, &stop_sequences), None);
+        assert_eq!(find_stop("Hello END", &[], &[]), None);
+    }
+
+    #[test]
+    fn test_stop_reason() {
+        assert_eq!(stop_reason(Some("stop".to_owned()), None), ("end_turn".to_owned(), None));
+        assert_eq!(stop_reason(Some("length".to_owned()), None), ("max_tokens".to_owned(), None));
+        assert_eq!(stop_reason(None, Some("END".to_owned())), ("stop_sequence".to_owned(), Some("END".to_owned())));
+    }
+}
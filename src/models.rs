@@ -0,0 +1,116 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+};
+
+use crate::AppState;
+use crate::endpoint_loader::{Endpoint, EndpointLoader};
+use crate::error::{Error, Json};
+use crate::types::{Model, ModelList};
+
+/// List all configured models.
+pub async fn list_models(State(state): State<AppState>) -> impl IntoResponse {
+    let data = state.endpoints.endpoints().iter()
+        .map(|endpoint| model(&state.endpoints, endpoint))
+        .collect();
+
+    Json(ModelList {
+        object: "list".to_owned(),
+        data,
+    })
+}
+
+/// Retrieve a configured model by id.
+pub async fn retrieve_model(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Model>, Error> {
+    match state.endpoints.get_endpoint(&id) {
+        Some(endpoint) => Ok(Json(model(&state.endpoints, endpoint))),
+        None => Err(Error::ModelNotFound(id)),
+    }
+}
+
+fn model(endpoints: &EndpointLoader, endpoint: &Endpoint) -> Model {
+    Model {
+        id: endpoint.model.to_owned(),
+        object: "model".to_owned(),
+        created: endpoint.metadata.created.unwrap_or(endpoints.loaded_at()),
+        owned_by: endpoint.backend.owned_by().to_owned(),
+        context_length: endpoint.metadata.context_length,
+        description: endpoint.metadata.description.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use axum::body::to_bytes;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn state() -> AppState {
+        let temp = TempDir::new().unwrap();
+        let config_path = temp.path().join("config.yaml");
+        fs::write(config_path.as_path(), r"models:
+  - model: Llama-3-8B
+    endpoint_name: lmi-llama-3-8b
+    backend: LMI
+    metadata:
+      context_length: 8192
+      description: Llama 3 8B
+      created: 1713398400
+  - model: meta/Llama-3.1-8B
+    target_model: meta.llama3-1-8b-instruct-v1:0
+    backend: Bedrock
+").unwrap();
+        AppState {
+            endpoints: Arc::new(EndpointLoader::load(config_path.as_path()).unwrap()),
+            backends: Default::default(),
+            tokenizers: Default::default(),
+            templates: Default::default(),
+            allow_file_images: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let state = state();
+        let response = list_models(State(state.clone())).await.into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let models: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(models, serde_json::json!({
+            "object": "list",
+            "data": [
+                {
+                    "id": "Llama-3-8B",
+                    "object": "model",
+                    "created": 1713398400,
+                    "owned_by": "sagemaker",
+                    "context_length": 8192,
+                    "description": "Llama 3 8B",
+                },
+                {
+                    "id": "meta/Llama-3.1-8B",
+                    "object": "model",
+                    "created": state.endpoints.loaded_at(),
+                    "owned_by": "bedrock",
+                },
+            ],
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_model() {
+        let Json(model) = retrieve_model(State(state()), Path("meta/Llama-3.1-8B".to_owned())).await.unwrap();
+        assert_eq!(model.id, "meta/Llama-3.1-8B");
+        assert_eq!(model.owned_by, "bedrock");
+
+        let err = retrieve_model(State(state()), Path("Llama-3-70B".to_owned())).await.unwrap_err();
+        assert!(matches!(err, Error::ModelNotFound(_)));
+        assert_eq!(err.into_response().status(), 404);
+    }
+}
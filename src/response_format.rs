@@ -0,0 +1,154 @@
+use jsonschema::JSONSchema;
+use serde_json::{json, Value};
+
+use crate::error::Error;
+use crate::types::{ChatCompletionsMessage, ResponseFormat};
+
+/// JSON output requested by `response_format`, which is enforced by instructions in the prompt,
+/// guided decoding when the endpoint supports it, and validation of the final output.
+pub struct JsonOutput {
+    /// Schema sent to backends with guided decoding. `json_object` asks for any object.
+    schema: Value,
+    name: Option<String>,
+    description: Option<String>,
+    validator: Option<JSONSchema>,
+}
+
+impl JsonOutput {
+    /// Returns `None` for `text`, and rejects schemas which can not be compiled.
+    pub fn new(format: Option<&ResponseFormat>) -> Result<Option<JsonOutput>, Error> {
+        match format {
+            None | Some(ResponseFormat::Text) => Ok(None),
+            Some(ResponseFormat::JsonObject) => Ok(Some(JsonOutput {
+                schema: json!({"type": "object"}),
+                name: None,
+                description: None,
+                validator: None,
+            })),
+            Some(ResponseFormat::JsonSchema { json_schema }) => {
+                let schema = json_schema.schema.to_owned().unwrap_or_else(|| json!({"type": "object"}));
+                let validator = JSONSchema::compile(&schema)
+                    .map_err(|err| Error::invalid_param("response_format", format!("Invalid JSON schema: {}", err)))?;
+                Ok(Some(JsonOutput {
+                    schema,
+                    name: Some(json_schema.name.to_owned()),
+                    description: json_schema.description.to_owned(),
+                    validator: Some(validator),
+                }))
+            }
+        }
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// Instructions appended to the prompt so that models without guided decoding respond with JSON.
+    pub fn instructions(&self) -> String {
+        let mut instructions = "Respond only with a valid JSON object, without any other text.".to_owned();
+        if let Some(name) = self.name.as_ref() {
+            instructions.push_str(&format!(" The object must follow the JSON schema `{}`", name));
+            if let Some(description) = self.description.as_ref() {
+                instructions.push_str(&format!(" ({})", description));
+            }
+            instructions.push_str(&format!(":\n{}", self.schema));
+        }
+        instructions
+    }
+
+    /// Append the instructions to the last user message, or add a user message if there is none.
+    pub fn apply(&self, messages: &[ChatCompletionsMessage]) -> Vec<ChatCompletionsMessage> {
+        append_instructions(messages, &self.instructions())
+    }
+
+    /// Validate the generated text, returning the JSON object without surrounding whitespace
+    /// or a Markdown code fence.
+    pub fn validate(&self, text: &str) -> Result<String, String> {
+        let json = strip_code_fence(text.trim());
+        let value: Value = serde_json::from_str(json).map_err(|err| format!("The output is not valid JSON: {}", err))?;
+        if !value.is_object() {
+            return Err("The output is not a JSON object".to_owned());
+        }
+        if let Some(validator) = self.validator.as_ref() {
+            if let Err(errors) = validator.validate(&value) {
+                let errors: Vec<String> = errors.map(|err| err.to_string()).collect();
+                return Err(format!("The output does not follow the JSON schema: {}", errors.join("; ")));
+            }
+        }
+
+        Ok(json.to_owned())
+    }
+}
+
+/// Append `instructions` to the last user message, or add a user message if there is none.
+pub fn append_instructions(messages: &[ChatCompletionsMessage], instructions: &str) -> Vec<ChatCompletionsMessage> {
+    let mut messages = messages.to_vec();
+    match messages.iter_mut().rev().find(|m| m.role == "user") {
+        Some(user) => user.set_text(format!("{}\n\n{}", user.text(), instructions)),
+        None => messages.push(ChatCompletionsMessage::new("user", instructions)),
+    }
+    messages
+}
+
+fn strip_code_fence(text: &str) -> &str {
+    text.strip_prefix("```")
+        .and_then(|text| text.strip_suffix("```"))
+        .map(|text| text.strip_prefix("json").unwrap_or(text).trim())
+        .unwrap_or(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_schema() -> ResponseFormat {
+        serde_json::from_value(json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "city",
+                "schema": {
+                    "type": "object",
+                    "properties": {"name": {"type": "string"}, "population": {"type": "integer"}},
+                    "required": ["name", "population"],
+                },
+            },
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let output = JsonOutput::new(Some(&json_schema())).unwrap().unwrap();
+        assert_eq!(output.validate(r#" {"name": "Paris", "population": 2102650} "#).unwrap(), r#"{"name": "Paris", "population": 2102650}"#);
+        assert_eq!(output.validate("```json\n{\"name\": \"Paris\", \"population\": 1}\n```").unwrap(), r#"{"name": "Paris", "population": 1}"#);
+        assert!(output.validate(r#"{"name": "Paris"}"#).unwrap_err().contains("JSON schema"));
+        assert!(output.validate("Paris").unwrap_err().contains("not valid JSON"));
+
+        let output = JsonOutput::new(Some(&ResponseFormat::JsonObject)).unwrap().unwrap();
+        assert!(output.validate(r#"{"name": "Paris"}"#).is_ok());
+        assert!(output.validate("[1, 2]").is_err());
+
+        assert!(JsonOutput::new(Some(&ResponseFormat::Text)).unwrap().is_none());
+        let invalid: ResponseFormat = serde_json::from_value(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "invalid", "schema": {"type": "unknown"}},
+        })).unwrap();
+        assert!(JsonOutput::new(Some(&invalid)).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let output = JsonOutput::new(Some(&ResponseFormat::JsonObject)).unwrap().unwrap();
+        let messages = output.apply(&[
+            ChatCompletionsMessage::new("system", "You are a geographer."),
+            ChatCompletionsMessage::new("user", "Describe Paris."),
+        ]);
+        assert_eq!(messages[0].text(), "You are a geographer.");
+        assert_eq!(messages[1].text(), "Describe Paris.\n\nRespond only with a valid JSON object, without any other text.");
+
+        let output = JsonOutput::new(Some(&json_schema())).unwrap().unwrap();
+        let messages = output.apply(&[ChatCompletionsMessage::new("system", "You are a geographer.")]);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].text().contains(r#"JSON schema `city`:
+{"type":"object","properties""#));
+    }
+}
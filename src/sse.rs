@@ -0,0 +1,61 @@
+use std::convert::Infallible;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Sentinel which terminates OpenAI streaming responses.
+pub const DONE: &str = "[DONE]";
+
+/// Encode items as server-sent events in the OpenAI streaming format, terminated by
+/// `data: [DONE]` once the stream ends. Errors are sent as an OpenAI error object, since the
+/// status code has already been sent.
+pub fn openai_sse<S, T>(stream: S) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    S: Stream<Item = Result<T, Error>> + Send + 'static,
+    T: Serialize,
+{
+    let events = stream
+        .map(|data| {
+            let event = match data {
+                Ok(data) => Event::default().json_data(data),
+                Err(err) => Event::default().json_data(err.to_response()),
+            };
+            event.expect("unable to serialize event")
+        })
+        .chain(stream::once(async { Event::default().data(DONE) }))
+        .map(Ok);
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::response::IntoResponse;
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_openai_sse() {
+        let response = openai_sse(stream::iter(vec![Ok(json!({"index": 0})), Ok(json!({"index": 1}))])).into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        assert_eq!(body, "data: {\"index\":0}\n\ndata: {\"index\":1}\n\ndata: [DONE]\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_openai_sse_error() {
+        let response = openai_sse(stream::iter(vec![Ok(json!({"index": 0})), Err(Error::Timeout("timed out".to_owned()))])).into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        assert_eq!(body, concat!(
+            "data: {\"index\":0}\n\n",
+            "data: {\"error\":{\"message\":\"timed out\",\"type\":\"server_error\",\"param\":null,\"code\":\"timeout\"}}\n\n",
+            "data: [DONE]\n\n",
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::backend::Generation;
+use crate::chat_template::ChatTemplates;
+use crate::endpoint_loader::Endpoint;
+use crate::error::Error;
+use crate::types::Stop;
+
+/// Maximum number of stop sequences in a request, as in the OpenAI API.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Stop strings of the model, which default to the EOS token of its chat template.
+pub fn model_stop(endpoint: &Endpoint, templates: &ChatTemplates) -> Vec<String> {
+    match endpoint.stop.as_ref() {
+        Some(stop) => stop.to_owned(),
+        None => templates.eos_token(&endpoint.model).into_iter().collect(),
+    }
+}
+
+/// Stop sequences of the OpenAI `stop` parameter.
+pub fn request_stop(stop: Option<&Stop>) -> Result<Vec<String>, Error> {
+    let stop = stop.map(|stop| stop.sequences()).unwrap_or_default();
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(Error::invalid_param("stop", format!("stop must have at most {} sequences", MAX_STOP_SEQUENCES)));
+    }
+    if stop.iter().any(|stop| stop.is_empty()) {
+        return Err(Error::invalid_param("stop", "stop sequences must not be empty"));
+    }
+    Ok(stop)
+}
+
+/// Stop strings sent to the backend. The model's stop strings are included when the endpoint
+/// is configured to forward them.
+pub fn backend_stop(endpoint: &Endpoint, model_stop: &[String], request_stop: &[String]) -> Vec<String> {
+    let model_stop = if endpoint.forward_stop { model_stop } else { &[] };
+    model_stop.iter().chain(request_stop).cloned().collect()
+}
+
+/// Find the earliest occurrence of any stop string in `text`, returning its position and the
+/// matched stop string.
+pub fn find_stop<'a>(text: &str, stop: &'a [String]) -> Option<(usize, &'a str)> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()).map(|pos| (pos, stop.as_str())))
+        .min_by_key(|(pos, _)| *pos)
+}
+
+/// Truncate the generated text at the first stop string and determine the finish reason.
+pub fn truncate(generation: Generation, stop: &[String]) -> (String, String) {
+    match find_stop(&generation.text, stop) {
+        Some((pos, _)) => (generation.text[0..pos].to_owned(), "stop".to_owned()),
+        None => {
+            let finish_reason = generation.finish_reason.unwrap_or_else(|| "length".to_owned());
+            (generation.text, finish_reason)
+        }
+    }
+}
+
+/// Finds stop strings in streamed text.
+///
+/// A stop string may be split across chunks, so the end of the text which could be the
+/// beginning of a stop string is held back until the following chunk tells whether it matches.
+#[derive(Debug)]
+pub struct StopMatcher {
+    stop: Vec<String>,
+    buffer: String,
+}
+
+impl StopMatcher {
+    pub fn new(stop: Vec<String>) -> StopMatcher {
+        StopMatcher {
+            stop,
+            buffer: String::new(),
+        }
+    }
+
+    /// Append a chunk and return the text which can be sent, and the stop string if one is
+    /// found. The text after the stop string is discarded.
+    pub fn push(&mut self, text: &str) -> (String, Option<String>) {
+        self.buffer.push_str(text);
+        if let Some((pos, stop)) = find_stop(&self.buffer, &self.stop) {
+            let stop = stop.to_owned();
+            let mut text = std::mem::take(&mut self.buffer);
+            text.truncate(pos);
+            return (text, Some(stop));
+        }
+
+        let held = self.partial_match_len();
+        let text = self.buffer[..self.buffer.len() - held].to_owned();
+        self.buffer.drain(..self.buffer.len() - held);
+        (text, None)
+    }
+
+    /// Return the held back text at the end of the stream.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Length of the longest end of the buffer which is the beginning of a stop string.
+    fn partial_match_len(&self) -> usize {
+        self.stop.iter()
+            .filter_map(|stop| {
+                (1..stop.len().min(self.buffer.len() + 1)).rev()
+                    .find(|len| stop.is_char_boundary(*len) && self.buffer.ends_with(&stop[..*len]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_stop() {
+        let stop = vec!["<|eot_id|>".to_owned(), "<|eom_id|>".to_owned()];
+        assert_eq!(find_stop("Hi<|eom_id|><|eot_id|>", &stop), Some((2, "<|eom_id|>")));
+        assert_eq!(find_stop("Hi<|eot_id|>", &stop), Some((2, "<|eot_id|>")));
+        assert_eq!(find_stop("Hi", &stop), None);
+        assert_eq!(find_stop("Hi", &["".to_owned()]), None);
+    }
+
+    #[test]
+    fn test_request_stop() {
+        assert_eq!(request_stop(None).unwrap(), Vec::<String>::new());
+        assert_eq!(request_stop(Some(&Stop::Single("END".to_owned()))).unwrap(), vec!["END".to_owned()]);
+        let stop = Stop::Multiple(["a", "b", "c", "d", "e"].iter().map(|stop| stop.to_string()).collect());
+        assert!(request_stop(Some(&stop)).is_err());
+        assert!(request_stop(Some(&Stop::Single("".to_owned()))).is_err());
+    }
+
+    #[test]
+    fn test_stop_matcher() {
+        let mut matcher = StopMatcher::new(vec!["<|eot_id|>".to_owned(), "\n\nUser:".to_owned()]);
+        assert_eq!(matcher.push("Hello"), ("Hello".to_owned(), None));
+        assert_eq!(matcher.push(" world<|eo"), (" world".to_owned(), None));
+        assert_eq!(matcher.push("t_id|>ignored"), ("".to_owned(), Some("<|eot_id|>".to_owned())));
+
+        let mut matcher = StopMatcher::new(vec!["\n\nUser:".to_owned()]);
+        assert_eq!(matcher.push("Hi.\n"), ("Hi.".to_owned(), None));
+        assert_eq!(matcher.push("\nUs"), ("".to_owned(), None));
+        assert_eq!(matcher.push("ually"), ("\n\nUsually".to_owned(), None));
+        assert_eq!(matcher.push(" so.\n\n"), (" so.".to_owned(), None));
+        assert_eq!(matcher.flush(), "\n\n".to_owned());
+
+        let mut matcher = StopMatcher::new(vec!["終わり".to_owned()]);
+        assert_eq!(matcher.push("これで終"), ("これで".to_owned(), None));
+        assert_eq!(matcher.push("わり"), ("".to_owned(), Some("終わり".to_owned())));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let stop = vec!["<|eot_id|>".to_owned()];
+        let generation = Generation {
+            text: "Paris.<|eot_id|>".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(truncate(generation, &stop), ("Paris.".to_owned(), "stop".to_owned()));
+
+        let generation = Generation {
+            text: "Paris is".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(truncate(generation, &stop), ("Paris is".to_owned(), "length".to_owned()));
+
+        let generation = Generation {
+            text: "Paris.".to_owned(),
+            finish_reason: Some("stop".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(truncate(generation, &[]), ("Paris.".to_owned(), "stop".to_owned()));
+    }
+}
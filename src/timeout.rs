@@ -0,0 +1,185 @@
+use std::future::Future;
+use std::time::Duration;
+
+use async_stream::stream;
+use axum::http::HeaderMap;
+use futures_util::StreamExt;
+use tokio::time::{Instant, timeout_at};
+
+use crate::backend::GenerationStream;
+use crate::endpoint_loader::TimeoutConfig;
+use crate::error::Error;
+
+/// Request header overriding `total_ms` of the model.
+pub const TIMEOUT_HEADER: &str = "x-msgapi-timeout-ms";
+/// Request header overriding `first_token_ms` of the model.
+pub const FIRST_TOKEN_TIMEOUT_HEADER: &str = "x-msgapi-first-token-timeout-ms";
+/// Request header overriding `idle_ms` of the model.
+pub const IDLE_TIMEOUT_HEADER: &str = "x-msgapi-idle-timeout-ms";
+
+/// Timeouts of a request, from the config of a model and the request headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub total: Option<Duration>,
+    pub first_token: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Timeouts set by the request headers, in milliseconds.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Timeouts, Error> {
+        let header = |name: &str| -> Result<Option<Duration>, Error> {
+            let Some(value) = headers.get(name) else {
+                return Ok(None);
+            };
+            match value.to_str().ok().and_then(|value| value.trim().parse::<u64>().ok()) {
+                Some(ms) if ms > 0 => Ok(Some(Duration::from_millis(ms))),
+                _ => Err(Error::invalid_request(format!("{} must be a positive number of milliseconds", name))),
+            }
+        };
+        Ok(Timeouts {
+            total: header(TIMEOUT_HEADER)?,
+            first_token: header(FIRST_TOKEN_TIMEOUT_HEADER)?,
+            idle: header(IDLE_TIMEOUT_HEADER)?,
+        })
+    }
+
+    /// Timeouts of the request to a model, which default to the config of the model.
+    pub fn or_config(self, config: &TimeoutConfig) -> Timeouts {
+        Timeouts {
+            total: self.total.or(config.total_ms.map(Duration::from_millis)),
+            first_token: self.first_token.or(config.first_token_ms.map(Duration::from_millis)),
+            idle: self.idle.or(config.idle_ms.map(Duration::from_millis)),
+        }
+    }
+
+    /// Total timeout alone, for streams whose other timeouts are enforced by the backend.
+    pub fn total_only(self) -> Timeouts {
+        Timeouts { total: self.total, ..Default::default() }
+    }
+
+    /// Deadline of the request started at `started`.
+    pub fn deadline(&self, started: Instant) -> Option<Instant> {
+        self.total.map(|total| started + total)
+    }
+
+    pub fn total_message(&self) -> String {
+        format!("The request did not complete within {} ms", self.total.unwrap_or_default().as_millis())
+    }
+
+    pub fn first_token_message(&self) -> String {
+        format!("The model sent no tokens within {} ms", self.first_token.unwrap_or_default().as_millis())
+    }
+
+    pub fn idle_message(&self) -> String {
+        format!("The model sent no tokens for {} ms", self.idle.unwrap_or_default().as_millis())
+    }
+}
+
+/// Run `future` until `deadline`, failing with a timeout error with `message` after it.
+pub async fn until<T, E, F>(deadline: Option<Instant>, message: String, future: F) -> Result<T, E>
+where
+    E: From<Error>,
+    F: Future<Output = Result<T, E>>,
+{
+    match deadline {
+        Some(deadline) => timeout_at(deadline, future).await.unwrap_or_else(|_| Err(Error::Timeout(message).into())),
+        None => future.await,
+    }
+}
+
+/// End `generation_stream` with a timeout error when the first chunk does not arrive within the
+/// first token timeout, when no chunk arrives within the idle timeout, or when the total timeout
+/// passes, all from the request started at `started`. The generation stream is then dropped,
+/// which cancels the invocation.
+///
+/// Without a first token timeout, the idle timeout applies to the first chunk.
+pub fn stream_until(mut generation_stream: GenerationStream, timeouts: Timeouts, started: Instant) -> GenerationStream {
+    if timeouts == Timeouts::default() {
+        return generation_stream;
+    }
+    Box::pin(stream! {
+        let mut first_chunk = true;
+        loop {
+            let wait = match timeouts.first_token {
+                Some(first_token) if first_chunk => Some((started + first_token, timeouts.first_token_message())),
+                _ => timeouts.idle.map(|idle| (Instant::now() + idle, timeouts.idle_message())),
+            };
+            let total = timeouts.deadline(started).map(|deadline| (deadline, timeouts.total_message()));
+            let (until, message) = [wait, total].into_iter()
+                .flatten()
+                .min_by_key(|(deadline, _)| *deadline)
+                .expect("a timeout is set");
+            match timeout_at(until, generation_stream.next()).await {
+                Ok(Some(chunk)) => yield chunk,
+                Ok(None) => break,
+                Err(_) => {
+                    yield Err(Error::Timeout(message).into());
+                    break;
+                }
+            }
+            first_chunk = false;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use crate::backend::GenerationChunk;
+
+    use super::*;
+
+    #[test]
+    fn test_timeouts() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, "30000".parse().unwrap());
+        let config = TimeoutConfig { total_ms: Some(60_000), first_token_ms: Some(5_000), idle_ms: None };
+        assert_eq!(Timeouts::from_headers(&headers).unwrap().or_config(&config), Timeouts {
+            total: Some(Duration::from_secs(30)),
+            first_token: Some(Duration::from_secs(5)),
+            idle: None,
+        });
+        assert_eq!(Timeouts::from_headers(&headers).unwrap().or_config(&config).total_only(), Timeouts {
+            total: Some(Duration::from_secs(30)),
+            ..Default::default()
+        });
+
+        headers.insert(IDLE_TIMEOUT_HEADER, "0".parse().unwrap());
+        assert!(Timeouts::from_headers(&headers).is_err());
+        headers.insert(IDLE_TIMEOUT_HEADER, "1s".parse().unwrap());
+        assert!(Timeouts::from_headers(&headers).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_until() {
+        let chunk = || Ok(GenerationChunk { text: "Hi".to_owned(), ..Default::default() });
+        let stalled = || Box::pin(stream::iter(vec![chunk()]).chain(stream::pending())) as GenerationStream;
+
+        let idle = Timeouts { idle: Some(Duration::from_millis(10)), ..Default::default() };
+        let chunks: Vec<_> = stream_until(stalled(), idle, Instant::now()).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert_eq!(chunks[1].as_ref().unwrap_err().to_string(), "The model sent no tokens for 10 ms");
+
+        let timeouts = Timeouts { total: Some(Duration::from_millis(10)), idle: Some(Duration::from_millis(500)), ..Default::default() };
+        let chunks: Vec<_> = stream_until(stalled(), timeouts, Instant::now()).collect().await;
+        assert_eq!(chunks.last().unwrap().as_ref().unwrap_err().to_string(), "The request did not complete within 10 ms");
+
+        let chunks: Vec<_> = stream_until(Box::pin(stream::iter(vec![chunk()])), idle, Instant::now()).collect().await;
+        assert_eq!(chunks.len(), 1);
+
+        let first_token = Timeouts { first_token: Some(Duration::from_millis(10)), idle: Some(Duration::from_millis(500)), ..Default::default() };
+        let chunks: Vec<_> = stream_until(Box::pin(stream::pending()), first_token, Instant::now()).collect().await;
+        assert_eq!(chunks[0].as_ref().unwrap_err().to_string(), "The model sent no tokens within 10 ms");
+    }
+
+    #[tokio::test]
+    async fn test_until() {
+        let deadline = Some(Instant::now() + Duration::from_millis(10));
+        let result: Result<(), Error> = until(deadline, "timed out".to_owned(), futures::future::pending()).await;
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        assert_eq!(until(deadline, "timed out".to_owned(), async { Ok::<_, Error>(1) }).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokenizers::Tokenizer;
+
+use crate::backend::Usage;
+use crate::endpoint_loader::EndpointLoader;
+use crate::types::ChatCompletionsUsage;
+
+/// Counts tokens with a HuggingFace `tokenizer.json`.
+#[derive(Debug)]
+pub struct TokenCounter {
+    tokenizer: Tokenizer,
+}
+
+impl TokenCounter {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<TokenCounter> {
+        let tokenizer = Tokenizer::from_file(path.as_ref())
+            .map_err(|err| anyhow!("unable to load tokenizer {}: {}", path.as_ref().display(), err))?;
+
+        Ok(TokenCounter { tokenizer })
+    }
+
+    /// Count tokens of the text as it is sent to or returned by the model, so special tokens
+    /// rendered by the chat template are counted once and no BOS token is added.
+    pub fn count(&self, text: &str) -> i64 {
+        match self.tokenizer.encode(text, false) {
+            Ok(encoding) => encoding.len() as i64,
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Token counters keyed by model name.
+#[derive(Debug, Default)]
+pub struct Tokenizers {
+    tokenizers: HashMap<String, Arc<TokenCounter>>,
+}
+
+impl Tokenizers {
+    pub fn from_endpoints(endpoints: &EndpointLoader) -> Result<Tokenizers> {
+        let mut tokenizers = HashMap::new();
+        for endpoint in endpoints.endpoints() {
+            if let Some(path) = endpoint.tokenizer.as_ref() {
+                tokenizers.insert(endpoint.model.to_owned(), Arc::new(TokenCounter::from_file(path)?));
+            }
+        }
+
+        Ok(Tokenizers { tokenizers })
+    }
+
+    pub fn get<S: AsRef<str>>(&self, model: S) -> Option<Arc<TokenCounter>> {
+        self.tokenizers.get(model.as_ref()).cloned()
+    }
+}
+
+/// Build the usage of a generation, counting tokens the backend did not report with the
+/// model's tokenizer. Counts are zero when neither is available.
+pub fn usage(usage: &Usage, tokenizer: Option<&TokenCounter>, prompt: &str, completion: &str) -> ChatCompletionsUsage {
+    let count = |text: &str| tokenizer.map(|tokenizer| tokenizer.count(text)).unwrap_or(0);
+    let prompt_tokens = usage.prompt_tokens.unwrap_or_else(|| count(prompt));
+    let completion_tokens = usage.completion_tokens.unwrap_or_else(|| count(completion));
+
+    ChatCompletionsUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Word level tokenizer splitting on whitespace, with `<|eot_id|>` as a special token.
+    const TOKENIZER_JSON: &str = r#"{
+  "version": "1.0",
+  "added_tokens": [{"id": 0, "content": "<|eot_id|>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}],
+  "pre_tokenizer": {"type": "Whitespace"},
+  "model": {"type": "WordLevel", "vocab": {"<|eot_id|>": 0, "[UNK]": 1, "hello": 2, "world": 3}, "unk_token": "[UNK]"}
+}"#;
+
+    fn token_counter() -> TokenCounter {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("tokenizer.json");
+        fs::write(path.as_path(), TOKENIZER_JSON).unwrap();
+        TokenCounter::from_file(path).unwrap()
+    }
+
+    #[test]
+    fn test_count() {
+        let counter = token_counter();
+        assert_eq!(counter.count("hello world"), 2);
+        assert_eq!(counter.count("hello world<|eot_id|>"), 3);
+        assert_eq!(counter.count(""), 0);
+    }
+
+    #[test]
+    fn test_usage() {
+        let counter = token_counter();
+        let reported = Usage { prompt_tokens: Some(10), completion_tokens: None };
+        assert_eq!(usage(&reported, Some(&counter), "hello", "hello world"), ChatCompletionsUsage {
+            prompt_tokens: 10,
+            completion_tokens: 2,
+            total_tokens: 12,
+        });
+        assert_eq!(usage(&Usage::default(), None, "hello", "hello world"), ChatCompletionsUsage::default());
+    }
+}
@@ -0,0 +1,434 @@
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::response_format;
+use crate::types::{ChatCompletionsMessage, FunctionCall, Tool, ToolCall, ToolChoice};
+
+/// Marker of a tool call generated by Llama 3.1.
+pub const PYTHON_TAG: &str = "<|python_tag|>";
+/// End of message token generated after a tool call by Llama 3.1.
+const EOM_ID: &str = "<|eom_id|>";
+
+/// Tool call in the Llama 3.1 JSON format.
+#[derive(Deserialize, Debug)]
+struct LlamaToolCall {
+    name: String,
+    #[serde(default, alias = "arguments")]
+    parameters: Map<String, Value>,
+}
+
+/// Tools offered to the model by a request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestTools {
+    pub tools: Vec<Tool>,
+    /// The model must call one of the tools, with `tool_choice: "required"` or a specific function.
+    pub required: bool,
+}
+
+/// Tools offered to the model. Tools are disabled by `tool_choice: "none"`. With a specific
+/// function, it is the only tool offered to the model, which must call it.
+pub fn request_tools(tools: Option<&Vec<Tool>>, tool_choice: Option<&ToolChoice>) -> Result<Option<RequestTools>, Error> {
+    let tools = tools.filter(|tools| !tools.is_empty());
+    let offered = |tools: Vec<Tool>, required: bool| Some(RequestTools { tools, required });
+    match (tools, tool_choice) {
+        (tools, None) => Ok(tools.and_then(|tools| offered(tools.to_owned(), false))),
+        (tools, Some(ToolChoice::Mode(mode))) if mode == "auto" => Ok(tools.and_then(|tools| offered(tools.to_owned(), false))),
+        (_, Some(ToolChoice::Mode(mode))) if mode == "none" => Ok(None),
+        (None, Some(_)) => Err(Error::invalid_param("tool_choice", "tool_choice requires tools")),
+        (Some(tools), Some(ToolChoice::Mode(mode))) if mode == "required" => Ok(offered(tools.to_owned(), true)),
+        (Some(_), Some(ToolChoice::Mode(mode))) => Err(Error::invalid_param("tool_choice", format!("tool_choice `{}` is not supported", mode))),
+        (Some(tools), Some(ToolChoice::Function(choice))) => {
+            let name = choice.pointer("/function/name").and_then(Value::as_str)
+                .ok_or_else(|| Error::invalid_param("tool_choice", format!("tool_choice {} is not a function", choice)))?;
+            let tool = tools.iter().find(|tool| tool.function.name == name)
+                .ok_or_else(|| Error::invalid_param("tool_choice", format!("tool_choice function `{}` is not one of the tools", name)))?;
+            Ok(offered(vec![tool.to_owned()], true))
+        }
+    }
+}
+
+impl RequestTools {
+    /// Instructions appended to the prompt so that the model calls a tool when it is required.
+    pub fn instructions(&self) -> Option<String> {
+        match self.tools.as_slice() {
+            _ if !self.required => None,
+            [tool] => Some(format!("You must call the function `{}`. Respond only with the function call.", tool.function.name)),
+            _ => Some("You must call one of the functions. Respond only with the function call.".to_owned()),
+        }
+    }
+
+    /// Append the instructions to the last user message when a tool call is required.
+    pub fn apply(&self, messages: &[ChatCompletionsMessage]) -> Vec<ChatCompletionsMessage> {
+        match self.instructions() {
+            Some(instructions) => response_format::append_instructions(messages, &instructions),
+            None => messages.to_vec(),
+        }
+    }
+
+    /// JSON schema of a call of one of the tools in the Llama 3.1 format, which constrains the
+    /// output with guided decoding when a tool call is required.
+    pub fn call_schema(&self) -> Option<Value> {
+        if !self.required {
+            return None;
+        }
+        let mut schemas: Vec<Value> = self.tools.iter()
+            .map(|tool| json!({
+                "type": "object",
+                "properties": {
+                    "name": {"const": tool.function.name},
+                    "parameters": tool.function.parameters.to_owned().unwrap_or_else(|| json!({"type": "object"})),
+                },
+                "required": ["name", "parameters"],
+            }))
+            .collect();
+        match schemas.len() {
+            1 => schemas.pop(),
+            _ => Some(json!({"anyOf": schemas})),
+        }
+    }
+}
+
+/// Parse tool calls generated by Llama 3.1, which are JSON objects with `name` and `parameters`
+/// separated by `;`, optionally after `<|python_tag|>`.
+///
+/// Returns `None` when the text is not a tool call.
+pub fn parse_tool_calls(text: &str) -> Option<Vec<ToolCall>> {
+    let text = text.trim();
+    let text = text.strip_prefix(PYTHON_TAG).unwrap_or(text);
+    let mut rest = text.strip_suffix(EOM_ID).unwrap_or(text).trim();
+    if !rest.starts_with('{') {
+        return None;
+    }
+
+    let mut tool_calls = vec![];
+    while !rest.is_empty() {
+        let mut stream = serde_json::Deserializer::from_str(rest).into_iter::<LlamaToolCall>();
+        let tool_call = stream.next()?.ok()?;
+        rest = rest[stream.byte_offset()..].trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+
+        tool_calls.push(ToolCall {
+            index: None,
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            tool_type: "function".to_owned(),
+            function: FunctionCall {
+                name: tool_call.name,
+                arguments: Value::Object(tool_call.parameters).to_string(),
+            },
+        });
+    }
+
+    Some(tool_calls)
+}
+
+/// Whether streamed text may still turn out to be a tool call, so it has to be held back
+/// until the end of the generation.
+pub fn may_be_tool_call(text: &str) -> bool {
+    let text = text.trim_start();
+    text.is_empty() || text.starts_with('{') || text.starts_with(PYTHON_TAG) || PYTHON_TAG.starts_with(text)
+}
+
+/// Incremental parser of tool calls streamed in the Llama 3.1 JSON format.
+///
+/// The `name` of each tool call is sent once parsed, followed by its arguments as they are
+/// generated. Only tool calls whose `name` comes first are parsed incrementally.
+#[derive(Debug, Default)]
+pub struct ToolCallParser {
+    /// Text received and not parsed yet.
+    pending: String,
+    state: ParserState,
+    /// Number of tool calls parsed so far.
+    calls: i32,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    /// Before the next tool call.
+    #[default]
+    Start,
+    /// In the arguments object of the current tool call, with the nesting depth of its objects
+    /// and arrays, which is 0 before the object starts.
+    Arguments { depth: u32, in_string: bool, escaped: bool },
+    /// After the arguments, before the end of the tool call object.
+    End,
+    /// The text is not a tool call, or no more tool calls follow.
+    Done,
+}
+
+/// Text which could not be parsed yet.
+enum Unparsed {
+    /// More text is needed.
+    Incomplete,
+    Invalid,
+}
+
+impl ToolCallParser {
+    /// Whether a tool call was parsed, in which case the text is not content.
+    pub fn called(&self) -> bool {
+        self.calls > 0
+    }
+
+    /// Parse `text`, returning the tool call deltas it completes. Each delta carries the `id`
+    /// and `name` of a new tool call, or continues the arguments of the tool call of `index`.
+    pub fn push(&mut self, text: &str) -> Vec<ToolCall> {
+        self.pending.push_str(text);
+        let mut deltas: Vec<ToolCall> = vec![];
+        loop {
+            match self.state {
+                ParserState::Start => {
+                    let mut rest = self.pending.trim_start();
+                    if self.calls == 0 {
+                        if PYTHON_TAG.starts_with(rest) && rest.len() < PYTHON_TAG.len() {
+                            break;
+                        }
+                        rest = rest.strip_prefix(PYTHON_TAG).unwrap_or(rest);
+                    } else {
+                        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+                        if rest.starts_with(EOM_ID) {
+                            self.state = ParserState::Done;
+                            continue;
+                        }
+                        if EOM_ID.starts_with(rest) {
+                            break;
+                        }
+                    }
+                    match parse_tool_call_start(rest) {
+                        Ok((name, len, arguments)) => {
+                            let offset = self.pending.len() - rest.len() + len;
+                            self.pending.drain(..offset);
+                            deltas.push(ToolCall {
+                                index: Some(self.calls),
+                                id: format!("call_{}", Uuid::new_v4().simple()),
+                                tool_type: "function".to_owned(),
+                                function: FunctionCall { name, arguments: if arguments { String::new() } else { "{}".to_owned() } },
+                            });
+                            self.calls += 1;
+                            self.state = if arguments {
+                                ParserState::Arguments { depth: 0, in_string: false, escaped: false }
+                            } else {
+                                ParserState::Start
+                            };
+                        }
+                        Err(Unparsed::Incomplete) => break,
+                        Err(Unparsed::Invalid) => self.state = ParserState::Done,
+                    }
+                }
+                ParserState::Arguments { mut depth, mut in_string, mut escaped } => {
+                    if depth == 0 {
+                        let whitespace = self.pending.len() - self.pending.trim_start().len();
+                        self.pending.drain(..whitespace);
+                        match self.pending.chars().next() {
+                            None => break,
+                            Some('{') => {}
+                            // Arguments must be an object.
+                            Some(_) => {
+                                self.state = ParserState::Done;
+                                continue;
+                            }
+                        }
+                    }
+                    let mut end = None;
+                    for (position, char) in self.pending.char_indices() {
+                        if in_string {
+                            match char {
+                                _ if escaped => escaped = false,
+                                '\\' => escaped = true,
+                                '"' => in_string = false,
+                                _ => {}
+                            }
+                            continue;
+                        }
+                        match char {
+                            '"' => in_string = true,
+                            '{' | '[' => depth += 1,
+                            '}' | ']' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    end = Some(position + 1);
+                                    break;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    let fragment: String = self.pending.drain(..end.unwrap_or(self.pending.len())).collect();
+                    if !fragment.is_empty() {
+                        let index = self.calls - 1;
+                        match deltas.last_mut().filter(|delta| delta.index == Some(index)) {
+                            Some(delta) => delta.function.arguments.push_str(&fragment),
+                            None => deltas.push(ToolCall {
+                                index: Some(index),
+                                id: String::new(),
+                                tool_type: String::new(),
+                                function: FunctionCall { name: String::new(), arguments: fragment },
+                            }),
+                        }
+                    }
+                    if end.is_none() {
+                        self.state = ParserState::Arguments { depth, in_string, escaped };
+                        break;
+                    }
+                    self.state = ParserState::End;
+                }
+                ParserState::End => match token(&self.pending, &["}"]) {
+                    Ok((_, rest)) => {
+                        let offset = self.pending.len() - rest.len();
+                        self.pending.drain(..offset);
+                        self.state = ParserState::Start;
+                    }
+                    Err(Unparsed::Incomplete) => break,
+                    Err(Unparsed::Invalid) => self.state = ParserState::Done,
+                },
+                ParserState::Done => {
+                    self.pending.clear();
+                    break;
+                }
+            }
+        }
+        deltas
+    }
+}
+
+/// Parse the start of a tool call object up to its arguments, `{"name": "...", "parameters": `,
+/// or the whole object without arguments, `{"name": "..."}`. Returns the name, the length of the
+/// parsed text and whether arguments follow.
+fn parse_tool_call_start(text: &str) -> Result<(String, usize, bool), Unparsed> {
+    let (_, rest) = token(text, &["{"])?;
+    let (_, rest) = token(rest, &["\"name\""])?;
+    let (_, rest) = token(rest, &[":"])?;
+    let rest = rest.trim_start();
+    let mut names = serde_json::Deserializer::from_str(rest).into_iter::<String>();
+    let name = match names.next() {
+        Some(Ok(name)) => name,
+        None => return Err(Unparsed::Incomplete),
+        Some(Err(err)) if err.is_eof() => return Err(Unparsed::Incomplete),
+        Some(Err(_)) => return Err(Unparsed::Invalid),
+    };
+    let rest = &rest[names.byte_offset()..];
+    let (separator, rest) = token(rest, &[",", "}"])?;
+    if separator == "}" {
+        return Ok((name, text.len() - rest.len(), false));
+    }
+    let (_, rest) = token(rest, &["\"parameters\"", "\"arguments\""])?;
+    let (_, rest) = token(rest, &[":"])?;
+    Ok((name, text.len() - rest.len(), true))
+}
+
+/// Skip whitespace and one of `tokens` at the start of `text`, returning the token and the rest
+/// of the text.
+fn token<'a>(text: &'a str, tokens: &[&'static str]) -> Result<(&'static str, &'a str), Unparsed> {
+    let text = text.trim_start();
+    for token in tokens {
+        if let Some(rest) = text.strip_prefix(token) {
+            return Ok((token, rest));
+        }
+    }
+    if tokens.iter().any(|token| token.starts_with(text)) {
+        return Err(Unparsed::Incomplete);
+    }
+    Err(Unparsed::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tool_calls() {
+        let tool_calls = parse_tool_calls(r#"{"name": "get_weather", "parameters": {"location": "Paris"}}"#).unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].function, FunctionCall {
+            name: "get_weather".to_owned(),
+            arguments: r#"{"location":"Paris"}"#.to_owned(),
+        });
+        assert!(tool_calls[0].id.starts_with("call_"));
+
+        let tool_calls = parse_tool_calls(r#"<|python_tag|>{"name": "get_weather", "parameters": {"location": "Paris"}}; {"name": "get_time"}<|eom_id|>"#).unwrap();
+        assert_eq!(tool_calls.iter().map(|tool_call| tool_call.function.name.as_str()).collect::<Vec<_>>(), vec!["get_weather", "get_time"]);
+        assert_eq!(tool_calls[1].function.arguments, "{}");
+
+        assert!(parse_tool_calls("The weather in Paris is sunny.").is_none());
+        assert!(parse_tool_calls(r#"{"location": "Paris"}"#).is_none());
+        assert!(parse_tool_calls(r#"{"name": "get_weather"} and more"#).is_none());
+    }
+
+    #[test]
+    fn test_tool_call_parser() {
+        let mut parser = ToolCallParser::default();
+        assert!(parser.push("<|python").is_empty());
+        assert!(parser.push("_tag|>{\"name\": \"get_wea").is_empty());
+        assert!(!parser.called());
+
+        let deltas = parser.push("ther\", \"parameters\": {\"city\": \"}{\\\"\"");
+        assert!(parser.called());
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].function, FunctionCall { name: "get_weather".to_owned(), arguments: r#"{"city": "}{\"""#.to_owned() });
+
+        let deltas = parser.push(", \"days\": [1, 2]}}; {\"name\": \"get_time\"}<|eom_id|>ignored");
+        let deltas: Vec<(Option<i32>, &str, &str)> = deltas.iter()
+            .map(|delta| (delta.index, delta.function.name.as_str(), delta.function.arguments.as_str()))
+            .collect();
+        assert_eq!(deltas, vec![(Some(0), "", r#", "days": [1, 2]}"#), (Some(1), "get_time", "{}")]);
+        assert!(parser.push("{\"name\": \"get_date\"}").is_empty());
+
+        let mut parser = ToolCallParser::default();
+        assert!(parser.push("The weather").is_empty());
+        assert!(parser.push(" {\"name\": \"get_weather\"}").is_empty());
+        assert!(!parser.called());
+    }
+
+    #[test]
+    fn test_may_be_tool_call() {
+        assert!(may_be_tool_call(""));
+        assert!(may_be_tool_call(" {\"name\""));
+        assert!(may_be_tool_call("<|python"));
+        assert!(may_be_tool_call("<|python_tag|>brave_search.call"));
+        assert!(!may_be_tool_call("The"));
+    }
+
+    #[test]
+    fn test_request_tools() {
+        let tool = |name: &str| Tool {
+            tool_type: "function".to_owned(),
+            function: serde_json::from_value(json!({"name": name, "parameters": {"type": "object"}})).unwrap(),
+        };
+        let tools = vec![tool("get_weather"), tool("get_time")];
+        let choice = |value: Value| serde_json::from_value::<ToolChoice>(value).unwrap();
+        assert_eq!(request_tools(Some(&tools), None).unwrap(), Some(RequestTools { tools: tools.clone(), required: false }));
+        assert_eq!(request_tools(Some(&tools), Some(&choice(json!("none")))).unwrap(), None);
+        assert_eq!(request_tools(Some(&vec![]), None).unwrap(), None);
+        assert_eq!(request_tools(Some(&tools), Some(&choice(json!("required")))).unwrap(), Some(RequestTools { tools: tools.clone(), required: true }));
+
+        let function = json!({"type": "function", "function": {"name": "get_time"}});
+        assert_eq!(request_tools(Some(&tools), Some(&choice(function.clone()))).unwrap(), Some(RequestTools { tools: vec![tool("get_time")], required: true }));
+        assert!(request_tools(None, Some(&choice(function))).is_err());
+        assert!(request_tools(Some(&tools), Some(&choice(json!({"type": "function", "function": {"name": "get_date"}})))).is_err());
+        assert!(request_tools(Some(&tools), Some(&choice(json!("any")))).is_err());
+    }
+
+    #[test]
+    fn test_required_tools() {
+        let tools: Vec<Tool> = serde_json::from_value(json!([
+            {"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object", "properties": {"city": {"type": "string"}}}}},
+            {"type": "function", "function": {"name": "get_time"}},
+        ])).unwrap();
+        let auto = RequestTools { tools: tools.clone(), required: false };
+        assert_eq!(auto.instructions(), None);
+        assert_eq!(auto.call_schema(), None);
+        let messages = vec![ChatCompletionsMessage::new("user", "Weather in Paris?")];
+        assert_eq!(auto.apply(&messages), messages);
+
+        let required = RequestTools { tools: tools[..1].to_vec(), required: true };
+        assert_eq!(required.apply(&messages)[0].text(), "Weather in Paris?\n\nYou must call the function `get_weather`. Respond only with the function call.");
+        let schema = required.call_schema().unwrap();
+        assert_eq!(schema["properties"]["name"], json!({"const": "get_weather"}));
+        assert_eq!(schema["properties"]["parameters"]["properties"]["city"], json!({"type": "string"}));
+        assert!(parse_tool_calls(r#"{"name": "get_weather", "parameters": {"city": "Paris"}}"#).is_some());
+
+        let required = RequestTools { tools, required: true };
+        let schema = required.call_schema().unwrap();
+        assert_eq!(schema["anyOf"][1]["properties"]["parameters"], json!({"type": "object"}));
+    }
+}
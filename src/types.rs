@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+
 use aws_sdk_sagemakerruntime::primitives::Blob;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+
+use crate::error::MessagesErrorBody;
 
 #[derive(Deserialize, Debug)]
 pub struct ChatCompletions {
@@ -11,26 +15,212 @@ pub struct ChatCompletions {
     pub top_k: Option<i32>,
     pub top_p: Option<f32>,
     pub stream: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
     pub do_sample: Option<bool>,
+    pub seed: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// TGI/LMI repetition penalty, which is not part of the OpenAI API.
+    pub repetition_penalty: Option<f32>,
+    /// Bias added to the logits of token ids.
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// Number of choices to generate.
+    pub n: Option<u32>,
+    /// Return the log probabilities of the generated tokens.
+    pub logprobs: Option<bool>,
+    /// Number of most likely alternatives returned for each token, which requires `logprobs`.
+    pub top_logprobs: Option<u32>,
+    pub stop: Option<Stop>,
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<ToolChoice>,
+    pub response_format: Option<ResponseFormat>,
     pub context: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+/// OpenAI `stop` parameter, which is a single sequence or a list of sequences.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Stop {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Stop {
+    pub fn sequences(&self) -> Vec<String> {
+        match self {
+            Stop::Single(stop) => vec![stop.to_owned()],
+            Stop::Multiple(stop) => stop.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ChatCompletionsMessage {
     pub role: String,
-    pub content: String,
+    /// Content of the message, which is `null` for assistant messages with tool calls.
+    #[serde(default)]
+    pub content: Option<ChatCompletionsContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Id of the tool call which a `tool` message responds to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 impl ChatCompletionsMessage {
     pub fn new<S: AsRef<str>>(role: S, content: S) -> ChatCompletionsMessage {
         ChatCompletionsMessage {
             role: role.as_ref().to_owned(),
-            content: content.as_ref().to_owned(),
+            content: Some(ChatCompletionsContent::Text(content.as_ref().to_owned())),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Text of the message, with text parts joined by newlines.
+    pub fn text(&self) -> String {
+        match self.content.as_ref() {
+            None => String::new(),
+            Some(ChatCompletionsContent::Text(text)) => text.to_owned(),
+            Some(ChatCompletionsContent::Parts(parts)) => parts.iter()
+                .filter_map(|part| match part {
+                    ChatCompletionsContentPart::Text { text } => Some(text.as_str()),
+                    ChatCompletionsContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    /// Replace the text of the message, keeping its images. The text takes the place of the first
+    /// text part, and the other text parts are removed.
+    pub fn set_text(&mut self, text: String) {
+        match self.content.as_mut() {
+            Some(ChatCompletionsContent::Parts(parts)) => {
+                let mut text = Some(text);
+                parts.retain_mut(|part| match part {
+                    ChatCompletionsContentPart::Text { text: part_text } => match text.take() {
+                        Some(text) => {
+                            *part_text = text;
+                            true
+                        }
+                        None => false,
+                    },
+                    ChatCompletionsContentPart::ImageUrl { .. } => true,
+                });
+                if let Some(text) = text {
+                    parts.push(ChatCompletionsContentPart::Text { text });
+                }
+            }
+            _ => self.content = Some(ChatCompletionsContent::Text(text)),
         }
     }
+
+    pub fn images(&self) -> impl Iterator<Item = &ImageUrl> {
+        let parts = match self.content.as_ref() {
+            Some(ChatCompletionsContent::Parts(parts)) => parts.as_slice(),
+            _ => &[],
+        };
+        parts.iter().filter_map(|part| match part {
+            ChatCompletionsContentPart::ImageUrl { image_url } => Some(image_url),
+            ChatCompletionsContentPart::Text { .. } => None,
+        })
+    }
 }
 
-#[derive(Serialize, Debug, Default)]
+/// Message content given either as a plain string or as a list of content parts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ChatCompletionsContent {
+    Text(String),
+    Parts(Vec<ChatCompletionsContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatCompletionsContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageUrl {
+    /// Base64 encoded `data:` URL, or a local file path when file images are allowed.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Tool the model may call.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+/// `tool_choice` parameter, which is `none`, `auto`, `required` or a specific function.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function(Value),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    /// Position of the tool call, which is only set in stream deltas.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<i32>,
+    /// Empty in the stream deltas continuing the arguments of a tool call.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    #[serde(rename = "type", skip_serializing_if = "String::is_empty")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    /// Empty in the stream deltas continuing the arguments of a tool call.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Arguments encoded as a JSON object, or a fragment of them in stream deltas.
+    pub arguments: String,
+}
+
+/// `response_format` parameter, which asks for a JSON object optionally following a schema.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub description: Option<String>,
+    pub schema: Option<Value>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
 pub struct ChatCompletionsResponse {
     pub id: String,
     pub object: String,
@@ -42,26 +232,50 @@ pub struct ChatCompletionsResponse {
     pub usage: Option<ChatCompletionsUsage>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct ChatCompletionsChoice {
     pub index: i32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message: Option<ChatCompletionsMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub delta: Option<ChatCompletionsChoiceDelta>,
-    pub logprobs: Option<i32>,
+    pub logprobs: Option<ChatCompletionsLogprobs>,
     pub finish_reason: Option<String>,
 }
 
-#[derive(Serialize, Debug, Default)]
+/// Log probabilities of the tokens of a choice or a delta.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ChatCompletionsLogprobs {
+    pub content: Vec<ChatCompletionsTokenLogprob>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChatCompletionsTokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    /// UTF-8 bytes of the token.
+    pub bytes: Option<Vec<u8>>,
+    pub top_logprobs: Vec<ChatCompletionsTopLogprob>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChatCompletionsTopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
 pub struct ChatCompletionsChoiceDelta {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
 }
 
-#[derive(Serialize, Debug, Default)]
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
 pub struct ChatCompletionsUsage {
     pub prompt_tokens: i64,
     pub completion_tokens: i64,
@@ -81,6 +295,25 @@ pub struct PredictParams {
     pub max_new_tokens: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub do_sample: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<bool>,
+    /// Number of most likely alternatives returned with each token in `details`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_n_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// TGI guided decoding, `{"type": "json", "value": <schema>}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grammar: Option<Value>,
+    /// LMI (vLLM and lmi-dist) guided decoding with a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_json: Option<Value>,
 }
 
 impl SMPredictionRequest {
@@ -99,6 +332,130 @@ pub struct SMPredictionRequest {
 #[derive(Deserialize, Debug)]
 pub struct SMPredictionOutput {
     pub generated_text: String,
+    pub details: Option<SMPredictionDetails>,
+}
+
+/// Generation details returned by LMI/TGI when `details` parameter is set.
+#[derive(Deserialize, Debug, Default)]
+pub struct SMPredictionDetails {
+    pub finish_reason: Option<String>,
+    pub generated_tokens: Option<i64>,
+    pub prompt_tokens: Option<i64>,
+    #[serde(default)]
+    pub tokens: Vec<SMToken>,
+    /// Most likely alternatives of each token, returned when `top_n_tokens` is set.
+    #[serde(default)]
+    pub top_tokens: Vec<Vec<SMToken>>,
+}
+
+/// Generated token in LMI/TGI details and token streams.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SMToken {
+    pub text: String,
+    /// TGI reports `logprob` and LMI `log_prob`.
+    #[serde(default, alias = "log_prob")]
+    pub logprob: f32,
+    #[serde(default)]
+    pub special: bool,
+}
+
+/// Event of a TGI token stream, or a line of an LMI JSON lines stream.
+#[derive(Deserialize, Debug)]
+pub struct SMStreamToken {
+    pub token: SMToken,
+    #[serde(default)]
+    pub top_tokens: Vec<SMToken>,
+    /// Details of the generation, sent with the last token.
+    pub details: Option<SMPredictionDetails>,
+}
+
+/// Error event of a TGI token stream.
+#[derive(Deserialize, Debug)]
+pub struct SMStreamError {
+    pub error: String,
+}
+
+/// LMI chat completions schema, used to send images to multimodal models. The endpoint applies
+/// the chat template and returns an OpenAI chat completion, or chat completion chunks as JSON lines.
+#[derive(Serialize, Debug, Default)]
+pub struct LmiChatRequest {
+    pub messages: Vec<ChatCompletionsMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub do_sample: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repetition_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    /// TGI guided decoding of the messages API, `{"type": "json", "value": <schema>}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+    /// LMI (vLLM and lmi-dist) guided decoding with a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guided_json: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+impl LmiChatRequest {
+    pub fn serialize(&self) -> Blob {
+        Blob::new(json!(self).to_string().as_str())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LmiChatResponse {
+    pub choices: Vec<LmiChatChoice>,
+    pub usage: Option<LmiChatUsage>,
+}
+
+/// Choice of a chat completion, with `message`, or of a chunk, with `delta`.
+#[derive(Deserialize, Debug)]
+pub struct LmiChatChoice {
+    pub message: Option<LmiChatMessage>,
+    pub delta: Option<LmiChatMessage>,
+    pub finish_reason: Option<String>,
+    pub logprobs: Option<LmiChatLogprobs>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LmiChatLogprobs {
+    #[serde(default)]
+    pub content: Vec<LmiChatTokenLogprob>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LmiChatTokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<LmiChatTokenLogprob>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LmiChatMessage {
+    pub content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LmiChatUsage {
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
 }
 
 #[derive(Serialize, Debug, Default)]
@@ -119,6 +476,8 @@ impl BedrockRequest {
 #[derive(Deserialize, Debug, Default)]
 pub struct BedrockResponse {
     pub generation: String,
+    pub prompt_token_count: Option<i64>,
+    pub generation_token_count: Option<i64>,
     pub stop_reason: String,
 }
 
@@ -128,4 +487,312 @@ pub struct BedrockStreamResponse {
     pub prompt_token_count: Option<i64>,
     pub generation_token_count: Option<i64>,
     pub stop_reason: Option<String>,
+    #[serde(rename = "amazon-bedrock-invocationMetrics")]
+    pub invocation_metrics: Option<BedrockInvocationMetrics>,
+}
+
+/// Metrics Bedrock appends to the last chunk of a response stream.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BedrockInvocationMetrics {
+    pub input_token_count: Option<i64>,
+    pub output_token_count: Option<i64>,
+}
+
+/// Request body of the Anthropic Messages API.
+#[derive(Deserialize, Debug)]
+pub struct Messages {
+    pub model: String,
+    pub messages: Vec<MessagesMessage>,
+    pub system: Option<MessagesContent>,
+    pub max_tokens: i64,
+    pub stop_sequences: Option<Vec<String>>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub stream: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MessagesMessage {
+    pub role: String,
+    pub content: MessagesContent,
+}
+
+/// Message content given either as a plain string or as a list of content blocks.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum MessagesContent {
+    Text(String),
+    Blocks(Vec<MessagesContentBlock>),
+}
+
+impl MessagesContent {
+    pub fn text(&self) -> String {
+        match self {
+            MessagesContent::Text(text) => text.to_owned(),
+            MessagesContent::Blocks(blocks) => blocks.iter()
+                .filter_map(|block| match block {
+                    MessagesContentBlock::Text { text } => Some(text.as_str()),
+                    MessagesContentBlock::Image { .. } => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesContentBlock {
+    Text { text: String },
+    /// Image of a user message, which is not part of responses.
+    Image { source: MessagesImageSource },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MessagesResponse {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub object: String,
+    pub role: String,
+    pub content: Vec<MessagesContentBlock>,
+    pub model: String,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: MessagesUsage,
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct MessagesUsage {
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+}
+
+/// Server-sent events of a streaming Messages API response.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesStreamEvent {
+    MessageStart { message: MessagesResponse },
+    ContentBlockStart { index: i32, content_block: MessagesContentBlock },
+    ContentBlockDelta { index: i32, delta: MessagesContentBlockDelta },
+    ContentBlockStop { index: i32 },
+    MessageDelta { delta: MessagesMessageDelta, usage: MessagesDeltaUsage },
+    MessageStop,
+    Error { error: MessagesErrorBody },
+}
+
+impl MessagesStreamEvent {
+    /// SSE event name, which is the same as the `type` field.
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            MessagesStreamEvent::MessageStart { .. } => "message_start",
+            MessagesStreamEvent::ContentBlockStart { .. } => "content_block_start",
+            MessagesStreamEvent::ContentBlockDelta { .. } => "content_block_delta",
+            MessagesStreamEvent::ContentBlockStop { .. } => "content_block_stop",
+            MessagesStreamEvent::MessageDelta { .. } => "message_delta",
+            MessagesStreamEvent::MessageStop => "message_stop",
+            MessagesStreamEvent::Error { .. } => "error",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagesContentBlockDelta {
+    TextDelta { text: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct MessagesMessageDelta {
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MessagesDeltaUsage {
+    pub output_tokens: i64,
+}
+
+/// OpenAI model object.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Model {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub owned_by: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ModelList {
+    pub object: String,
+    pub data: Vec<Model>,
+}
+
+/// Request body of the legacy text completion API.
+#[derive(Deserialize, Debug)]
+pub struct Completions {
+    pub model: String,
+    pub prompt: CompletionsPrompt,
+    pub suffix: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<i64>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub stream: Option<bool>,
+    pub do_sample: Option<bool>,
+    pub seed: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// TGI/LMI repetition penalty, which is not part of the OpenAI API.
+    pub repetition_penalty: Option<f32>,
+    /// Bias added to the logits of token ids.
+    pub logit_bias: Option<HashMap<String, f32>>,
+    pub echo: Option<bool>,
+    pub logprobs: Option<i32>,
+    pub stop: Option<Stop>,
+}
+
+/// A single prompt or a batch of prompts.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CompletionsPrompt {
+    Text(String),
+    Batch(Vec<String>),
+}
+
+impl CompletionsPrompt {
+    pub fn prompts(&self) -> Vec<String> {
+        match self {
+            CompletionsPrompt::Text(prompt) => vec![prompt.to_owned()],
+            CompletionsPrompt::Batch(prompts) => prompts.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: Option<String>,
+    pub choices: Vec<CompletionsChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ChatCompletionsUsage>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CompletionsChoice {
+    pub text: String,
+    pub index: i32,
+    pub logprobs: Option<CompletionsLogprobs>,
+    pub finish_reason: Option<String>,
+}
+
+/// Log probabilities of the tokens of a text completion choice or chunk.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct CompletionsLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<f32>,
+    /// Most likely alternatives of each token with their log probabilities.
+    pub top_logprobs: Vec<HashMap<String, f32>>,
+    /// Offset of each token in the text of the choice.
+    pub text_offset: Vec<usize>,
+}
+
+/// Request body of the embeddings API.
+#[derive(Deserialize, Debug)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub input: EmbeddingsInput,
+    pub encoding_format: Option<String>,
+    pub dimensions: Option<usize>,
+}
+
+/// A single input or a batch of inputs.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Text(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingsInput {
+    pub fn inputs(&self) -> Vec<String> {
+        match self {
+            EmbeddingsInput::Text(input) => vec![input.to_owned()],
+            EmbeddingsInput::Batch(inputs) => inputs.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingsUsage,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Embedding {
+    pub object: String,
+    pub embedding: EmbeddingVector,
+    pub index: i32,
+}
+
+/// Embedding vector encoded as a list of floats or as base64 of little-endian float32 bytes.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct EmbeddingsUsage {
+    pub prompt_tokens: i64,
+    pub total_tokens: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_text() {
+        let image = |url: &str| ChatCompletionsContentPart::ImageUrl {
+            image_url: ImageUrl { url: url.to_owned(), detail: None },
+        };
+        let text = |text: &str| ChatCompletionsContentPart::Text { text: text.to_owned() };
+        let mut message = ChatCompletionsMessage {
+            content: Some(ChatCompletionsContent::Parts(vec![image("a.png"), text("Compare"), image("b.png"), text("these.")])),
+            ..ChatCompletionsMessage::new("user", "")
+        };
+        message.set_text("Compare these images.".to_owned());
+        assert_eq!(message.content, Some(ChatCompletionsContent::Parts(vec![image("a.png"), text("Compare these images."), image("b.png")])));
+
+        let mut message = ChatCompletionsMessage {
+            content: Some(ChatCompletionsContent::Parts(vec![image("a.png")])),
+            ..ChatCompletionsMessage::new("user", "")
+        };
+        message.set_text("Describe it.".to_owned());
+        assert_eq!(message.content, Some(ChatCompletionsContent::Parts(vec![image("a.png"), text("Describe it.")])));
+
+        let mut message = ChatCompletionsMessage::new("user", "Hi");
+        message.set_text("Hello".to_owned());
+        assert_eq!(message.text(), "Hello");
+    }
 }